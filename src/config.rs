@@ -0,0 +1,157 @@
+use std::fmt::Write as FmtWrite;
+
+use crate::error::Result;
+
+/// The kinds of value a series configuration key takes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConfigKind {
+    Bool,
+    String,
+    /// A string that may appear more than once.
+    List,
+}
+
+/// The keys a series configuration may set, each providing a default for an option of `git
+/// series format` or `git series req`.
+pub const SERIES_CONFIG_KEYS: [(&str, ConfigKind); 10] = [
+    ("format.cc", ConfigKind::List),
+    ("format.ccCmd", ConfigKind::String),
+    ("format.ccTrailers", ConfigKind::Bool),
+    ("format.noFrom", ConfigKind::Bool),
+    ("format.rfc", ConfigKind::Bool),
+    ("format.signOff", ConfigKind::Bool),
+    ("format.subjectPrefix", ConfigKind::String),
+    ("format.to", ConfigKind::List),
+    ("req.patch", ConfigKind::Bool),
+    ("req.url", ConfigKind::String),
+];
+
+/// Settings that travel with a series. The `config` entry of the git-series tree stores one
+/// setting per line, as "key = value"; a key may appear more than once.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SeriesConfig {
+    pub entries: Vec<(String, String)>,
+}
+
+fn canonical_key(key: &str) -> Result<(&'static str, ConfigKind)> {
+    SERIES_CONFIG_KEYS.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .copied()
+        .ok_or_else(|| format!("Unknown series configuration key: {}", key).into())
+}
+
+// Check a new value for `key`, returning the canonical spelling of the key.
+fn check_value(key: &str, value: &str) -> Result<(&'static str, ConfigKind)> {
+    let (key, kind) = canonical_key(key)?;
+    if value.contains('\n') {
+        return Err(format!("Series configuration {} cannot contain a newline", key).into());
+    } else if kind == ConfigKind::Bool && git2::Config::parse_bool(value).is_err() {
+        return Err(format!("Invalid boolean value for series configuration {}: {}", key, value).into());
+    }
+    Ok((key, kind))
+}
+
+impl SeriesConfig {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// All values of `key`, in order.
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+
+    /// The last value of `key`, if set.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key).pop()
+    }
+
+    /// The last value of `key` as a boolean, if set.
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        match self.get(key) {
+            Some(value) => Ok(Some(git2::Config::parse_bool(value)
+                .map_err(|_| format!("Invalid boolean value for series configuration {}: {}", key, value))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set `key` to `value`, replacing any previous values.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let (key, _) = check_value(key, value)?;
+        match self.entries.iter().position(|(k, _)| k == key) {
+            Some(pos) => {
+                self.entries[pos].1 = value.to_string();
+                let mut seen = false;
+                self.entries.retain(|(k, _)| k != key || !std::mem::replace(&mut seen, true));
+            }
+            None => self.entries.push((key.to_string(), value.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Add another value for the multi-valued `key`.
+    pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
+        let (key, kind) = check_value(key, value)?;
+        if kind != ConfigKind::List && !self.get_all(key).is_empty() {
+            return Err(format!("Series configuration {} can only have one value", key).into());
+        }
+        self.entries.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Remove all values of `key`. Returns true if it had any.
+    pub fn unset(&mut self, key: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self.entries.len() != len
+    }
+}
+
+pub fn parse_series_config(content: &str) -> Result<SeriesConfig> {
+    let mut config = SeriesConfig::default();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let (key, value) = line.split_once('=')
+            .ok_or_else(|| format!("Malformed line in \"config\": {}", line))?;
+        // Keep keys this version doesn't know, for versions that do.
+        let key = canonical_key(key.trim()).map_or_else(|_| key.trim().to_string(), |(k, _)| k.to_string());
+        config.entries.push((key, value.trim().to_string()));
+    }
+    Ok(config)
+}
+
+pub fn write_series_config(config: &SeriesConfig) -> String {
+    let mut s = String::new();
+    for (key, value) in config.entries.iter() {
+        writeln!(s, "{} = {}", key, value).unwrap();
+    }
+    s
+}
+
+#[test]
+fn test_series_config() {
+    let mut config = parse_series_config("format.to = list@example.com\nformat.RFC = true\n").unwrap();
+    assert_eq!(config.get_bool("format.rfc").unwrap(), Some(true));
+    assert_eq!(config.get_bool("format.noFrom").unwrap(), None);
+    config.add("format.to", "Jane Doe <jane@example.com>").unwrap();
+    assert!(config.add("format.rfc", "false").is_err());
+    assert!(config.set("format.noFrom", "maybe").is_err());
+    config.set("format.subjectPrefix", "PATCH net-next").unwrap();
+    config.set("format.rfc", "no").unwrap();
+    assert_eq!(config.get_all("format.to"), vec!["list@example.com", "Jane Doe <jane@example.com>"]);
+    assert_eq!(
+        write_series_config(&config),
+        concat!(
+            "format.to = list@example.com\n",
+            "format.rfc = no\n",
+            "format.to = Jane Doe <jane@example.com>\n",
+            "format.subjectPrefix = PATCH net-next\n",
+        ),
+    );
+    assert!(config.unset("format.to"));
+    assert!(!config.unset("format.to"));
+    assert!(config.set("format.subject", "PATCH").is_err());
+    assert!(parse_series_config("format.rfc\n").is_err());
+}
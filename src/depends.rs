@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::fmt::Write as FmtWrite;
+
+use git2::{Oid, Repository};
+
+use crate::diff::get_commits;
+use crate::error::Result;
+use crate::patchid::tree_patch_id;
+use crate::series::Series;
+
+/// A prerequisite of a series that upstream does not have yet. The `depends-on` entry of the
+/// git-series tree stores one per line: "series NAME" for another patch series, whose patches the
+/// series builds on, or "commit ID" for a single prerequisite commit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Dependency {
+    Series(String),
+    Commit(Oid),
+}
+
+pub fn parse_dependencies(content: &str) -> Result<Vec<Dependency>> {
+    content.lines().filter(|line| !line.trim().is_empty()).map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("series"), Some(name), None) => Ok(Dependency::Series(name.to_string())),
+            (Some("commit"), Some(id), None) => Ok(Dependency::Commit(Oid::from_str(id)?)),
+            _ => Err(format!("Malformed line in \"depends-on\": {}", line).into()),
+        }
+    }).collect()
+}
+
+pub fn write_dependencies(dependencies: &[Dependency]) -> String {
+    let mut s = String::new();
+    for dependency in dependencies {
+        match dependency {
+            Dependency::Series(name) => writeln!(s, "series {}", name).unwrap(),
+            Dependency::Commit(id) => writeln!(s, "commit {}", id).unwrap(),
+        }
+    }
+    s
+}
+
+#[test]
+fn test_dependencies() {
+    let content = "series topic\ncommit 0123456789abcdef0123456789abcdef01234567\n";
+    let dependencies = parse_dependencies(content).unwrap();
+    assert_eq!(dependencies, vec![
+        Dependency::Series("topic".to_string()),
+        Dependency::Commit(Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap()),
+    ]);
+    assert_eq!(write_dependencies(&dependencies), content);
+    assert!(parse_dependencies("series\n").is_err());
+    assert!(parse_dependencies("branch topic\n").is_err());
+}
+
+fn add_dependency_commits(
+    repo: &Repository,
+    dependencies: &[Dependency],
+    seen: &mut HashSet<String>,
+    commits: &mut HashSet<Oid>,
+) -> Result<()> {
+    for dependency in dependencies {
+        match dependency {
+            Dependency::Commit(id) => {
+                commits.insert(*id);
+            }
+            Dependency::Series(name) => {
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                let series = Series::find(repo, name)?;
+                let internals = series.internals()?;
+                let tip = internals.working.get("series")?
+                    .ok_or_else(|| format!("Prerequisite series {} has no commits", name))?.id();
+                let base = internals.working.get("base")?
+                    .ok_or_else(|| format!("Prerequisite series {} has no base set", name))?.id();
+                commits.extend(get_commits(repo, base, tip)?.iter().map(|c| c.id()));
+                add_dependency_commits(repo, &series.dependencies()?, seen, commits)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// All the commits the dependencies provide: each prerequisite commit, and the patches of each
+/// prerequisite series and of its own dependencies, using the working version of each series.
+pub fn dependency_commits(repo: &Repository, dependencies: &[Dependency]) -> Result<HashSet<Oid>> {
+    let mut commits = HashSet::new();
+    add_dependency_commits(repo, dependencies, &mut HashSet::new(), &mut commits)?;
+    Ok(commits)
+}
+
+/// Where a series applies, as `git format-patch --base` reports it: the upstream commit the
+/// series and its prerequisites apply to, and the patch IDs of the prerequisite patches between
+/// that commit and the series base, in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BaseInfo {
+    pub base_commit: Oid,
+    pub prerequisite_patch_ids: Vec<Oid>,
+}
+
+/// Find where a series with base `base` applies. Given the `upstream` the series targets, that is
+/// where `base` branched from upstream; otherwise, walk back along first parents from `base` past
+/// any commits the dependencies provide. Either way, list the patches from there to `base`.
+pub fn base_info(
+    repo: &Repository,
+    base: Oid,
+    dependencies: &[Dependency],
+    upstream: Option<Oid>,
+) -> Result<BaseInfo> {
+    let mut base_commit = base;
+    if let Some(upstream) = upstream {
+        base_commit = repo.merge_base(base, upstream)?;
+    } else {
+        let provided = dependency_commits(repo, dependencies)?;
+        while provided.contains(&base_commit) {
+            let commit = repo.find_commit(base_commit)?;
+            match commit.parent_ids().next() {
+                Some(parent) => base_commit = parent,
+                None => break,
+            }
+        }
+    }
+    // Like git format-patch, detect renames unless diff.renames turns that off.
+    let renames = repo.config()?.get_bool("diff.renames").unwrap_or(true);
+    let mut prerequisite_patch_ids = Vec::new();
+    for commit in get_commits(repo, base_commit, base)? {
+        if commit.parent_count() == 1 {
+            let parent = commit.parent(0)?;
+            prerequisite_patch_ids.push(tree_patch_id(repo, &parent.tree()?, &commit.tree()?, renames)?);
+        }
+    }
+    Ok(BaseInfo { base_commit, prerequisite_patch_ids })
+}
@@ -0,0 +1,779 @@
+use std::cmp::max;
+use std::io::Write as IoWrite;
+
+use ansi_term::Style;
+use git2::{Commit, Config, Delta, Diff, Oid, Repository, Tree};
+
+use crate::error::Result;
+use crate::output::Output;
+use crate::util::commit_obj_summarize_components;
+
+pub struct DiffColors {
+    pub commit: Style,
+    pub meta: Style,
+    pub frag: Style,
+    pub func: Style,
+    pub context: Style,
+    pub old: Style,
+    pub new: Style,
+    pub series_old: Style,
+    pub series_new: Style,
+    /// For `RangeDiffOptions::dual_color`: lines of a commit's diff unchanged between versions.
+    pub old_dimmed: Style,
+    pub new_dimmed: Style,
+    pub context_dimmed: Style,
+    /// For `RangeDiffOptions::dual_color`: lines of a commit's diff changed between versions.
+    pub old_bold: Style,
+    pub new_bold: Style,
+    pub context_bold: Style,
+}
+
+impl DiffColors {
+    pub fn plain() -> Self {
+        DiffColors {
+            commit: Style::new(),
+            meta: Style::new(),
+            frag: Style::new(),
+            func: Style::new(),
+            context: Style::new(),
+            old: Style::new(),
+            new: Style::new(),
+            series_old: Style::new(),
+            series_new: Style::new(),
+            old_dimmed: Style::new(),
+            new_dimmed: Style::new(),
+            context_dimmed: Style::new(),
+            old_bold: Style::new(),
+            new_bold: Style::new(),
+            context_bold: Style::new(),
+        }
+    }
+
+    pub fn new(out: &Output, config: &Config) -> Result<Self> {
+        let old = out.get_color(config, "diff", "old", "red")?;
+        let new = out.get_color(config, "diff", "new", "green")?;
+        Ok(DiffColors {
+            commit: out.get_color(config, "diff", "commit", "yellow")?,
+            meta: out.get_color(config, "diff", "meta", "bold")?,
+            frag: out.get_color(config, "diff", "frag", "cyan")?,
+            func: out.get_color(config, "diff", "func", "normal")?,
+            context: out.get_color(config, "diff", "context", "normal")?,
+            old,
+            new,
+            series_old: old.reverse(),
+            series_new: new.reverse(),
+            old_dimmed: out.get_color(config, "diff", "oldDimmed", "dim red")?,
+            new_dimmed: out.get_color(config, "diff", "newDimmed", "dim green")?,
+            context_dimmed: out.get_color(config, "diff", "contextDimmed", "dim")?,
+            old_bold: out.get_color(config, "diff", "oldBold", "bold red")?,
+            new_bold: out.get_color(config, "diff", "newBold", "bold green")?,
+            context_bold: out.get_color(config, "diff", "contextBold", "bold")?,
+        })
+    }
+}
+
+pub fn diffstat(diff: &Diff) -> Result<String> {
+    let stats = diff.stats()?;
+    let stats_buf = stats.to_buf(git2::DiffStatsFormat::FULL | git2::DiffStatsFormat::INCLUDE_SUMMARY, 72)?;
+    Ok(stats_buf.as_str().unwrap().to_string())
+}
+
+pub fn write_diff<W: IoWrite>(
+    f: &mut W,
+    colors: &DiffColors,
+    diff: &Diff,
+    simplify: bool,
+) -> Result<usize> {
+    write_diff_origins(f, colors, diff, simplify, &mut Vec::new())
+}
+
+// Like write_diff, also recording the origin of each line of a simplified diff in `origins`.
+fn write_diff_origins<W: IoWrite>(
+    f: &mut W,
+    colors: &DiffColors,
+    diff: &Diff,
+    simplify: bool,
+    origins: &mut Vec<char>,
+) -> Result<usize> {
+    let mut err = Ok(());
+    let mut lines = 0;
+    let normal = Style::new();
+    diff.print(git2::DiffFormat::Patch, |_, _, l| {
+        err = || -> Result<()> {
+            let o = l.origin();
+            let style = match o {
+                '-' | '<' => colors.old,
+                '+' | '>' => colors.new,
+                _ if simplify => normal,
+                ' ' | '=' => colors.context,
+                'F' => colors.meta,
+                'H' => colors.frag,
+                _ => normal,
+            };
+            let obyte = [o as u8];
+            let mut v = Vec::new();
+            if o == '+' || o == '-' || o == ' ' {
+                v.push(style.paint(&obyte[..]));
+            }
+            if simplify {
+                if o == 'H' {
+                    v.push(normal.paint("@@\n".as_bytes()));
+                    origins.push(o);
+                    lines += 1;
+                } else if o == 'F' {
+                    for line in l.content().split(|c| *c == b'\n') {
+                        if !line.is_empty()
+                            && !line.starts_with(b"diff --git")
+                            && !line.starts_with(b"index ")
+                        {
+                            v.push(normal.paint(line.to_owned()));
+                            v.push(normal.paint("\n".as_bytes()));
+                            origins.push(o);
+                            lines += 1;
+                        }
+                    }
+                } else {
+                    v.push(style.paint(l.content()));
+                    origins.push(o);
+                    lines += 1;
+                }
+            } else if o == 'H' {
+                // Split frag and func
+                let line = l.content();
+                let at = &|&(_, &c): &(usize, &u8)| c == b'@';
+                let not_at = &|&(_, &c): &(usize, &u8)| c != b'@';
+                match line
+                    .iter()
+                    .enumerate()
+                    .skip_while(at)
+                    .skip_while(not_at)
+                    .skip_while(at)
+                    .nth(1)
+                    .unwrap_or((0, &b'\n'))
+                {
+                    (_, &b'\n') => v.push(style.paint(&line[..line.len() - 1])),
+                    (pos, _) => {
+                        v.push(style.paint(&line[..pos - 1]));
+                        v.push(normal.paint(" ".as_bytes()));
+                        v.push(colors.func.paint(&line[pos..line.len() - 1]));
+                    }
+                }
+                v.push(normal.paint("\n".as_bytes()));
+            } else {
+                // The less pager resets ANSI colors at each newline, so emit colors separately for
+                // each line.
+                for (n, line) in l.content().split(|c| *c == b'\n').enumerate() {
+                    if n != 0 {
+                        v.push(normal.paint("\n".as_bytes()));
+                    }
+                    if !line.is_empty() {
+                        v.push(style.paint(line));
+                    }
+                }
+            }
+            ansi_term::ANSIByteStrings(&v).write_to(f)?;
+            Ok(())
+        }();
+        err.is_ok()
+    })?;
+    err?;
+    Ok(lines)
+}
+
+pub fn get_commits(repo: &Repository, base: Oid, series: Oid) -> Result<Vec<Commit<'_>>> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
+    revwalk.push(series)?;
+    revwalk.hide(base)?;
+    revwalk.map(|c| {
+        let id = c?;
+        let commit = repo.find_commit(id)?;
+        Ok(commit)
+    }).collect()
+}
+
+/// How a commit in one version of a series corresponds to a commit in another version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PairStatus {
+    /// The commit appears in both versions, unchanged.
+    Unchanged,
+    /// The commit appears in both versions, with changes.
+    Modified,
+    /// The commit only appears in the new version.
+    Added,
+    /// The commit only appears in the old version.
+    Deleted,
+}
+
+/// One row of a range diff: indexes into the old and new commits of a `RangeDiff`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CommitPair {
+    pub old: Option<usize>,
+    pub new: Option<usize>,
+    pub status: PairStatus,
+}
+
+/// Options for matching up and showing the commits of a `RangeDiff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeDiffOptions {
+    /// The cost of treating a commit as added or deleted rather than pairing it with another
+    /// commit, as a percentage of the size of its diff, like `git range-diff --creation-factor`.
+    /// Lower values pair fewer commits.
+    pub creation_factor: usize,
+    /// Match commits by their diffs alone, ignoring differences in their commit messages.
+    pub ignore_messages: bool,
+    /// Match commits ignoring differences in their authors.
+    pub ignore_authors: bool,
+    /// Color the diff between two commits by both the changes between the commits and the
+    /// changes within each commit, like `git range-diff --dual-color`.
+    pub dual_color: bool,
+}
+
+impl Default for RangeDiffOptions {
+    fn default() -> Self {
+        RangeDiffOptions {
+            creation_factor: 50,
+            ignore_messages: false,
+            ignore_authors: false,
+            dual_color: false,
+        }
+    }
+}
+
+/// The correspondence between the commits of two commit ranges, such as two versions of a patch
+/// series, excluding any commits the two ranges have in common at their start.
+pub struct RangeDiff<'repo> {
+    /// The number of commits at the start of both ranges that the two ranges share.
+    pub ncommon: usize,
+    pub commits1: Vec<Commit<'repo>>,
+    pub commits2: Vec<Commit<'repo>>,
+    /// Pairs sorted by the new commit order, with deleted commits after their prerequisites.
+    pub pairs: Vec<CommitPair>,
+    texts1: Vec<Vec<u8>>,
+    texts2: Vec<Vec<u8>>,
+    // With dual_color, the origin of each line of texts1 and texts2 within its commit's diff.
+    origins1: Vec<Vec<char>>,
+    origins2: Vec<Vec<char>>,
+    dual_color: bool,
+}
+
+impl<'repo> RangeDiff<'repo> {
+    /// Match up the commits of `base1..series1` and `base2..series2`. Merge commits within either
+    /// range compare by their diff from their first parent.
+    pub fn new(
+        repo: &'repo Repository,
+        colors: &DiffColors,
+        opts: &RangeDiffOptions,
+        (base1, series1): (Oid, Oid),
+        (base2, series2): (Oid, Oid),
+    ) -> Result<Self> {
+        let mut commits1 = get_commits(repo, base1, series1)?;
+        let mut commits2 = get_commits(repo, base2, series2)?;
+        let ncommon = commits1.iter().zip(commits2.iter())
+            .take_while(|(c1, c2)| c1.id() == c2.id())
+            .count();
+        drop(commits1.drain(..ncommon));
+        drop(commits2.drain(..ncommon));
+        let mut range_diff = Self::from_commits(repo, colors, opts, commits1, commits2)?;
+        range_diff.ncommon = ncommon;
+        Ok(range_diff)
+    }
+
+    /// Match up two lists of commits, such as a series and the commits applied upstream. Each
+    /// commit compares by its diff from its first parent.
+    pub fn from_commits(
+        repo: &'repo Repository,
+        colors: &DiffColors,
+        opts: &RangeDiffOptions,
+        commits1: Vec<Commit<'repo>>,
+        commits2: Vec<Commit<'repo>>,
+    ) -> Result<Self> {
+        let ncommon = 0;
+        let ncommits1 = commits1.len();
+        let ncommits2 = commits2.len();
+        let n = ncommits1 + ncommits2;
+        if n == 0 {
+            return Ok(RangeDiff {
+                ncommon,
+                commits1,
+                commits2,
+                pairs: Vec::new(),
+                texts1: Vec::new(),
+                texts2: Vec::new(),
+                origins1: Vec::new(),
+                origins2: Vec::new(),
+                dual_color: opts.dual_color,
+            });
+        }
+        // Dual coloring colors the text of each commit when showing it, from the line origins.
+        let plain = DiffColors::plain();
+        let text_colors = if opts.dual_color { &plain } else { colors };
+        let commit_text = &|commit: &Commit, colors: &DiffColors, author: bool, message: bool| {
+            // For a merge commit, the first parent is the previous commit of the series, and the
+            // diff from it shows what the merge brought into the series.
+            let parent = commit.parent(0)?;
+            let diff = repo.diff_tree_to_tree(
+                Some(&parent.tree().unwrap()),
+                Some(&commit.tree().unwrap()),
+                None,
+            )?;
+            let mut v = Vec::new();
+            if author {
+                let author = commit.author();
+                v.write_all(b"From: ")?;
+                v.write_all(author.name_bytes())?;
+                v.write_all(b" <")?;
+                v.write_all(author.email_bytes())?;
+                v.write_all(b">\n")?;
+            }
+            for merged in commit.parents().skip(1) {
+                v.write_all(b"Merge: ")?;
+                v.write_all(merged.summary_bytes().unwrap_or(b""))?;
+                v.write_all(b"\n")?;
+            }
+            if message {
+                v.write_all(b"\n")?;
+                v.write_all(commit.message_bytes())?;
+                v.write_all(b"\n")?;
+            }
+            let mut origins = vec![' '; v.iter().filter(|&&c| c == b'\n').count()];
+            let lines = write_diff_origins(&mut v, colors, &diff, true, &mut origins)?;
+            Ok((v, lines, origins))
+        };
+        let texts1: Vec<_> = commits1.iter().map(|c| commit_text(c, text_colors, true, true)).collect::<Result<_>>()?;
+        let texts2: Vec<_> = commits2.iter().map(|c| commit_text(c, text_colors, true, true)).collect::<Result<_>>()?;
+        let ignoring = if opts.ignore_authors || opts.ignore_messages {
+            let match_text = |c| commit_text(c, &plain, !opts.ignore_authors, !opts.ignore_messages).map(|(v, _, _)| v);
+            Some((
+                commits1.iter().map(match_text).collect::<Result<Vec<_>>>()?,
+                commits2.iter().map(match_text).collect::<Result<Vec<_>>>()?,
+            ))
+        } else {
+            None
+        };
+        let (match_texts1, match_texts2): (Vec<&[u8]>, Vec<&[u8]>) = match ignoring {
+            Some((ref m1, ref m2)) => (m1.iter().map(|v| &v[..]).collect(), m2.iter().map(|v| &v[..]).collect()),
+            None => (texts1.iter().map(|t| &t.0[..]).collect(), texts2.iter().map(|t| &t.0[..]).collect()),
+        };
+        let creation_cost = |lines: usize| lines * opts.creation_factor / 100;
+
+        let mut weights = Vec::with_capacity(n * n);
+        for (text1, match_text1) in texts1.iter().zip(match_texts1.iter()) {
+            for match_text2 in match_texts2.iter() {
+                let patch = git2::Patch::from_buffers(match_text1, None, match_text2, None, None)?;
+                let (_, additions, deletions) = patch.line_stats()?;
+                weights.push(additions + deletions);
+            }
+            weights.extend(std::iter::repeat_n(creation_cost(text1.1), ncommits1));
+        }
+        for _ in ncommits1..n {
+            weights.extend(texts2.iter().map(|text2| creation_cost(text2.1)));
+            weights.extend(std::iter::repeat_n(0, ncommits1));
+        }
+        let mut weight_matrix = munkres::WeightMatrix::from_row_vec(n, weights);
+        let result = munkres::solve_assignment(&mut weight_matrix)?;
+
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        enum CommitState { Unhandled, Handled, Deleted }
+        let mut commits2_from1: Vec<_> = std::iter::repeat_n(None, ncommits2).collect();
+        let mut commits1_state: Vec<_> = std::iter::repeat_n(CommitState::Unhandled, ncommits1).collect();
+        let mut pairs = Vec::with_capacity(n);
+        for munkres::Position { row: i1, column: i2 } in result {
+            if i1 < ncommits1 {
+                if i2 < ncommits2 {
+                    commits2_from1[i2] = Some(i1);
+                } else {
+                    commits1_state[i1] = CommitState::Deleted;
+                }
+            }
+        }
+
+        let deleted = |i1| CommitPair { old: Some(i1), new: None, status: PairStatus::Deleted };
+
+        // Show matching or new commits sorted by the new commit order. Show deleted commits after
+        // showing all of their prerequisite commits.
+        let mut commits1_state_index = 0;
+        for (i2, opt_i1) in commits2_from1.iter().enumerate() {
+            while commits1_state_index < ncommits1 {
+                match commits1_state[commits1_state_index] {
+                    CommitState::Unhandled => { break }
+                    CommitState::Handled => {}
+                    CommitState::Deleted => {
+                        pairs.push(deleted(commits1_state_index));
+                    }
+                }
+                commits1_state_index += 1;
+            }
+            if let Some(i1) = *opt_i1 {
+                let patch = git2::Patch::from_buffers(&texts1[i1].0, None, &texts2[i2].0, None, None)?;
+                let status = if let Delta::Unmodified = patch.delta().status() {
+                    PairStatus::Unchanged
+                } else {
+                    PairStatus::Modified
+                };
+                pairs.push(CommitPair { old: Some(i1), new: Some(i2), status });
+                commits1_state[i1] = CommitState::Handled;
+            } else {
+                pairs.push(CommitPair { old: None, new: Some(i2), status: PairStatus::Added });
+            }
+        }
+        for (i1, state) in commits1_state.iter().enumerate().skip(commits1_state_index) {
+            if *state == CommitState::Deleted {
+                pairs.push(deleted(i1));
+            }
+        }
+
+        Ok(RangeDiff {
+            ncommon,
+            commits1,
+            commits2,
+            pairs,
+            origins1: texts1.iter().map(|t| t.2.clone()).collect(),
+            origins2: texts2.iter().map(|t| t.2.clone()).collect(),
+            texts1: texts1.into_iter().map(|(v, _, _)| v).collect(),
+            texts2: texts2.into_iter().map(|(v, _, _)| v).collect(),
+            dual_color: opts.dual_color,
+        })
+    }
+
+    /// Compare commit `old` of `commits1` with commit `new` of `commits2`, returning the number
+    /// of lines that differ between the two, and the total number of lines of the two, counting
+    /// the author, message and diff of each as the range diff shows them.
+    pub fn changes(&self, old: usize, new: usize) -> Result<(usize, usize)> {
+        let (text1, text2) = (&self.texts1[old], &self.texts2[new]);
+        let patch = git2::Patch::from_buffers(text1, None, text2, None, None)?;
+        let (_, additions, deletions) = patch.line_stats()?;
+        let lines = |text: &[u8]| text.iter().filter(|&&c| c == b'\n').count();
+        Ok((additions + deletions, lines(text1) + lines(text2)))
+    }
+
+    /// Write the range diff in the series diff format.
+    pub fn write<W: IoWrite>(&mut self, out: &mut W, colors: &DiffColors) -> Result<()> {
+        if self.pairs.is_empty() {
+            return Ok(());
+        }
+        ansi_term::ANSIByteStrings(&[
+            colors.meta.paint("diff --series".as_bytes()),
+            Style::new().paint("\n".as_bytes()),
+        ]).write_to(out)?;
+        self.write_pairs(out, colors)
+    }
+
+    /// Write one line per pair of commits, each followed by the diff between the two commits if
+    /// they differ, without the "diff --series" header of `write`.
+    pub fn write_pairs<W: IoWrite>(&mut self, out: &mut W, colors: &DiffColors) -> Result<()> {
+        if self.pairs.is_empty() {
+            return Ok(());
+        }
+        let normal = Style::new();
+        let nl = |v: &mut Vec<_>| { v.push(normal.paint("\n".as_bytes())); };
+        let mut v = Vec::new();
+
+        let offset = self.ncommon + 1;
+        let nwidth = max(self.commits1.len() + offset, self.commits2.len() + offset).to_string().len();
+        let commits1_summaries: Vec<_> = self.commits1.iter_mut().map(commit_obj_summarize_components).collect::<Result<_>>()?;
+        let commits2_summaries: Vec<_> = self.commits2.iter_mut().map(commit_obj_summarize_components).collect::<Result<_>>()?;
+        let idwidth = commits1_summaries.iter().chain(commits2_summaries.iter())
+            .map(|(short_id, _)| short_id.len())
+            .max().unwrap();
+        for commit_pair in self.pairs.iter() {
+            match (commit_pair.old, commit_pair.new) {
+                (None, None) => unreachable!(),
+                (Some(i1), None) => {
+                    let (ref c1_short_id, ref c1_summary) = commits1_summaries[i1];
+                    v.push(colors.old.paint(format!(
+                        "{:nwidth$}: {:idwidth$} < {:-<nwidth$}: {:-<idwidth$} {}",
+                        i1 + offset, c1_short_id, "", "", c1_summary, nwidth=nwidth, idwidth=idwidth,
+                    ).as_bytes().to_owned()));
+                    nl(&mut v);
+                }
+                (None, Some(i2)) => {
+                    let (ref c2_short_id, ref c2_summary) = commits2_summaries[i2];
+                    v.push(colors.new.paint(format!(
+                        "{:-<nwidth$}: {:-<idwidth$} > {:nwidth$}: {:idwidth$} {}",
+                        "", "", i2 + offset, c2_short_id, c2_summary, nwidth=nwidth, idwidth=idwidth,
+                    ).as_bytes().to_owned()));
+                    nl(&mut v);
+                }
+                (Some(i1), Some(i2)) => {
+                    let mut patch = git2::Patch::from_buffers(&self.texts1[i1], None, &self.texts2[i2], None, None)?;
+                    let (old, ch, new) = if commit_pair.status == PairStatus::Unchanged {
+                        (colors.commit, '=', colors.commit)
+                    } else {
+                        (colors.series_old, '!', colors.series_new)
+                    };
+                    let (ref c1_short_id, _) = commits1_summaries[i1];
+                    let (ref c2_short_id, ref c2_summary) = commits2_summaries[i2];
+                    v.push(old.paint(format!("{:nwidth$}: {:idwidth$}", i1 + offset, c1_short_id, nwidth=nwidth, idwidth=idwidth).as_bytes().to_owned()));
+                    v.push(colors.commit.paint(format!(" {} ", ch).as_bytes().to_owned()));
+                    v.push(new.paint(format!("{:nwidth$}: {:idwidth$}", i2 + offset, c2_short_id, nwidth=nwidth, idwidth=idwidth).as_bytes().to_owned()));
+                    v.push(colors.commit.paint(format!(" {}", c2_summary).as_bytes().to_owned()));
+                    nl(&mut v);
+                    let (origins1, origins2) = (&self.origins1[i1], &self.origins2[i2]);
+                    let dual_color = self.dual_color;
+                    patch.print(&mut |_, _, l| {
+                        let o = l.origin();
+                        let style = match o {
+                            '-' | '<' => old,
+                            '+' | '>' => new,
+                            _ => normal,
+                        };
+                        if o == '+' || o == '-' || o == ' ' {
+                            v.push(style.paint(vec![o as u8]));
+                        }
+                        let style = if o == 'H' {
+                            colors.frag
+                        } else if dual_color {
+                            // Color the line by its origin within the commit's own diff, dimmed
+                            // if unchanged between the two commits.
+                            let inner = match (l.old_lineno(), l.new_lineno()) {
+                                (_, Some(n)) if o == '+' => origins2.get(n as usize - 1),
+                                (Some(n), _) => origins1.get(n as usize - 1),
+                                _ => None,
+                            };
+                            match (inner, o == ' ') {
+                                (Some('-'), false) => colors.old_bold,
+                                (Some('+'), false) => colors.new_bold,
+                                (Some('H'), _) => colors.frag,
+                                (_, false) => colors.context_bold,
+                                (Some('-'), true) => colors.old_dimmed,
+                                (Some('+'), true) => colors.new_dimmed,
+                                (_, true) => colors.context_dimmed,
+                            }
+                        } else {
+                            normal
+                        };
+                        if o != 'F' {
+                            let content = l.content();
+                            match content.strip_suffix(b"\n") {
+                                Some(line) => {
+                                    v.push(style.paint(line.to_owned()));
+                                    nl(&mut v);
+                                }
+                                None => v.push(style.paint(content.to_owned())),
+                            }
+                        }
+                        true
+                    })?;
+                }
+            }
+        }
+
+        ansi_term::ANSIByteStrings(&v).write_to(out)?;
+        Ok(())
+    }
+}
+
+pub fn write_commit_range_diff<W: IoWrite>(
+    out: &mut W,
+    repo: &Repository,
+    colors: &DiffColors,
+    opts: &RangeDiffOptions,
+    range1: (Oid, Oid),
+    range2: (Oid, Oid),
+) -> Result<()> {
+    RangeDiff::new(repo, colors, opts, range1, range2)?.write(out, colors)
+}
+
+pub fn write_series_diff<W: IoWrite>(
+    out: &mut W,
+    repo: &Repository,
+    colors: &DiffColors,
+    opts: &RangeDiffOptions,
+    tree1: Option<&Tree>,
+    tree2: Option<&Tree>,
+) -> Result<()> {
+    let diff = repo.diff_tree_to_tree(tree1, tree2, None)?;
+    write_diff(out, colors, &diff, false)?;
+
+    let base1 = tree1.and_then(|t| t.get_name("base"));
+    let series1 = tree1.and_then(|t| t.get_name("series"));
+    let base2 = tree2.and_then(|t| t.get_name("base"));
+    let series2 = tree2.and_then(|t| t.get_name("series"));
+
+    if let (Some(base1), Some(series1), Some(base2), Some(series2)) = (base1, series1, base2, series2) {
+        write_commit_range_diff(
+            out,
+            repo,
+            colors,
+            opts,
+            (base1.id(), series1.id()),
+            (base2.id(), series2.id()),
+        )?;
+    } else {
+        writeln!(out, "Can't diff series: both versions must have base and series to diff")?;
+    }
+
+    Ok(())
+}
+
+
+#[test]
+fn test_range_diff_options() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let d = "1\n2\n3\n4\n5\n6\n";
+    let message = "Add d\n\nA long explanation\nof the change,\nover several\nlines.\n";
+    let x = t.commit(&[("d", Some(d))], message);
+    t.checkout(base);
+    let reworded = t.commit(&[("d", Some(d))], "Add d\n\nA different account\nwritten again\nfrom scratch,\nentirely.\n");
+    t.checkout(base);
+    let changed = t.commit(&[("d", Some("1\n2\nthree\n4\n5\n6\n"))], message);
+    t.checkout(base);
+    let longer = t.commit(&[("d", Some("1\n2\n3\n4\n5\n6\n7\n"))], message);
+    let other = git2::Signature::new("Other Person", "other@example.com", &git2::Time::new(1_500_000_000, 0)).unwrap();
+    let longer = repo.find_commit(longer).unwrap();
+    let other_author = repo.commit(None, &other, &other, message, &longer.tree().unwrap(), &[&longer.parent(0).unwrap()]).unwrap();
+
+    let range_diff = |colors: &DiffColors, new: Oid, opts: RangeDiffOptions| {
+        let (old, new) = (vec![repo.find_commit(x).unwrap()], vec![repo.find_commit(new).unwrap()]);
+        RangeDiff::from_commits(repo, colors, &opts, old, new).unwrap()
+    };
+    // A low creation factor only pairs commits that match closely.
+    let paired = |new: Oid, opts: RangeDiffOptions| {
+        let opts = RangeDiffOptions { creation_factor: 10, ..opts };
+        range_diff(&DiffColors::plain(), new, opts).pairs == [CommitPair { old: Some(0), new: Some(0), status: PairStatus::Modified }]
+    };
+    assert!(!paired(reworded, RangeDiffOptions::default()));
+    assert!(paired(reworded, RangeDiffOptions { ignore_messages: true, ..Default::default() }));
+    assert!(!paired(other_author, RangeDiffOptions::default()));
+    assert!(paired(other_author, RangeDiffOptions { ignore_authors: true, ..Default::default() }));
+
+    // Dual coloring colors the lines of each commit's diff by their origin, and bold if they
+    // changed between the two commits.
+    let mut colors = DiffColors::plain();
+    colors.new_bold = ansi_term::Colour::Green.bold();
+    colors.new_dimmed = ansi_term::Colour::Green.dimmed();
+    let write = |dual_color| {
+        let mut out = Vec::new();
+        range_diff(&colors, changed, RangeDiffOptions { dual_color, ..Default::default() })
+            .write_pairs(&mut out, &colors).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let header = "1: e973b42 ! 1: 3c1d0cb Add d\n@@ -13,7 +13,7 @@ new file mode 100644\n @@\n";
+    assert_eq!(write(false), format!("{} +1\n +2\n-+3\n++three\n +4\n +5\n +6\n", header));
+    let (dimmed, bold) = (colors.new_dimmed, colors.new_bold);
+    let lines: Vec<_> = ["+1", "+2", "+3", "+three", "+4", "+5", "+6"].iter().enumerate()
+        .map(|(i, line)| if i == 2 || i == 3 { bold.paint(*line) } else { dimmed.paint(*line) }.to_string())
+        .collect();
+    assert_eq!(write(true), format!(
+        "{} {}\n {}\n-{}\n+{}\n {}\n {}\n {}\n",
+        header, lines[0], lines[1], lines[2], lines[3], lines[4], lines[5], lines[6],
+    ));
+}
+
+#[test]
+fn test_range_diff_branches() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let shared = t.commit(&[("s", Some("1\n"))], "Shared");
+    t.commit(&[("p", Some("1\n"))], "Add p");
+    t.commit(&[("q", Some("1\n"))], "Add q");
+    let tip1 = t.commit(&[("r", Some("1\n"))], "Add r");
+    t.checkout(shared);
+    t.commit(&[("q", Some("1\n"))], "Add q");
+    t.commit(&[("p", Some("2\n"))], "Add p");
+    let tip2 = t.commit(&[("u", Some("1\n"))], "Add u");
+    let opts = RangeDiffOptions::default();
+    let mut range_diff = RangeDiff::new(repo, &DiffColors::plain(), &opts, (base, tip1), (base, tip2)).unwrap();
+    // The shared first commit only counts towards the numbering.
+    assert_eq!(range_diff.ncommon, 1);
+    let mut out = Vec::new();
+    range_diff.write(&mut out, &DiffColors::plain()).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), concat!(
+        "diff --series\n",
+        "3: b8d3be0 = 2: 3366401 Add q\n",
+        "2: 107baa1 ! 3: 0ea6e8d Add p\n",
+        "@@ -5,4 +5,4 @@ new file mode 100644\n",
+        " --- /dev/null\n",
+        " +++ b/p\n",
+        " @@\n",
+        "-+1\n",
+        "++2\n",
+        "4: 57686bd < -: ------- Add r\n",
+        "-: ------- > 4: ccf135e Add u\n",
+    ));
+
+    // Ranges with different bases share no commits, and comparing a range with itself shows
+    // nothing.
+    let range_diff = RangeDiff::new(repo, &DiffColors::plain(), &opts, (shared, tip1), (base, tip2)).unwrap();
+    assert_eq!((range_diff.ncommon, range_diff.commits1.len(), range_diff.commits2.len()), (0, 3, 4));
+    let mut range_diff = RangeDiff::new(repo, &DiffColors::plain(), &opts, (base, tip1), (base, tip1)).unwrap();
+    let mut out = Vec::new();
+    range_diff.write(&mut out, &DiffColors::plain()).unwrap();
+    assert!(out.is_empty());
+}
+
+#[cfg(test)]
+fn series_tree(repo: &Repository, base: Oid, series: Oid) -> Oid {
+    let mut builder = repo.treebuilder(None).unwrap();
+    builder.insert("base", base, 0o160000).unwrap();
+    builder.insert("series", series, 0o160000).unwrap();
+    builder.write().unwrap()
+}
+
+#[test]
+fn test_series_diff_merge() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let b = t.commit(&[("m", Some("1\n2\n3\n4\n5\n6\n"))], "Add m");
+    t.checkout(base);
+    let c = t.commit(&[("m", Some("1\n2\nthree\n4\n5\n6\n"))], "Add m");
+    t.checkout(base);
+    let a = t.commit(&[("a", Some("1\n"))], "Add a");
+    let sig = git2::Signature::new("A U Thor", "author@example.com", &git2::Time::new(1_500_000_000, 0)).unwrap();
+    let merge = |side: Oid| {
+        let (a, side) = (repo.find_commit(a).unwrap(), repo.find_commit(side).unwrap());
+        let mut index = repo.merge_commits(&a, &side, None).unwrap();
+        let tree = repo.find_tree(index.write_tree_to(repo).unwrap()).unwrap();
+        let message = format!("Merge {}", side.summary().unwrap());
+        repo.commit(None, &sig, &sig, &message, &tree, &[&a, &side]).unwrap()
+    };
+    let (merge_b, merge_c) = (merge(b), merge(c));
+
+    // A merge commit shows the branches it merged, and its diff from its first parent.
+    let range_diff = RangeDiff::new(repo, &DiffColors::plain(), &RangeDiffOptions::default(), (base, merge_b), (base, merge_c)).unwrap();
+    assert_eq!(String::from_utf8(range_diff.texts2[range_diff.texts2.len() - 1].clone()).unwrap(), concat!(
+        "From: A U Thor <author@example.com>\n",
+        "Merge: Add m\n",
+        "\n",
+        "Merge Add m\n",
+        "new file mode 100644\n",
+        "--- /dev/null\n",
+        "+++ b/m\n",
+        "@@\n",
+        "+1\n+2\n+three\n+4\n+5\n+6\n",
+    ));
+
+    // "git series log -p" shows a series merge commit as a series diff from each parent.
+    let tree = |series| repo.find_tree(series_tree(repo, base, series)).unwrap();
+    let new_tree = tree(merge_c);
+    let write = |old: Oid| {
+        let mut out = Vec::new();
+        let opts = RangeDiffOptions::default();
+        write_series_diff(&mut out, repo, &DiffColors::plain(), &opts, Some(&tree(old)), Some(&new_tree)).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let series_header = |old| format!(concat!(
+        "diff --git a/series b/series\n",
+        "index {:.7}..8d86e94 160000\n",
+        "--- a/series\n",
+        "+++ b/series\n",
+        "@@ -1 +1 @@\n",
+        "-Subproject commit {}\n",
+        "+Subproject commit 8d86e94d6bfe53ec808cc0dfa94697908e1d13a4\n",
+        "diff --series\n",
+    ), old, old);
+    let changed = "@@\n +1\n +2\n-+3\n++three\n +4\n +5\n +6\n";
+    assert_eq!(write(merge_b), format!(
+        "{}2: 25b00ab ! 2: deba8d3 Add m\n@@ -7,7 +7,7 @@ new file mode 100644\n {}3: 7c3e62c ! 3: 8d86e94 Merge Add m\n@@ -8,7 +8,7 @@ new file mode 100644\n {}",
+        series_header(merge_b), changed, changed,
+    ));
+    assert_eq!(write(a), format!(
+        "{}-: ------- > 2: deba8d3 Add m\n-: ------- > 3: 8d86e94 Merge Add m\n",
+        series_header(a),
+    ));
+}
@@ -0,0 +1,34 @@
+use quick_error::quick_error;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Git2(err: git2::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        IO(err: std::io::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        Munkres(err: munkres::Error) {
+            from()
+            display("{:?}", err)
+        }
+        Msg(msg: String) {
+            from()
+            from(s: &'static str) -> (s.to_string())
+            description(msg)
+            display("{}", msg)
+        }
+        Utf8Error(err: std::str::Utf8Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
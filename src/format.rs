@@ -0,0 +1,831 @@
+use std::collections::HashSet;
+use std::fmt::Write as FmtWrite;
+use std::io::Write as IoWrite;
+
+use git2::{Commit, Oid, Repository};
+
+use crate::depends::{base_info, BaseInfo};
+use crate::diff::{diffstat, write_diff, DiffColors, RangeDiff, RangeDiffOptions};
+use crate::error::Result;
+use crate::notes::find_note;
+use crate::patchid::patch_id;
+use crate::send::parse_addresses;
+use crate::series::{history_from, SeriesVersion};
+use crate::util::{
+    cmd_maybe_shell, commit_summarize, date_822, ensure_nl, ensure_space, get_signature, split_message,
+};
+use crate::versions::SentVersion;
+
+/// Options controlling how `format_series` prepares mails.
+#[derive(Clone, Debug, Default)]
+pub struct FormatOptions<'a> {
+    /// Make the first mail a reply to this Message-Id.
+    pub in_reply_to: Option<&'a str>,
+    /// Use the commit author as the From: address rather than adding in-body From: lines.
+    pub no_from: bool,
+    /// Mark the series as PATCH vN.
+    pub reroll_count: Option<&'a str>,
+    /// Use [RFC PATCH] as the subject prefix.
+    pub rfc: bool,
+    /// Use this subject prefix instead of [PATCH].
+    pub subject_prefix: Option<&'a str>,
+    /// Addresses for the To: header of every mail.
+    pub to: Vec<String>,
+    /// Addresses for the Cc: header of every mail.
+    pub cc: Vec<String>,
+    /// Cc each patch to the addresses in the Signed-off-by, Reviewed-by, Acked-by, Tested-by,
+    /// and Cc trailers of its commit message.
+    pub cc_trailers: bool,
+    /// Run this command on each patch, and Cc the patch to each address it prints, one per line.
+    pub cc_cmd: Option<&'a str>,
+    /// Add a Signed-off-by trailer for the committer to each patch that doesn't end with one.
+    pub signoff: bool,
+    /// The name of the series, for the `{series_name}` placeholder of the cover letter.
+    pub series_name: &'a str,
+    /// Add a "Changes since" section to the cover letter, listing the messages of the series
+    /// commits since this previously sent version.
+    pub changes_since: Option<&'a SentVersion>,
+    /// Add the diff between the tips of this previous version and the formatted version to the
+    /// cover letter.
+    pub interdiff: Option<PreviousVersion>,
+    /// Add a range diff against this previous version to the cover letter.
+    pub range_diff: Option<PreviousVersion>,
+    /// How to match up commits for `range_diff`.
+    pub range_diff_options: RangeDiffOptions,
+}
+
+/// A previous version of a series, for the cover letter to compare against.
+#[derive(Clone, Debug)]
+pub struct PreviousVersion {
+    /// How the cover letter refers to the previous version, such as "v1".
+    pub label: String,
+    pub base: Oid,
+    pub series: Oid,
+}
+
+/// One formatted mail of a patch series.
+#[derive(Clone, Debug)]
+pub struct Mail {
+    /// The file name to write this mail to, such as "v2-0001-Add-feature.patch".
+    pub file_name: String,
+    pub message_id: String,
+    /// The full mail in mbox format, including the "From <id>" line.
+    pub content: Vec<u8>,
+}
+
+pub fn mail_signature() -> String {
+    format!("-- \ngit-series {}", env!("CARGO_PKG_VERSION"))
+}
+
+pub fn shortlog(commits: &mut [Commit]) -> String {
+    let mut s = String::new();
+    let mut author_map = std::collections::HashMap::new();
+
+    for commit in commits {
+        let author = commit.author().name().unwrap().to_string();
+        author_map.entry(author).or_insert_with(Vec::new)
+            .push(commit.summary().unwrap().to_string());
+    }
+
+    let mut authors: Vec<_> = author_map.keys().collect();
+    authors.sort();
+    let mut first = true;
+    for author in authors {
+        if first {
+            first = false;
+        } else {
+            writeln!(s).unwrap();
+        }
+        let summaries = author_map.get(author).unwrap();
+        writeln!(s, "{} ({}):", author, summaries.len()).unwrap();
+        for summary in summaries {
+            writeln!(s, "  {}", summary).unwrap();
+        }
+    }
+
+    s
+}
+
+/// Replace each "{name}" in `text` with the value of `name` in `values`, leaving braces around
+/// any other text alone. Returns the result and the names that appeared.
+pub fn expand_placeholders<'a>(text: &str, values: &[(&'a str, &str)]) -> (String, Vec<&'a str>) {
+    let mut result = String::with_capacity(text.len());
+    let mut used = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let found = rest.find('}').and_then(|end| {
+            let (name, value) = values.iter().find(|(name, _)| *name == &rest[1..end])?;
+            Some((end, *name, *value))
+        });
+        match found {
+            Some((end, name, value)) => {
+                result.push_str(value);
+                if !used.contains(&name) {
+                    used.push(name);
+                }
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    (result, used)
+}
+
+#[test]
+fn test_expand_placeholders() {
+    let values = [("version", "2"), ("shortlog", "A (1):\n  Fix it")];
+    let (text, used) = expand_placeholders("v{version}: {{version}} {shortlog} {unknown} {", &values);
+    assert_eq!(text, "v2: {2} A (1):\n  Fix it {unknown} {");
+    assert_eq!(used, vec!["version", "shortlog"]);
+}
+
+fn sanitize_summary(summary: &str) -> String {
+    let mut s = String::with_capacity(summary.len());
+    let mut prev_dot = false;
+    let mut need_space = false;
+    for c in summary.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            if need_space {
+                s.push('-');
+                need_space = false;
+            }
+            if !(prev_dot && c == '.') {
+                s.push(c);
+            }
+        } else if !s.is_empty() {
+            need_space = true;
+        }
+        prev_dot = c == '.';
+    }
+    let end = s.trim_end_matches(['.', '-']).len();
+    s.truncate(end);
+    s
+}
+
+#[test]
+fn test_sanitize_summary() {
+    let tests = vec![
+        ("", ""),
+        ("!!!!!", ""),
+        ("Test", "Test"),
+        ("Test case", "Test-case"),
+        ("Test    case", "Test-case"),
+        ("    Test    case    ", "Test-case"),
+        ("...Test...case...", ".Test.case"),
+        ("...Test...case.!!", ".Test.case"),
+        (".!.Test.!.case.!.", ".-.Test.-.case"),
+    ];
+    for (summary, sanitized) in tests {
+        assert_eq!(sanitize_summary(summary), sanitized.to_string());
+    }
+}
+
+/// Encode `text` for a mail header as RFC 2047 encoded words, as `git format-patch` does, if it
+/// contains anything other than ASCII.
+fn encode_header(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        let mut encoded = String::new();
+        if c.is_ascii_alphanumeric() || "!*+-/".contains(c) {
+            encoded.push(c);
+        } else {
+            for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                encoded.push_str(&format!("={:02X}", b));
+            }
+        }
+        // Each encoded word, with its "=?UTF-8?q?" and "?=", may be at most 75 characters.
+        if word.len() + encoded.len() > 75 - 12 {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push_str(&encoded);
+    }
+    words.push(word);
+    words.iter().map(|word| format!("=?UTF-8?q?{}?=", word)).collect::<Vec<_>>().join("\n ")
+}
+
+/// Encode the name in an address such as "Name <name@example.com>" for a mail header.
+fn encode_address(address: &str) -> String {
+    match address.rfind('<') {
+        Some(pos) if !address[..pos].is_ascii() => {
+            format!("{} {}", encode_header(address[..pos].trim()), &address[pos..])
+        }
+        _ => address.to_string(),
+    }
+}
+
+// Declare the charset of a mail that contains anything other than ASCII, as `git format-patch`
+// does.
+fn add_mime_headers(mail: &mut Vec<u8>) {
+    if mail.is_ascii() {
+        return;
+    }
+    if let Some(end) = mail.windows(2).position(|w| w == b"\n\n") {
+        let headers = "MIME-Version: 1.0\nContent-Type: text/plain; charset=UTF-8\nContent-Transfer-Encoding: 8bit\n";
+        mail.splice(end + 1..end + 1, headers.bytes());
+    }
+}
+
+#[test]
+fn test_encode_header() {
+    assert_eq!(encode_header("Fix the frobnicator"), "Fix the frobnicator");
+    assert_eq!(encode_header("Grüße, Welt"), "=?UTF-8?q?Gr=C3=BC=C3=9Fe=2C=20Welt?=");
+    let long = encode_header(&"ü".repeat(20));
+    assert_eq!(long, format!("=?UTF-8?q?{}?=\n =?UTF-8?q?{}?=", "=C3=BC".repeat(10), "=C3=BC".repeat(10)));
+    assert_eq!(encode_address("Jörg <jorg@example.com>"), "=?UTF-8?q?J=C3=B6rg?= <jorg@example.com>");
+    assert_eq!(encode_address("Joe <joe@example.com>"), "Joe <joe@example.com>");
+
+    let mut mail = b"From: =?UTF-8?q?J=C3=B6rg?= <jorg@example.com>\nSubject: Test\n\nBody\n".to_vec();
+    add_mime_headers(&mut mail);
+    assert_eq!(mail, b"From: =?UTF-8?q?J=C3=B6rg?= <jorg@example.com>\nSubject: Test\n\nBody\n".to_vec());
+    let mut mail = "Subject: Test\n\nGrüße\n".as_bytes().to_vec();
+    add_mime_headers(&mut mail);
+    assert_eq!(String::from_utf8(mail).unwrap(), concat!(
+        "Subject: Test\n",
+        "MIME-Version: 1.0\n",
+        "Content-Type: text/plain; charset=UTF-8\n",
+        "Content-Transfer-Encoding: 8bit\n",
+        "\n",
+        "Grüße\n",
+    ));
+}
+
+fn write_recipients(out: &mut Vec<u8>, to: &[String], cc: &[String]) -> Result<()> {
+    for (header, addresses) in [("To", to), ("Cc", cc)].iter() {
+        if !addresses.is_empty() {
+            let addresses: Vec<String> = addresses.iter().map(|address| encode_address(address)).collect();
+            writeln!(out, "{}: {}", header, addresses.join(",\n    "))?;
+        }
+    }
+    Ok(())
+}
+
+// Write the base-commit and prerequisite-patch-id lines that tell a maintainer where the series
+// applies.
+fn write_base_info(out: &mut Vec<u8>, info: &BaseInfo) -> Result<()> {
+    writeln!(out, "base-commit: {}", info.base_commit)?;
+    for id in info.prerequisite_patch_ids.iter() {
+        writeln!(out, "prerequisite-patch-id: {}", id)?;
+    }
+    Ok(())
+}
+
+fn is_trailer(line: &str) -> bool {
+    match line.find(": ") {
+        Some(pos) => pos > 0 && line[..pos].chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+        None => false,
+    }
+}
+
+// Add the trailer `signoff` to a commit message body, unless it already ends with that trailer.
+// The trailer joins the last paragraph if that consists of trailers, or starts a new one.
+fn add_signoff(body: &str, signoff: &str) -> String {
+    let body = body.trim_end();
+    if body.is_empty() {
+        return format!("{}\n", signoff);
+    }
+    let last_paragraph = body.rsplit("\n\n").next().unwrap();
+    if last_paragraph.lines().last() == Some(signoff) {
+        return format!("{}\n", body);
+    }
+    let separator = if last_paragraph.lines().all(is_trailer) { "\n" } else { "\n\n" };
+    format!("{}{}{}\n", body, separator, signoff)
+}
+
+#[test]
+fn test_add_signoff() {
+    let signoff = "Signed-off-by: A U Thor <author@example.com>";
+    assert_eq!(add_signoff("", signoff), format!("{}\n", signoff));
+    assert_eq!(add_signoff("Explain.\n", signoff), format!("Explain.\n\n{}\n", signoff));
+    assert_eq!(
+        add_signoff("Explain: why.\n\nReviewed-by: Jane Doe <jane@example.com>\n", signoff),
+        format!("Explain: why.\n\nReviewed-by: Jane Doe <jane@example.com>\n{}\n", signoff),
+    );
+    let signed = format!("Explain.\n\n{}\n", signoff);
+    assert_eq!(add_signoff(&signed, signoff), signed);
+}
+
+const CC_TRAILERS: [&str; 5] = ["signed-off-by", "reviewed-by", "acked-by", "tested-by", "cc"];
+
+/// The addresses in the Signed-off-by, Reviewed-by, Acked-by, Tested-by, and Cc trailers of a
+/// commit message, in order, without any trailing "# comment".
+pub fn trailer_addresses(message: &str) -> Vec<String> {
+    message.lines().filter_map(|line| {
+        let pos = line.find(':')?;
+        if !CC_TRAILERS.iter().any(|t| t.eq_ignore_ascii_case(&line[..pos])) {
+            return None;
+        }
+        let value = line[pos + 1..].split('#').next().unwrap().trim();
+        if value.contains('@') { Some(value.to_string()) } else { None }
+    }).collect()
+}
+
+#[test]
+fn test_trailer_addresses() {
+    let message = concat!(
+        "Fix a bug\n",
+        "\n",
+        "Reported-by: Someone <someone@example.com>\n",
+        "Cc: stable@vger.kernel.org # 4.2+\n",
+        "Reviewed-by: Jane Doe <jane@example.com>\n",
+        "signed-off-by: A U Thor <author@example.com>\n",
+        "Acked-by: nobody\n",
+    );
+    assert_eq!(trailer_addresses(message), vec![
+        "stable@vger.kernel.org",
+        "Jane Doe <jane@example.com>",
+        "A U Thor <author@example.com>",
+    ]);
+}
+
+// Run a get_maintainer-style command on a file containing the message and diff of a commit, and
+// return the addresses it prints.
+fn run_cc_cmd(repo: &Repository, cmd: &str, commit: &Commit) -> Result<Vec<String>> {
+    let diff = repo.diff_tree_to_tree(
+        Some(&commit.parent(0)?.tree()?),
+        Some(&commit.tree()?),
+        None,
+    )?;
+    let dir = tempdir::TempDir::new("git-series-cc-cmd")?;
+    let path = dir.path().join(format!("{}.patch", commit.id()));
+    let mut file = std::fs::File::create(&path)?;
+    let message = commit.message().unwrap();
+    writeln!(file, "Subject: {}{}---", message, ensure_nl(message))?;
+    write_diff(&mut file, &DiffColors::plain(), &diff, false)?;
+    drop(file);
+
+    let output = cmd_maybe_shell(cmd, true).arg(&path).output()?;
+    if !output.status.success() {
+        return Err(format!("{} exited with status {}", cmd, output.status).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+// Compare addresses by their lowercased bare address, ignoring any name.
+fn address_key(address: &str) -> Option<String> {
+    parse_addresses(address).into_iter().next().map(|a| a.to_lowercase())
+}
+
+/// A "Changes since vN" section listing the messages of the series commits in the history of
+/// `version` but not of the version `since`, oldest first, or None if there are none.
+pub fn changes_since(repo: &Repository, since: &SentVersion, version: &SeriesVersion) -> Result<Option<String>> {
+    let sent: HashSet<Oid> = history_from(repo, since.series_commit)?.iter().map(|v| v.id()).collect();
+    let mut s = String::new();
+    for v in history_from(repo, version.id())?.iter().rev().filter(|v| !sent.contains(&v.id())) {
+        let message = String::from_utf8_lossy(v.commit().message_bytes()).into_owned();
+        let (subject, body) = split_message(&message);
+        writeln!(s, "- {}", subject).unwrap();
+        if !body.is_empty() {
+            writeln!(s).unwrap();
+            for line in body.lines() {
+                writeln!(s, "{}{}", if line.is_empty() { "" } else { "  " }, line).unwrap();
+            }
+        }
+    }
+    if s.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(format!("Changes since v{}:\n{}", since.version, s)))
+}
+
+/// Prepare one mail per patch of the series version `version`, plus a cover letter if the series
+/// has one.
+pub fn format_series(
+    repo: &Repository,
+    version: &SeriesVersion,
+    opts: &FormatOptions,
+    diffcolors: &DiffColors,
+) -> Result<Vec<Mail>> {
+    let config = repo.config()?.snapshot()?;
+    let no_from = opts.no_from;
+    let stree = version.tree();
+
+    let series = stree.get_name("series")
+        .ok_or("Internal error: series did not contain \"series\"")?;
+    let base = stree.get_name("base")
+        .ok_or("Cannot format series; no base set.\nUse \"git series base\" to set base.")?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
+    revwalk.push(series.id())?;
+    revwalk.hide(base.id())?;
+    let mut commits: Vec<Commit> = revwalk.map(|c| {
+        let id = c?;
+        let commit = repo.find_commit(id)?;
+        if commit.parent_ids().count() > 1 {
+            return Err(format!(
+                "Error: cannot format merge commit as patch:\n{}",
+                commit_summarize(repo, id)?,
+            ).into());
+        }
+        Ok(commit)
+    }).collect::<Result<_>>()?;
+    if commits.is_empty() {
+        return Err("No patches to format; series and base identical.".into());
+    }
+    let upstream = match version.upstream(repo)? {
+        Some(upstream) => Some(
+            repo.revparse_single(&upstream)
+                .map_err(|e| format!("Cannot find upstream {} of the series: {}", upstream, e.message()))?
+                .peel_to_commit()?
+                .id(),
+        ),
+        None => None,
+    };
+    let base_info = base_info(repo, base.id(), &version.dependencies(repo)?, upstream)?;
+
+    let committer = get_signature(&config, "COMMITTER")?;
+    let committer_name = committer.name().unwrap();
+    let committer_email = committer.email().unwrap();
+    let message_id_suffix = format!(
+        "{}.git-series.{}",
+        committer.when().seconds(),
+        committer_email,
+    );
+
+    // Harvest Cc addresses for each patch, skipping any address already on every mail, the
+    // sender's own address, and duplicates. The cover letter goes to all of them.
+    let mut every_mail: HashSet<String> = opts.to.iter().chain(opts.cc.iter())
+        .filter_map(|a| address_key(a))
+        .collect();
+    every_mail.insert(committer_email.to_lowercase());
+    let mut series_keys = every_mail.clone();
+    let mut series_cc = Vec::new();
+    let mut patch_ccs = Vec::new();
+    for commit in commits.iter() {
+        let mut candidates = Vec::new();
+        if opts.cc_trailers {
+            candidates.extend(trailer_addresses(commit.message().unwrap()));
+        }
+        if let Some(cmd) = opts.cc_cmd {
+            candidates.extend(run_cc_cmd(repo, cmd, commit)?);
+        }
+        let mut patch_keys = every_mail.clone();
+        let mut patch_cc = opts.cc.clone();
+        for address in candidates {
+            if let Some(key) = address_key(&address) {
+                if patch_keys.insert(key.clone()) {
+                    if series_keys.insert(key) {
+                        series_cc.push(address.clone());
+                    }
+                    patch_cc.push(address);
+                }
+            }
+        }
+        patch_ccs.push(patch_cc);
+    }
+    let cover_cc: Vec<String> = opts.cc.iter().chain(series_cc.iter()).cloned().collect();
+
+    let cover_entry = stree.get_name("cover");
+    let notes = version.notes(repo)?;
+    let changes = match opts.changes_since {
+        Some(since) => {
+            if cover_entry.is_none() {
+                return Err("Cannot add a \"Changes since\" section; the series has no cover letter.".into());
+            }
+            changes_since(repo, since, version)?
+        }
+        None => None,
+    };
+    if cover_entry.is_none() && (opts.interdiff.is_some() || opts.range_diff.is_some()) {
+        return Err("Cannot add an interdiff or range diff; the series has no cover letter.".into());
+    }
+    let mut in_reply_to_message_id = opts.in_reply_to
+        .map(|v| format!(
+            "{}{}{}",
+            if v.starts_with('<') { "" } else { "<" },
+            v,
+            if v.ends_with('>') { "" } else { ">" },
+        ));
+
+    let subject_prefix = if opts.rfc {
+        "RFC PATCH"
+    } else {
+        opts.subject_prefix.unwrap_or("PATCH")
+    };
+    let subject_patch = opts.reroll_count.map_or(
+        subject_prefix.to_string(),
+        |n| format!("{}{}v{}", subject_prefix, ensure_space(subject_prefix), n),
+    );
+    let file_prefix = opts.reroll_count.map_or("".to_string(), |n| format!("v{}-", n));
+
+    let num_width = commits.len().to_string().len();
+
+    let signature = mail_signature();
+
+    let mut mails = Vec::new();
+
+    if let Some(ref entry) = cover_entry {
+        let series_tree = repo.find_commit(series.id())?.tree().unwrap();
+        let base_tree = repo.find_commit(base.id())?.tree().unwrap();
+        let diff = repo.diff_tree_to_tree(Some(&base_tree), Some(&series_tree), None)?;
+        let stats = diffstat(&diff)?;
+        let series_shortlog = shortlog(&mut commits);
+
+        // The cover letter may place the generated sections itself; append the rest.
+        let cover_blob = repo.find_blob(entry.id())?;
+        let (content, used) = expand_placeholders(std::str::from_utf8(cover_blob.content())?, &[
+            ("shortlog", series_shortlog.trim_end()),
+            ("diffstat", stats.trim_end()),
+            ("base", &base.id().to_string()),
+            ("version", opts.reroll_count.unwrap_or("1")),
+            ("series_name", opts.series_name),
+            ("changes_since", changes.as_deref().unwrap_or("").trim_end()),
+        ]);
+        let (subject, body) = split_message(&content);
+
+        let mut out = Vec::new();
+        writeln!(out, "From {} Mon Sep 17 00:00:00 2001", version.id())?;
+        let cover_message_id = format!("<cover.{}.{}>", version.id(), message_id_suffix);
+        writeln!(out, "Message-Id: {}", cover_message_id)?;
+        if let Some(ref message_id) = in_reply_to_message_id {
+            writeln!(out, "In-Reply-To: {}", message_id)?;
+            writeln!(out, "References: {}", message_id)?;
+        }
+        in_reply_to_message_id = Some(cover_message_id.clone());
+        write_recipients(&mut out, &opts.to, &cover_cc)?;
+        writeln!(out, "From: {} <{}>", encode_header(committer_name), committer_email)?;
+        writeln!(out, "Date: {}", date_822(committer.when()))?;
+        writeln!(
+            out,
+            "Subject: [{}{}{:0>num_width$}/{}] {}\n",
+            subject_patch,
+            ensure_space(&subject_patch),
+            0,
+            commits.len(),
+            encode_header(subject),
+            num_width=num_width,
+        )?;
+        if !body.is_empty() {
+            writeln!(out, "{}", body)?;
+        }
+        if let Some(changes) = changes.filter(|_| !used.contains(&"changes_since")) {
+            writeln!(out, "{}", changes)?;
+        }
+        if !used.contains(&"shortlog") {
+            writeln!(out, "{}", series_shortlog)?;
+        }
+        if !used.contains(&"diffstat") {
+            writeln!(out, "{}", stats)?;
+        }
+        if let Some(ref previous) = opts.interdiff {
+            let old_tree = repo.find_commit(previous.series)?.tree()?;
+            let new_tree = repo.find_commit(series.id())?.tree()?;
+            let diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+            writeln!(out, "Interdiff against {}:", previous.label)?;
+            write_diff(&mut out, &DiffColors::plain(), &diff, false)?;
+            writeln!(out)?;
+        }
+        if let Some(ref previous) = opts.range_diff {
+            writeln!(out, "Range-diff against {}:", previous.label)?;
+            let plain = DiffColors::plain();
+            RangeDiff::new(repo, &plain, &opts.range_diff_options, (previous.base, previous.series), (base.id(), series.id()))?
+                .write_pairs(&mut out, &plain)?;
+            writeln!(out)?;
+        }
+        write_base_info(&mut out, &base_info)?;
+        writeln!(out, "{}", signature)?;
+        add_mime_headers(&mut out);
+        mails.push(Mail {
+            file_name: format!("{}0000-cover-letter.patch", file_prefix),
+            message_id: cover_message_id,
+            content: out,
+        });
+    }
+
+    let patch_subjects = commits.iter()
+        .map(|c| Ok((patch_id(repo, c)?, c.summary().unwrap_or("").to_string())))
+        .collect::<Result<Vec<_>>>()?;
+    for (commit_num, commit) in commits.iter().enumerate() {
+        let first_mail = commit_num == 0 && cover_entry.is_none();
+
+        let message = commit.message().unwrap();
+        let (subject, body) = split_message(message);
+        let commit_id = commit.id();
+        let commit_author = commit.author();
+        let commit_author_name = commit_author.name().unwrap();
+        let commit_author_email = commit_author.email().unwrap();
+        let summary_sanitized = sanitize_summary(subject);
+        let this_message_id = format!("<{}.{}>", commit_id, message_id_suffix);
+        let parent = commit.parent(0)?;
+        let diff = repo.diff_tree_to_tree(
+            Some(&parent.tree().unwrap()),
+            Some(&commit.tree().unwrap()),
+            None,
+        )?;
+        let stats = diffstat(&diff)?;
+
+        let mut out = Vec::new();
+        writeln!(out, "From {} Mon Sep 17 00:00:00 2001", commit_id)?;
+        writeln!(out, "Message-Id: {}", this_message_id)?;
+        if let Some(ref message_id) = in_reply_to_message_id {
+            writeln!(out, "In-Reply-To: {}", message_id)?;
+            writeln!(out, "References: {}", message_id)?;
+        }
+        if first_mail {
+            in_reply_to_message_id = Some(this_message_id.clone());
+        }
+        write_recipients(&mut out, &opts.to, &patch_ccs[commit_num])?;
+        if no_from {
+            writeln!(out, "From: {} <{}>", encode_header(commit_author_name), commit_author_email)?;
+        } else {
+            writeln!(out, "From: {} <{}>", encode_header(committer_name), committer_email)?;
+        }
+        writeln!(out, "Date: {}", date_822(commit_author.when()))?;
+        let prefix = if commits.len() == 1 && cover_entry.is_none() {
+            if subject_patch.is_empty() {
+                "".to_string()
+            } else {
+                format!("[{}] ", subject_patch)
+            }
+        } else {
+            format!(
+                "[{}{}{:0>num_width$}/{}] ",
+                subject_patch,
+                ensure_space(&subject_patch),
+                commit_num + 1,
+                commits.len(),
+                num_width=num_width,
+            )
+        };
+        writeln!(out, "Subject: {}{}\n", prefix, encode_header(subject))?;
+
+        if !no_from && (commit_author_name, commit_author_email) != (committer_name, committer_email) {
+            writeln!(out, "From: {} <{}>\n", commit_author_name, commit_author_email)?;
+        }
+        let body = if opts.signoff {
+            add_signoff(body, &format!("Signed-off-by: {} <{}>", committer_name, committer_email))
+        } else {
+            body.to_string()
+        };
+        if !body.is_empty() {
+            write!(out, "{}{}", body, ensure_nl(&body))?;
+        }
+        writeln!(out, "---")?;
+        let (id, ref summary) = patch_subjects[commit_num];
+        if let Some(note) = find_note(&notes, &patch_subjects, id, summary) {
+            writeln!(out, "{}", note.text)?;
+        }
+        writeln!(out, "{}", stats)?;
+        write_diff(&mut out, diffcolors, &diff, false)?;
+        if first_mail {
+            writeln!(out)?;
+            write_base_info(&mut out, &base_info)?;
+        }
+        writeln!(out, "{}", signature)?;
+        add_mime_headers(&mut out);
+        mails.push(Mail {
+            file_name: format!("{}{:04}-{}.patch", file_prefix, commit_num + 1, summary_sanitized),
+            message_id: this_message_id,
+            content: out,
+        });
+    }
+
+    Ok(mails)
+}
+
+// Commit two versions of the series "topic" with a cover letter: the first with the patches "Add
+// a" and "Add b", and the second, on a new base, with "Add b" changed and a new patch "Add c"
+// after it, committed in two steps. Returns the first version, recorded as sent as v1, and the
+// last version.
+#[cfg(test)]
+fn two_versions(t: &crate::testutil::TestRepo) -> (SentVersion, Oid) {
+    use crate::series::Series;
+
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    t.commit(&[("a", Some("1\n"))], "Add a");
+    t.commit(&[("b", Some("1\n"))], "Add b");
+    let series = Series::start(&t.repo, "topic").unwrap();
+    series.set_base(Some(base)).unwrap();
+    series.set_cover(Some("Add a, b and c\n\nSome letters.\n")).unwrap();
+    let v1 = series.commit("First version", true).unwrap();
+    let sent = SentVersion { version: "1".to_string(), series_commit: v1, message_id: "<v1@example.com>".to_string() };
+    series.record_version(sent.clone()).unwrap();
+
+    t.checkout(base);
+    let new_base = t.commit(&[("f", Some("2\n"))], "New base");
+    t.commit(&[("a", Some("1\n"))], "Add a");
+    t.commit(&[("b", Some("2\n"))], "Add b");
+    series.set_base(Some(new_base)).unwrap();
+    series.commit("Rework b\n\nUse 2 rather than 1.\n", true).unwrap();
+    t.commit(&[("c", Some("1\n"))], "Add c");
+    (sent, series.commit("Add c", true).unwrap())
+}
+
+#[cfg(test)]
+fn cover_letter(repo: &Repository, version: Oid, opts: &FormatOptions) -> String {
+    let version = SeriesVersion::find(repo, version).unwrap();
+    let mails = format_series(repo, &version, opts, &DiffColors::plain()).unwrap();
+    String::from_utf8(mails[0].content.clone()).unwrap()
+}
+
+#[test]
+fn test_changes_since() {
+    let t = crate::testutil::TestRepo::new();
+    let (sent, v2) = two_versions(&t);
+    let repo = &t.repo;
+    let changes = "Changes since v1:\n- Rework b\n\n  Use 2 rather than 1.\n- Add c\n";
+    let version = SeriesVersion::find(repo, v2).unwrap();
+    assert_eq!(changes_since(repo, &sent, &version).unwrap().as_deref(), Some(changes));
+    let v1 = SeriesVersion::find(repo, sent.series_commit).unwrap();
+    assert_eq!(changes_since(repo, &sent, &v1).unwrap(), None);
+
+    let opts = FormatOptions { changes_since: Some(&sent), ..Default::default() };
+    let cover = cover_letter(repo, v2, &opts);
+    assert!(cover.contains(&format!("\n\nSome letters.\n\n{}\nA U Thor (3):\n", changes)), "{}", cover);
+}
+
+#[test]
+fn test_cover_interdiff_range_diff() {
+    let t = crate::testutil::TestRepo::new();
+    let (sent, v2) = two_versions(&t);
+    let repo = &t.repo;
+    let v1 = SeriesVersion::find(repo, sent.series_commit).unwrap();
+    let previous = PreviousVersion { label: "v1".to_string(), base: v1.base().unwrap(), series: v1.series().unwrap() };
+    let opts = FormatOptions { interdiff: Some(previous.clone()), range_diff: Some(previous), ..Default::default() };
+    let cover = cover_letter(repo, v2, &opts);
+    let start = cover.find("Interdiff against v1:").unwrap();
+    let end = cover.find("base-commit: ").unwrap();
+    assert_eq!(&cover[start..end], concat!(
+        "Interdiff against v1:\n",
+        "diff --git a/b b/b\n",
+        "index d00491f..0cfbf08 100644\n",
+        "--- a/b\n",
+        "+++ b/b\n",
+        "@@ -1 +1 @@\n",
+        "-1\n",
+        "+2\n",
+        "diff --git a/c b/c\n",
+        "new file mode 100644\n",
+        "index 0000000..d00491f\n",
+        "--- /dev/null\n",
+        "+++ b/c\n",
+        "@@ -0,0 +1 @@\n",
+        "+1\n",
+        "diff --git a/f b/f\n",
+        "index d00491f..0cfbf08 100644\n",
+        "--- a/f\n",
+        "+++ b/f\n",
+        "@@ -1 +1 @@\n",
+        "-1\n",
+        "+2\n",
+        "\n",
+        "Range-diff against v1:\n",
+        "1: 79ffeef = 1: 7864db7 Add a\n",
+        "2: 0c44873 ! 2: 48903e6 Add b\n",
+        "@@ -5,4 +5,4 @@ new file mode 100644\n",
+        " --- /dev/null\n",
+        " +++ b/b\n",
+        " @@\n",
+        "-+1\n",
+        "++2\n",
+        "-: ------- > 3: ebcfe78 Add c\n",
+        "\n",
+    ));
+    assert!(cover.starts_with(&format!("From {} ", v2)));
+    assert!(cover[..start].contains("\n 3 files changed, 3 insertions(+)\n"));
+
+    let series = crate::series::Series::find(repo, "topic").unwrap();
+    series.set_cover(None).unwrap();
+    let v3 = SeriesVersion::find(repo, series.commit("Drop the cover letter", true).unwrap()).unwrap();
+    let err = format_series(repo, &v3, &opts, &DiffColors::plain()).err().unwrap();
+    assert_eq!(err.to_string(), "Cannot add an interdiff or range diff; the series has no cover letter.");
+}
+
+#[test]
+fn test_cover_range_diff_creation_factor() {
+    let t = crate::testutil::TestRepo::new();
+    let (sent, v2) = two_versions(&t);
+    let repo = &t.repo;
+    let v1 = SeriesVersion::find(repo, sent.series_commit).unwrap();
+    let previous = PreviousVersion { label: "v1".to_string(), base: v1.base().unwrap(), series: v1.series().unwrap() };
+    // With no cost to showing commits as added or deleted, the changed commit no longer matches.
+    let opts = FormatOptions {
+        range_diff: Some(previous),
+        range_diff_options: RangeDiffOptions { creation_factor: 0, ..Default::default() },
+        ..Default::default()
+    };
+    let cover = cover_letter(repo, v2, &opts);
+    let start = cover.find("Range-diff against v1:").unwrap();
+    assert!(cover[start..].starts_with(concat!(
+        "Range-diff against v1:\n",
+        "1: 79ffeef = 1: 7864db7 Add a\n",
+        "2: 0c44873 < -: ------- Add b\n",
+        "-: ------- > 2: 48903e6 Add b\n",
+        "-: ------- > 3: ebcfe78 Add c\n",
+        "\nbase-commit: ",
+    )), "{}", cover);
+}
@@ -0,0 +1,293 @@
+use std::path::Path;
+
+use crate::error::Result;
+
+/// One mail read from an mbox or patch file.
+#[derive(Clone, Debug)]
+pub struct ImportedMail {
+    /// The Subject header, decoded, including any "[PATCH ...]" prefix.
+    pub subject: String,
+    /// The mail body, after the headers, with any bytes that are not UTF-8 replaced.
+    pub body: String,
+    /// The complete mail, in mbox format, as `git am` accepts it.
+    pub raw: Vec<u8>,
+}
+
+impl ImportedMail {
+    /// True if this mail is a cover letter: its subject numbers it as patch 0, as in
+    /// "[PATCH v2 0/3]".
+    pub fn is_cover(&self) -> bool {
+        match subject_prefix(&self.subject) {
+            Some(prefix) => prefix.split_whitespace().last()
+                .and_then(|n| n.split('/').next())
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|c| c == b'0')),
+            None => false,
+        }
+    }
+
+    /// The "base-commit:" trailer of this mail, if any.
+    pub fn base_commit(&self) -> Option<&str> {
+        self.body.lines()
+            .filter_map(|line| line.strip_prefix("base-commit: "))
+            .map(str::trim)
+            .next()
+    }
+
+    /// The cover letter text for a cover letter mail: the subject without its "[PATCH ...]"
+    /// prefix, and the body without the shortlog, diffstat, "base-commit:" trailer, and
+    /// signature that `git series format` adds.
+    pub fn cover_text(&self) -> String {
+        let subject = match subject_prefix(&self.subject) {
+            Some(prefix) => self.subject[prefix.len() + 2..].trim_start(),
+            None => &self.subject,
+        };
+        let lines: Vec<&str> = self.body.lines().collect();
+        let mut end = lines.len();
+        for (n, line) in lines.iter().enumerate() {
+            let next = lines.get(n + 1).unwrap_or(&"");
+            if *line == "-- " || line.starts_with("base-commit: ") || is_shortlog_header(line) && next.starts_with("  ") {
+                end = n;
+                break;
+            }
+        }
+        let body = lines[..end].join("\n");
+        let body = body.trim();
+        if body.is_empty() {
+            format!("{}\n", subject)
+        } else {
+            format!("{}\n\n{}\n", subject, body)
+        }
+    }
+}
+
+// The text between the brackets of a "[PATCH ...]" subject prefix.
+fn subject_prefix(subject: &str) -> Option<&str> {
+    if !subject.starts_with('[') {
+        return None;
+    }
+    subject.find(']').map(|end| &subject[1..end])
+}
+
+// A shortlog header line has the form "Author Name (N):".
+fn is_shortlog_header(line: &str) -> bool {
+    line.strip_suffix("):")
+        .and_then(|rest| rest.rfind(" (").map(|pos| &rest[pos + 2..]))
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()))
+}
+
+// An mbox "From " separator line has an address (or commit ID) and a date with a time.
+fn is_from_line(line: &str) -> bool {
+    match line.strip_prefix("From ") {
+        Some(rest) => {
+            let mut words = rest.split_whitespace();
+            words.next().is_some() && words.any(|w| w.contains(':'))
+        }
+        None => false,
+    }
+}
+
+// Decode the RFC 2047 encoded words, such as "=?UTF-8?q?Gr=C3=BC=C3=9Fe?=", in a header.
+fn decode_header(value: &str) -> String {
+    fn decode_word(word: &str) -> Option<String> {
+        let mut parts = word.strip_prefix("=?")?.strip_suffix("?=")?.splitn(3, '?');
+        let (charset, encoding, text) = (parts.next()?, parts.next()?, parts.next()?);
+        let bytes = match encoding {
+            "q" | "Q" => {
+                let mut bytes = Vec::new();
+                let mut rest = text.as_bytes();
+                while let Some((&c, tail)) = rest.split_first() {
+                    match c {
+                        b'_' => bytes.push(b' '),
+                        b'=' if tail.len() >= 2 => {
+                            bytes.push(u8::from_str_radix(std::str::from_utf8(&tail[..2]).ok()?, 16).ok()?);
+                            rest = &tail[2..];
+                            continue;
+                        }
+                        _ => bytes.push(c),
+                    }
+                    rest = tail;
+                }
+                bytes
+            }
+            "b" | "B" => {
+                const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+                let mut bytes = Vec::new();
+                let (mut n, mut bits) = (0u32, 0);
+                for c in text.bytes().filter(|&c| c != b'=') {
+                    n = n << 6 | CHARS.iter().position(|&d| d == c)? as u32;
+                    bits += 6;
+                    if bits >= 8 {
+                        bits -= 8;
+                        bytes.push((n >> bits) as u8);
+                    }
+                }
+                bytes
+            }
+            _ => return None,
+        };
+        if charset.eq_ignore_ascii_case("iso-8859-1") || charset.eq_ignore_ascii_case("latin1") {
+            Some(bytes.iter().map(|&b| b as char).collect())
+        } else {
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
+    }
+
+    let mut result = String::new();
+    let mut pending_space = String::new();
+    let mut after_word = false;
+    for (n, token) in value.split(' ').enumerate() {
+        if n > 0 {
+            pending_space.push(' ');
+        }
+        match decode_word(token) {
+            Some(decoded) => {
+                // Whitespace between two encoded words does not appear in the decoded text.
+                if !after_word {
+                    result.push_str(&pending_space);
+                }
+                result.push_str(&decoded);
+                after_word = true;
+            }
+            None if token.is_empty() => continue,
+            None => {
+                result.push_str(&pending_space);
+                result.push_str(token);
+                after_word = false;
+            }
+        }
+        pending_space.clear();
+    }
+    result
+}
+
+fn parse_mail(raw: &[u8]) -> ImportedMail {
+    let text = String::from_utf8_lossy(raw);
+    let (headers, body) = match text.find("\n\n") {
+        Some(pos) => (&text[..pos], &text[pos + 2..]),
+        None => (&text[..], ""),
+    };
+    let mut subject: Option<String> = None;
+    for line in headers.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(s) = subject.as_mut() {
+                s.push(' ');
+                s.push_str(line.trim());
+            }
+        } else if let Some(value) = line.strip_prefix("Subject:") {
+            subject = Some(value.trim().to_string());
+        } else if subject.is_some() {
+            break;
+        }
+    }
+    ImportedMail {
+        subject: decode_header(&subject.unwrap_or_default()),
+        body: body.to_string(),
+        raw: raw.to_vec(),
+    }
+}
+
+// A mail from an mbox, without the blank lines that separate it from the next mail. Only whole
+// blank lines go: a line with a space may be context at the end of a patch.
+fn mbox_mail(raw: &[u8]) -> &[u8] {
+    let mut end = raw.len();
+    while end > 0 && raw[end - 1] == b'\n' {
+        end -= 1;
+    }
+    &raw[..(end + 1).min(raw.len())]
+}
+
+/// Split an mbox into its mails.
+pub fn split_mbox(content: &[u8]) -> Vec<ImportedMail> {
+    let mut mails = Vec::new();
+    let mut start = None;
+    let mut pos = 0;
+    for line in content.split_inclusive(|&c| c == b'\n') {
+        if is_from_line(String::from_utf8_lossy(line).trim_end()) {
+            if let Some(start) = start {
+                mails.push(parse_mail(mbox_mail(&content[start..pos])));
+            }
+            start = Some(pos);
+        }
+        pos += line.len();
+    }
+    if let Some(start) = start {
+        mails.push(parse_mail(mbox_mail(&content[start..])));
+    }
+    mails
+}
+
+/// Read mails from an mbox file, or from the "*.patch" files in a directory in name order, as
+/// written by `git series format`.
+pub fn read_mails(path: &Path) -> Result<Vec<ImportedMail>> {
+    if !path.is_dir() {
+        return Ok(split_mbox(&std::fs::read(path)?));
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.extension().is_some_and(|ext| ext == "patch") {
+            files.push(file);
+        }
+    }
+    files.sort();
+    let mut mails = Vec::new();
+    for file in files {
+        mails.extend(split_mbox(&std::fs::read(&file)?));
+    }
+    Ok(mails)
+}
+
+#[test]
+fn test_split_mbox() {
+    let mbox = concat!(
+        "From 1111111111111111111111111111111111111111 Mon Sep 17 00:00:00 2001\n",
+        "From: A U Thor <author@example.com>\n",
+        "Subject: [PATCH v2 0/2] Add a\n",
+        " feature\n",
+        "\n",
+        "This adds a feature.\n",
+        "\n",
+        "A U Thor (2):\n",
+        "  First\n",
+        "  Second\n",
+        "\n",
+        "base-commit: 2222222222222222222222222222222222222222\n",
+        "-- \n",
+        "git-series 0.9.1\n",
+        "\n",
+        "From 3333333333333333333333333333333333333333 Mon Sep 17 00:00:00 2001\n",
+        "Subject: [PATCH v2 1/2] First\n",
+        "\n",
+        "From the start.\n",
+    );
+    let mails = split_mbox(mbox.as_bytes());
+    assert_eq!(mails.len(), 2);
+    assert_eq!(mails[0].subject, "[PATCH v2 0/2] Add a feature");
+    assert!(mails[0].is_cover());
+    assert!(!mails[1].is_cover());
+    assert_eq!(mails[0].base_commit(), Some("2222222222222222222222222222222222222222"));
+    assert_eq!(mails[0].cover_text(), "Add a feature\n\nThis adds a feature.\n");
+    assert_eq!(mails[1].body, "From the start.\n");
+
+    // A patch without a signature keeps the space of its last context line, and a mail need not
+    // be UTF-8.
+    let mbox = [
+        &b"From 3333333333333333333333333333333333333333 Mon Sep 17 00:00:00 2001\n"[..],
+        b"Subject: =?ISO-8859-1?q?Gr=FC=DFe?= aus\n =?UTF-8?B?TcO8bmNoZW4=?=\n",
+        b"\n",
+        b"Gr\xfc\xdfe\n",
+        b"---\n",
+        b"@@ -1,2 +1,2 @@\n",
+        b"-a\n",
+        b"+b\n",
+        b" \n",
+        b"\n",
+        b"\n",
+    ].concat();
+    let mails = split_mbox(&mbox);
+    assert_eq!(mails[0].subject, "Grüße aus München");
+    assert_eq!(mails[0].raw, &mbox[..mbox.len() - 2]);
+    assert!(mails[0].raw.ends_with(b"+b\n \n"));
+    assert!(mails[0].body.starts_with("Gr\u{fffd}\u{fffd}e\n"));
+    assert_eq!(decode_header("=?UTF-8?q?a?= =?UTF-8?q?_b?= c =?UTF-8?q?d?="), "a b c d");
+}
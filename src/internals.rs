@@ -0,0 +1,171 @@
+use git2::{ObjectType, Reference, Repository, TreeBuilder};
+
+use crate::error::Result;
+use crate::util::{get_signature, notfound_to_none, parents_from_ids, reference_matching_opt};
+
+pub const SERIES_PREFIX: &str = "refs/heads/git-series/";
+pub const SHEAD_REF: &str = "refs/SHEAD";
+pub const STAGED_PREFIX: &str = "refs/git-series-internals/staged/";
+pub const WORKING_PREFIX: &str = "refs/git-series-internals/working/";
+pub const MERGE_PREFIX: &str = "refs/git-series-internals/merge/";
+
+pub const GIT_FILEMODE_BLOB: u32 = 0o100644;
+pub const GIT_FILEMODE_COMMIT: u32 = 0o160000;
+
+pub fn shead_series_name(shead: &Reference) -> Result<String> {
+    let shead_target = shead.symbolic_target().ok_or("SHEAD not a symbolic reference")?;
+    if !shead_target.starts_with(SERIES_PREFIX) {
+        return Err(format!("SHEAD does not start with {}", SERIES_PREFIX).into());
+    }
+    Ok(shead_target[SERIES_PREFIX.len()..].to_string())
+}
+
+/// The staged and working versions of a patch series, as tree builders over the git-series tree
+/// entries.
+pub struct Internals<'repo> {
+    name: String,
+    pub staged: TreeBuilder<'repo>,
+    pub working: TreeBuilder<'repo>,
+}
+
+impl<'repo> Internals<'repo> {
+    /// Read the internals of the current series, updating the working `series` to HEAD.
+    pub fn read(repo: &'repo Repository) -> Result<Self> {
+        let shead = repo.find_reference(SHEAD_REF)?;
+        let series_name = shead_series_name(&shead)?;
+        let mut internals = Internals::read_series(repo, &series_name)?;
+        internals.update_series(repo)?;
+        Ok(internals)
+    }
+
+    pub fn read_series(repo: &'repo Repository, series_name: &str) -> Result<Self> {
+        let committed_id = notfound_to_none(repo.refname_to_id(&format!("{}{}", SERIES_PREFIX, series_name)))?;
+        let maybe_get_ref = |prefix: &str| -> Result<TreeBuilder<'repo>> {
+            match notfound_to_none(repo.refname_to_id(&format!("{}{}", prefix, series_name)))?.or(committed_id) {
+                Some(id) => {
+                    let c = repo.find_commit(id)?;
+                    let t = c.tree()?;
+                    Ok(repo.treebuilder(Some(&t))?)
+                }
+                None => Ok(repo.treebuilder(None)?),
+            }
+        };
+        Ok(Internals {
+            name: series_name.to_string(),
+            staged: maybe_get_ref(STAGED_PREFIX)?,
+            working: maybe_get_ref(WORKING_PREFIX)?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn exists(repo: &'repo Repository, series_name: &str) -> Result<bool> {
+        for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX, MERGE_PREFIX].iter() {
+            let prefixed_name = format!("{}{}", prefix, series_name);
+            if notfound_to_none(repo.refname_to_id(&prefixed_name))?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Returns true if it had anything to copy.
+    pub fn copy(repo: &'repo Repository, source: &str, dest: &str) -> Result<bool> {
+        let mut copied_any = false;
+        for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX, MERGE_PREFIX].iter() {
+            let prefixed_source = format!("{}{}", prefix, source);
+            if let Some(r) = notfound_to_none(repo.find_reference(&prefixed_source))? {
+                let oid = r.target()
+                    .ok_or(format!("Internal error: \"{}\" is a symbolic reference", prefixed_source))?;
+                let prefixed_dest = format!("{}{}", prefix, dest);
+                repo.reference(
+                    &prefixed_dest,
+                    oid,
+                    false,
+                    &format!("copied from {}", prefixed_source),
+                )?;
+                copied_any = true;
+            }
+        }
+        Ok(copied_any)
+    }
+
+    // Returns true if it had anything to delete.
+    pub fn delete(repo: &'repo Repository, series_name: &str) -> Result<bool> {
+        let mut deleted_any = false;
+        for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX, MERGE_PREFIX].iter() {
+            let prefixed_name = format!("{}{}", prefix, series_name);
+            if let Some(mut r) = notfound_to_none(repo.find_reference(&prefixed_name))? {
+                r.delete()?;
+                deleted_any = true;
+            }
+        }
+        Ok(deleted_any)
+    }
+
+    pub fn update_series(&mut self, repo: &'repo Repository) -> Result<()> {
+        let head_id = repo.refname_to_id("HEAD")?;
+        self.working.insert("series", head_id, GIT_FILEMODE_COMMIT as i32)?;
+        Ok(())
+    }
+
+    pub fn write(&self, repo: &'repo Repository) -> Result<()> {
+        let config = repo.config()?;
+        let author = get_signature(&config, "AUTHOR")?;
+        let committer = get_signature(&config, "COMMITTER")?;
+
+        let maybe_commit = |prefix: &str, tb: &TreeBuilder| -> Result<()> {
+            let tree_id = tb.write()?;
+            let refname = format!("{}{}", prefix, self.name);
+            let old_commit_id = notfound_to_none(repo.refname_to_id(&refname))?;
+            if let Some(id) = old_commit_id {
+                let c = repo.find_commit(id)?;
+                if c.tree_id() == tree_id {
+                    return Ok(());
+                }
+            }
+            let tree = repo.find_tree(tree_id)?;
+            let mut parents = Vec::new();
+            // Include all commits from tree, to keep them reachable and fetchable. Include base,
+            // because series might not have it as an ancestor; we don't enforce that until commit.
+            for e in tree.iter() {
+                if e.kind() == Some(ObjectType::Commit) {
+                    parents.push(e.id());
+                }
+            }
+            let parents = parents_from_ids(repo, parents)?;
+            let parents_ref: Vec<&_> = parents.iter().collect();
+            let commit_id = repo.commit(None, &author, &committer, &refname, &tree, &parents_ref)?;
+            repo.reference_ensure_log(&refname)?;
+            reference_matching_opt(
+                repo,
+                &refname,
+                commit_id,
+                true,
+                old_commit_id,
+                &format!("commit: {}", refname),
+            )?;
+            Ok(())
+        };
+        maybe_commit(STAGED_PREFIX, &self.staged)?;
+        maybe_commit(WORKING_PREFIX, &self.working)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_merge_ref_exists() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let id = t.commit(&[("f", Some("1\n"))], "Base");
+    assert!(!Internals::exists(repo, "topic").unwrap());
+    // A series with only an in-progress merge still exists, so copy and delete agree with it.
+    repo.reference(&format!("{}topic", MERGE_PREFIX), id, false, "test").unwrap();
+    assert!(Internals::exists(repo, "topic").unwrap());
+    assert!(Internals::copy(repo, "topic", "copy").unwrap());
+    assert!(Internals::exists(repo, "copy").unwrap());
+    assert!(Internals::delete(repo, "topic").unwrap());
+    assert!(!Internals::exists(repo, "topic").unwrap());
+}
@@ -0,0 +1,507 @@
+//! Machine-readable records describing series, their status, their history, and series diffs.
+//!
+//! These records form a stable output format: fields may be added, but existing fields keep their
+//! names and meanings. Object IDs appear as full hexadecimal strings.
+
+use chrono::offset::TimeZone;
+use git2::{Delta, Oid, Repository, Signature, Tree};
+use serde::Serialize;
+
+use crate::config::SeriesConfig;
+use crate::depends::Dependency;
+use crate::diff::{CommitPair, DiffColors, PairStatus, RangeDiff, RangeDiffOptions};
+use crate::error::Result;
+use crate::remote::{list_remote_series, sync_status, SyncStatus};
+use crate::series::{list_series, EntryChange, Series, SeriesVersion};
+use crate::upstream::UpstreamPatch;
+use crate::versions::SentVersion;
+
+fn oid_string(id: Option<Oid>) -> Option<String> {
+    id.map(|id| id.to_string())
+}
+
+fn tree_entry(tree: &Tree, name: &str) -> Option<String> {
+    oid_string(tree.get_name(name).map(|e| e.id()))
+}
+
+/// A series in the list of all series.
+#[derive(Clone, Debug, Serialize)]
+pub struct SeriesRecord {
+    pub name: String,
+    pub current: bool,
+    /// The last series commit, or null if the series has no commits yet.
+    pub head: Option<String>,
+    /// The base in the working version of the series.
+    pub base: Option<String>,
+    /// The series tip in the working version of the series.
+    pub series: Option<String>,
+    /// The remote-tracking versions of this series, and how the last series commit compares.
+    pub remotes: Vec<RemoteStatusRecord>,
+}
+
+/// How the last series commit of a local series compares to a remote-tracking version of it.
+#[derive(Clone, Debug, Serialize)]
+pub struct RemoteStatusRecord {
+    pub remote: String,
+    /// The remote-tracking series commit.
+    pub id: String,
+    /// The number of series commits only in the local series.
+    pub ahead: usize,
+    /// The number of series commits only in the remote-tracking series.
+    pub behind: usize,
+}
+
+impl SeriesRecord {
+    pub fn new(series: &Series) -> Result<Self> {
+        let internals = series.internals()?;
+        let base = internals.working.get("base")?.map(|e| e.id());
+        let series_tip = internals.working.get("series")?.map(|e| e.id());
+        let head = series.head()?.map(|h| h.id());
+        let mut remotes = Vec::new();
+        if let Some(head) = head {
+            for remote in list_remote_series(series.repo())?.into_iter().filter(|r| r.name == series.name()) {
+                let (ahead, behind) = match sync_status(series.repo(), head, remote.id)? {
+                    SyncStatus::UpToDate => (0, 0),
+                    SyncStatus::Ahead(n) => (n, 0),
+                    SyncStatus::Behind(n) => (0, n),
+                    SyncStatus::Diverged { ahead, behind } => (ahead, behind),
+                };
+                remotes.push(RemoteStatusRecord { remote: remote.remote, id: remote.id.to_string(), ahead, behind });
+            }
+        }
+        Ok(SeriesRecord {
+            name: series.name().to_string(),
+            current: series.is_current()?,
+            head: oid_string(head),
+            base: oid_string(base),
+            series: oid_string(series_tip),
+            remotes,
+        })
+    }
+
+    /// Records for all series in the repository, sorted by name.
+    pub fn list(repo: &Repository) -> Result<Vec<Self>> {
+        list_series(repo)?.iter()
+            .map(|name| SeriesRecord::new(&Series::find(repo, name)?))
+            .collect()
+    }
+}
+
+/// A change to one git-series tree entry.
+#[derive(Clone, Debug, Serialize)]
+pub struct EntryChangeRecord {
+    pub entry: String,
+    /// One of "added", "deleted", "modified", or another lowercase git delta status.
+    pub status: String,
+}
+
+impl EntryChangeRecord {
+    pub fn new(change: &EntryChange) -> Self {
+        EntryChangeRecord {
+            entry: change.entry.clone(),
+            status: delta_name(change.status),
+        }
+    }
+}
+
+fn delta_name(delta: Delta) -> String {
+    format!("{:?}", delta).to_lowercase()
+}
+
+fn entry_changes(changes: &[EntryChange]) -> Vec<EntryChangeRecord> {
+    changes.iter().map(EntryChangeRecord::new).collect()
+}
+
+/// A prerequisite of a series.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DependencyRecord {
+    Series { name: String },
+    Commit { id: String },
+}
+
+impl DependencyRecord {
+    pub fn new(dependency: &Dependency) -> Self {
+        match dependency {
+            Dependency::Series(name) => DependencyRecord::Series { name: name.clone() },
+            Dependency::Commit(id) => DependencyRecord::Commit { id: id.to_string() },
+        }
+    }
+}
+
+/// One setting of a series.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConfigRecord {
+    pub key: String,
+    pub value: String,
+}
+
+fn config_records(config: &SeriesConfig) -> Vec<ConfigRecord> {
+    config.entries.iter().map(|(key, value)| ConfigRecord { key: key.clone(), value: value.clone() }).collect()
+}
+
+/// The status of a series, as shown by `git series status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct StatusRecord {
+    pub name: String,
+    /// The last series commit, or null for a series with no commits yet.
+    pub head: Option<String>,
+    pub base: Option<String>,
+    pub series: Option<String>,
+    /// The series commit being merged, or null if no series merge is in progress.
+    pub merging: Option<String>,
+    /// The prerequisites in the working version of the series.
+    pub depends_on: Vec<DependencyRecord>,
+    /// The upstream branch in the working version of the series.
+    pub upstream: Option<String>,
+    /// The settings in the working version of the series, in order.
+    pub config: Vec<ConfigRecord>,
+    /// Changes from the last series commit to the staged version.
+    pub staged: Vec<EntryChangeRecord>,
+    /// Changes from the staged version to the working version.
+    pub unstaged: Vec<EntryChangeRecord>,
+}
+
+impl StatusRecord {
+    pub fn new(series: &Series) -> Result<Self> {
+        let status = series.status()?;
+        let info = SeriesRecord::new(series)?;
+        Ok(StatusRecord {
+            name: info.name,
+            head: info.head,
+            base: info.base,
+            series: info.series,
+            merging: oid_string(series.merging()?),
+            depends_on: series.dependencies()?.iter().map(DependencyRecord::new).collect(),
+            upstream: series.upstream()?,
+            config: config_records(&series.config()?),
+            staged: entry_changes(&status.staged),
+            unstaged: entry_changes(&status.unstaged),
+        })
+    }
+}
+
+/// A person and time, from a commit's author or committer.
+#[derive(Clone, Debug, Serialize)]
+pub struct SignatureRecord {
+    pub name: String,
+    pub email: String,
+    /// RFC 3339 date.
+    pub date: String,
+}
+
+impl SignatureRecord {
+    pub fn new(sig: &Signature) -> Self {
+        let when = sig.when();
+        let offset = chrono::offset::fixed::FixedOffset::east(when.offset_minutes() * 60);
+        SignatureRecord {
+            name: String::from_utf8_lossy(sig.name_bytes()).into_owned(),
+            email: String::from_utf8_lossy(sig.email_bytes()).into_owned(),
+            date: offset.timestamp(when.seconds(), 0).to_rfc3339(),
+        }
+    }
+}
+
+/// One commit of a patch series within a range diff.
+#[derive(Clone, Debug, Serialize)]
+pub struct PatchRecord {
+    /// 1-based position of the commit within its version of the series.
+    pub position: usize,
+    pub id: String,
+    pub summary: String,
+}
+
+/// One row of a range diff.
+#[derive(Clone, Debug, Serialize)]
+pub struct CommitPairRecord {
+    /// One of "unchanged", "modified", "added", or "deleted".
+    pub status: &'static str,
+    pub old: Option<PatchRecord>,
+    pub new: Option<PatchRecord>,
+}
+
+/// The correspondence between the commits of two versions of a patch series.
+#[derive(Clone, Debug, Serialize)]
+pub struct RangeDiffRecord {
+    /// The number of commits both versions share at the start of the series; these do not appear
+    /// in `pairs`.
+    pub common: usize,
+    pub pairs: Vec<CommitPairRecord>,
+}
+
+impl RangeDiffRecord {
+    pub fn new(range_diff: &RangeDiff) -> Self {
+        let patch = |commits: &[git2::Commit], i: Option<usize>| i.map(|i| {
+            let commit = &commits[i];
+            PatchRecord {
+                position: range_diff.ncommon + i + 1,
+                id: commit.id().to_string(),
+                summary: String::from_utf8_lossy(commit.summary_bytes().unwrap_or(b"")).into_owned(),
+            }
+        });
+        let pair = |p: &CommitPair| CommitPairRecord {
+            status: match p.status {
+                PairStatus::Unchanged => "unchanged",
+                PairStatus::Modified => "modified",
+                PairStatus::Added => "added",
+                PairStatus::Deleted => "deleted",
+            },
+            old: patch(&range_diff.commits1, p.old),
+            new: patch(&range_diff.commits2, p.new),
+        };
+        RangeDiffRecord {
+            common: range_diff.ncommon,
+            pairs: range_diff.pairs.iter().map(pair).collect(),
+        }
+    }
+}
+
+/// The difference between two versions of a series: changed tree entries, plus the range diff if
+/// both versions have a base and series.
+#[derive(Clone, Debug, Serialize)]
+pub struct SeriesDiffRecord {
+    pub changes: Vec<EntryChangeRecord>,
+    /// Null if either version lacks a base or series.
+    pub range_diff: Option<RangeDiffRecord>,
+}
+
+impl SeriesDiffRecord {
+    pub fn new(
+        repo: &Repository,
+        opts: &RangeDiffOptions,
+        tree1: Option<&Tree>,
+        tree2: Option<&Tree>,
+    ) -> Result<Self> {
+        let diff = repo.diff_tree_to_tree(tree1, tree2, None)?;
+        let mut changes = Vec::new();
+        for delta in diff.deltas() {
+            changes.push(EntryChangeRecord {
+                entry: delta.old_file().path().unwrap().to_string_lossy().into_owned(),
+                status: delta_name(delta.status()),
+            });
+        }
+        let range = |t: Option<&Tree>| t.and_then(|t| Some((t.get_name("base")?.id(), t.get_name("series")?.id())));
+        let range_diff = match (range(tree1), range(tree2)) {
+            (Some(range1), Some(range2)) => {
+                Some(RangeDiffRecord::new(&RangeDiff::new(repo, &DiffColors::plain(), opts, range1, range2)?))
+            }
+            _ => None,
+        };
+        Ok(SeriesDiffRecord { changes, range_diff })
+    }
+}
+
+/// One patch of a series and whether upstream applied it, as shown by
+/// `git series upstream-status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct UpstreamPatchRecord {
+    /// One of "applied", "modified", or "pending".
+    pub status: &'static str,
+    pub patch: PatchRecord,
+    /// The upstream commit that applied the patch.
+    pub upstream: Option<String>,
+}
+
+impl UpstreamPatchRecord {
+    pub fn new(position: usize, patch: &UpstreamPatch) -> Self {
+        UpstreamPatchRecord {
+            status: patch.status.name(),
+            patch: PatchRecord {
+                position,
+                id: patch.commit.id().to_string(),
+                summary: String::from_utf8_lossy(patch.commit.summary_bytes().unwrap_or(b"")).into_owned(),
+            },
+            upstream: patch.upstream.as_ref().map(|c| c.id().to_string()),
+        }
+    }
+}
+
+/// One version of a series, as shown by `git series log`.
+#[derive(Clone, Debug, Serialize)]
+pub struct VersionRecord {
+    pub id: String,
+    /// Previous versions of the series; more than one for a series merge commit.
+    pub parents: Vec<String>,
+    pub author: SignatureRecord,
+    pub committer: SignatureRecord,
+    pub message: String,
+    pub base: Option<String>,
+    pub series: Option<String>,
+    /// The blob ID of the cover letter.
+    pub cover: Option<String>,
+    /// The blob ID of the recipients.
+    pub recipients: Option<String>,
+    /// The blob ID of the patch notes.
+    pub notes: Option<String>,
+    /// The blob ID of the list of prerequisites.
+    pub depends_on: Option<String>,
+    /// The blob ID of the upstream branch.
+    pub upstream: Option<String>,
+    /// The blob ID of the series settings.
+    pub config: Option<String>,
+    /// The blob ID of the record of versions sent.
+    pub versions: Option<String>,
+    /// The diff from the first parent version, if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<SeriesDiffRecord>,
+    /// For a series merge commit, the diffs from each parent version in turn, if requested.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parent_diffs: Vec<SeriesDiffRecord>,
+}
+
+impl VersionRecord {
+    pub fn new(repo: &Repository, version: &SeriesVersion, with_diff: bool) -> Result<Self> {
+        let commit = version.commit();
+        let parents = version.history_parents();
+        let mut parent_diffs = Vec::new();
+        let mut diff = None;
+        if with_diff {
+            for id in parents.iter() {
+                let parent_tree = repo.find_commit(*id)?.tree()?;
+                parent_diffs.push(SeriesDiffRecord::new(repo, &RangeDiffOptions::default(), Some(&parent_tree), Some(version.tree()))?);
+            }
+            diff = match parent_diffs.first() {
+                Some(first) => Some(first.clone()),
+                None => Some(SeriesDiffRecord::new(repo, &RangeDiffOptions::default(), None, Some(version.tree()))?),
+            };
+            if parent_diffs.len() < 2 {
+                parent_diffs.clear();
+            }
+        }
+        Ok(VersionRecord {
+            id: version.id().to_string(),
+            parents: parents.iter().map(|id| id.to_string()).collect(),
+            author: SignatureRecord::new(&commit.author()),
+            committer: SignatureRecord::new(&commit.committer()),
+            message: String::from_utf8_lossy(commit.message_bytes()).into_owned(),
+            base: tree_entry(version.tree(), "base"),
+            series: tree_entry(version.tree(), "series"),
+            cover: tree_entry(version.tree(), "cover"),
+            recipients: tree_entry(version.tree(), "recipients"),
+            notes: tree_entry(version.tree(), "notes"),
+            depends_on: tree_entry(version.tree(), "depends-on"),
+            upstream: tree_entry(version.tree(), "upstream"),
+            config: tree_entry(version.tree(), "config"),
+            versions: tree_entry(version.tree(), "versions"),
+            diff,
+            parent_diffs,
+        })
+    }
+}
+
+/// A version of a series recorded as sent, as shown by `git series versions`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SentVersionRecord {
+    pub version: String,
+    /// The series commit sent as this version.
+    pub id: String,
+    /// The Message-Id of the first mail sent, which begins the thread.
+    pub message_id: String,
+}
+
+impl SentVersionRecord {
+    pub fn new(sent: &SentVersion) -> Self {
+        SentVersionRecord {
+            version: sent.version.clone(),
+            id: sent.series_commit.to_string(),
+            message_id: sent.message_id.clone(),
+        }
+    }
+}
+
+#[test]
+fn test_series_and_diff_records() {
+    use serde_json::{json, to_value};
+
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let a = t.commit(&[("a", Some("1\n2\n3\n"))], "Add a");
+    let b1 = t.commit(&[("b", Some("1\n2\n3\n"))], "Add b");
+    let topic = Series::start(repo, "topic").unwrap();
+    topic.set_base(Some(base)).unwrap();
+    let v1 = topic.commit("First version", true).unwrap();
+    t.checkout(a);
+    let b2 = t.commit(&[("b", Some("1\ntwo\n3\n"))], "Add b");
+    let c = t.commit(&[("c", Some("1\n"))], "Add c");
+    let v2 = topic.commit("Second version", true).unwrap();
+    repo.reference("refs/remotes/origin/git-series/topic", v1, false, "test").unwrap();
+    Series::start(repo, "empty").unwrap();
+
+    // The new current series has no series commits yet; "topic" is one series commit ahead of
+    // its remote-tracking version.
+    assert_eq!(to_value(SeriesRecord::list(repo).unwrap()).unwrap(), json!([
+        {"name": "empty", "current": true, "head": null, "base": null, "series": c.to_string(), "remotes": []},
+        {
+            "name": "topic", "current": false, "head": v2.to_string(), "base": base.to_string(),
+            "series": c.to_string(),
+            "remotes": [{"remote": "origin", "id": v1.to_string(), "ahead": 1, "behind": 0}],
+        },
+    ]));
+
+    let tree = |id| repo.find_commit(id).unwrap().tree().unwrap();
+    let diff = |old: Option<Oid>, new| {
+        let (old, new) = (old.map(tree), tree(new));
+        to_value(SeriesDiffRecord::new(repo, &RangeDiffOptions::default(), old.as_ref(), Some(&new)).unwrap()).unwrap()
+    };
+    let patch = |position, id: Oid, summary| json!({"position": position, "id": id.to_string(), "summary": summary});
+    assert_eq!(diff(Some(v1), v2), json!({
+        "changes": [{"entry": "series", "status": "modified"}],
+        "range_diff": {"common": 1, "pairs": [
+            {"status": "modified", "old": patch(2, b1, "Add b"), "new": patch(2, b2, "Add b")},
+            {"status": "added", "old": null, "new": patch(3, c, "Add c")},
+        ]},
+    }));
+    // Without a base and series in both versions, there is no range diff.
+    assert_eq!(diff(None, v2), json!({
+        "changes": [{"entry": "base", "status": "added"}, {"entry": "series", "status": "added"}],
+        "range_diff": null,
+    }));
+}
+
+#[test]
+fn test_status_and_version_records() {
+    use crate::versions::SentVersion;
+    use serde_json::{json, to_value};
+
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let tip = t.commit(&[("f", Some("2\n"))], "Change");
+    let series = Series::start(repo, "topic").unwrap();
+    series.set_base(Some(base)).unwrap();
+    series.set_upstream(Some("origin/main")).unwrap();
+    series.set_dependencies(&[Dependency::Commit(base)]).unwrap();
+    let mut config = SeriesConfig::default();
+    config.set("format.rfc", "true").unwrap();
+    series.set_config(&config).unwrap();
+    let id = series.commit("Initial version", true).unwrap();
+    series.record_version(SentVersion { version: "1".to_string(), series_commit: id, message_id: "<m>".to_string() })
+        .unwrap();
+
+    let status = to_value(StatusRecord::new(&series).unwrap()).unwrap();
+    assert_eq!(status, json!({
+        "name": "topic",
+        "head": id.to_string(),
+        "base": base.to_string(),
+        "series": tip.to_string(),
+        "merging": null,
+        "depends_on": [{"type": "commit", "id": base.to_string()}],
+        "upstream": "origin/main",
+        "config": [{"key": "format.rfc", "value": "true"}],
+        "staged": [],
+        "unstaged": [{"entry": "versions", "status": "added"}],
+    }));
+
+    let version = SeriesVersion::find(repo, id).unwrap();
+    let record = to_value(VersionRecord::new(repo, &version, false).unwrap()).unwrap();
+    let tree = version.tree();
+    for key in ["cover", "recipients", "notes", "versions"].iter() {
+        assert_eq!(record[key], json!(null));
+    }
+    for (key, entry) in [("depends_on", "depends-on"), ("upstream", "upstream"), ("config", "config")].iter() {
+        assert_eq!(record[key], json!(tree.get_name(entry).unwrap().id().to_string()));
+    }
+    assert_eq!(record["message"], json!("Initial version"));
+    assert!(record.get("diff").is_none());
+}
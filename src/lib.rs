@@ -0,0 +1,73 @@
+//! Track patch series in git.
+//!
+//! This library provides the implementation of `git series`: reading and writing the git-series
+//! internals, looking up series, manipulating their `base`, `cover`, `depends-on`, `recipients`
+//! and `series` entries, walking their history, and formatting and sending them as email. See
+//! INTERNALS.md for the storage format.
+
+mod config;
+mod depends;
+mod diff;
+mod error;
+mod format;
+mod import;
+mod internals;
+pub mod json;
+mod merge;
+mod notes;
+mod output;
+mod patchid;
+mod pull;
+mod rebase;
+mod recipients;
+mod remote;
+mod restack;
+mod send;
+mod series;
+#[cfg(test)]
+mod testutil;
+mod upstream;
+mod util;
+mod versions;
+
+pub use crate::config::{ConfigKind, SeriesConfig, SERIES_CONFIG_KEYS};
+pub use crate::depends::{base_info, dependency_commits, BaseInfo, Dependency};
+pub use crate::diff::{
+    diffstat, get_commits, write_commit_range_diff, write_diff, write_series_diff, CommitPair, DiffColors,
+    PairStatus, RangeDiff, RangeDiffOptions,
+};
+pub use crate::error::{Error, Result};
+pub use crate::format::{
+    changes_since, format_series, mail_signature, shortlog, trailer_addresses, FormatOptions, Mail,
+    PreviousVersion,
+};
+pub use crate::import::{read_mails, split_mbox, ImportedMail};
+pub use crate::internals::{
+    shead_series_name, Internals, GIT_FILEMODE_BLOB, GIT_FILEMODE_COMMIT, MERGE_PREFIX, SERIES_PREFIX,
+    SHEAD_REF, STAGED_PREFIX, WORKING_PREFIX,
+};
+pub use crate::merge::{merge_series, series_merge_base, EntryConflict, MergeOutcome};
+pub use crate::notes::{find_note, PatchNote};
+pub use crate::output::Output;
+pub use crate::patchid::{patch_id, tree_patch_id};
+pub use crate::pull::PullRequest;
+pub use crate::rebase::{autosquash, parse_todo, write_todo, Rebase, RebaseStep, RebaseStop, TodoItem};
+pub use crate::recipients::Recipients;
+pub use crate::remote::{
+    fetch_refs, list_remote_series, push_refspecs, remote_internals_refname, remote_series_refname, sync_status,
+    RemoteSeries, SyncStatus,
+};
+pub use crate::restack::{previous_ranges, restack_dependents, Restack, RestackStatus};
+pub use crate::send::{
+    mail_recipients, mail_sender, parse_addresses, send_mails, strip_mbox_from, SendEmailConfig, SmtpConnection,
+};
+pub use crate::series::{
+    current_series_name, detach, history_from, list_series, EntryChange, Series, SeriesVersion, Status,
+};
+pub use crate::upstream::{upstream_status, UpstreamPatch, UpstreamStatus};
+pub use crate::util::{
+    cmd_maybe_shell, commit_obj_summarize, commit_obj_summarize_components, commit_summarize,
+    commit_summarize_components, config_values, date_822, ensure_nl, get_editor, get_signature, notfound_to_none,
+    run_editor, split_message,
+};
+pub use crate::versions::{next_version, previous_version, SentVersion};
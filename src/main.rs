@@ -1,50 +1,27 @@
-use std::cmp::max;
-use std::env;
-use std::ffi::{OsStr, OsString};
 use std::fmt::Write as FmtWrite;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write as IoWrite;
-use std::process::Command;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
 use ansi_term::Style;
-use chrono::offset::TimeZone;
-use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
-use git2::{Commit, Config, Delta, Diff, Object, ObjectType, Oid, Reference, Repository, Tree, TreeBuilder};
-use quick_error::quick_error;
-
-quick_error! {
-    #[derive(Debug)]
-    enum Error {
-        Git2(err: git2::Error) {
-            from()
-            cause(err)
-            display("{}", err)
-        }
-        IO(err: std::io::Error) {
-            from()
-            cause(err)
-            display("{}", err)
-        }
-        Munkres(err: munkres::Error) {
-            from()
-            display("{:?}", err)
-        }
-        Msg(msg: String) {
-            from()
-            from(s: &'static str) -> (s.to_string())
-            description(msg)
-            display("{}", msg)
-        }
-        Utf8Error(err: std::str::Utf8Error) {
-            from()
-            cause(err)
-            display("{}", err)
-        }
-    }
-}
-
-type Result<T> = std::result::Result<T, Error>;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use git2::{Commit, Object, ObjectType, Repository};
+
+use git_series::json::{
+    RangeDiffRecord, SentVersionRecord, SeriesDiffRecord, SeriesRecord, StatusRecord, UpstreamPatchRecord, VersionRecord,
+};
+use git_series::{
+    autosquash, commit_obj_summarize, commit_summarize, commit_summarize_components, config_values,
+    current_series_name, ensure_nl, fetch_refs, format_series, list_remote_series, mail_recipients, merge_series,
+    next_version, parse_todo, previous_ranges, previous_version, push_refspecs, read_mails, restack_dependents,
+    run_editor, send_mails, sync_status, upstream_status, write_series_diff, write_todo, Dependency, DiffColors,
+    EntryChange, FormatOptions, Internals, MergeOutcome, Output, PreviousVersion, PullRequest, RangeDiff,
+    RangeDiffOptions, Rebase, RebaseStep, RebaseStop, RemoteSeries, Restack, RestackStatus, Result,
+    SendEmailConfig, SentVersion, Series, SeriesVersion, SyncStatus, TodoItem, SERIES_PREFIX,
+    SHEAD_REF, STAGED_PREFIX, WORKING_PREFIX,
+};
 
 const COMMIT_MESSAGE_COMMENT: &str = "
 # Please enter the commit message for your changes. Lines starting
@@ -53,6 +30,14 @@ const COMMIT_MESSAGE_COMMENT: &str = "
 const COVER_LETTER_COMMENT: &str = "
 # Please enter the cover letter for your changes. Lines starting
 # with '#' will be ignored, and an empty message aborts the change.
+#
+# \"git series format\" replaces these placeholders:
+# {shortlog}, {diffstat}, {base}, {version}, {series_name}, {changes_since}
+";
+const NOTE_COMMENT: &str = "
+# Please enter the note for this patch, such as the changes since the last
+# version. Lines starting with '#' will be ignored, and an empty note aborts
+# the change.
 ";
 const REBASE_COMMENT: &str = "\
 #
@@ -71,6 +56,11 @@ const REBASE_COMMENT: &str = "\
 #
 # However, if you remove everything, the rebase will be aborted.
 ";
+const REBASE_RESUME: &str = concat!(
+    "Then run \"git series rebase --continue\".\n",
+    "To skip this commit, run \"git series rebase --skip\"; ",
+    "to abandon the rebase, run \"git series rebase --abort\".",
+);
 const SCISSOR_LINE: &str = "\
 # ------------------------ >8 ------------------------";
 const SCISSOR_COMMENT: &str = "\
@@ -78,290 +68,117 @@ const SCISSOR_COMMENT: &str = "\
 # Everything below will be removed.
 ";
 
-const SHELL_METACHARS: &str = "|&;<>()$`\\\"' \t\n*?[#~=%";
-
-const SERIES_PREFIX: &str = "refs/heads/git-series/";
-const SHEAD_REF: &str = "refs/SHEAD";
-const STAGED_PREFIX: &str = "refs/git-series-internals/staged/";
-const WORKING_PREFIX: &str = "refs/git-series-internals/working/";
-
-const GIT_FILEMODE_BLOB: u32 = 0o100644;
-const GIT_FILEMODE_COMMIT: u32 = 0o160000;
-
-fn commit_obj_summarize_components(commit: &mut Commit) -> Result<(String, String)> {
-    let short_id_buf = commit.as_object().short_id()?;
-    let short_id = short_id_buf.as_str().unwrap();
-    let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap());
-    Ok((short_id.to_string(), summary.to_string()))
-}
-
-fn commit_summarize_components(repo: &Repository, id: Oid) -> Result<(String, String)> {
-    let mut commit = repo.find_commit(id)?;
-    commit_obj_summarize_components(&mut commit)
-}
-
-fn commit_obj_summarize(commit: &mut Commit) -> Result<String> {
-    let (short_id, summary) = commit_obj_summarize_components(commit)?;
-    Ok(format!("{} {}", short_id, summary))
-}
-
-fn commit_summarize(repo: &Repository, id: Oid) -> Result<String> {
-    let mut commit = repo.find_commit(id)?;
-    commit_obj_summarize(&mut commit)
-}
-
-fn notfound_to_none<T>(result: std::result::Result<T, git2::Error>) -> Result<Option<T>> {
-    match result {
-        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
-        Err(e) => Err(e.into()),
-        Ok(x) => Ok(Some(x)),
-    }
-}
-
-// If current_id_opt is Some, acts like reference_matching.  If current_id_opt is None, acts like
-// reference.
-fn reference_matching_opt<'repo>(
-    repo: &'repo Repository,
-    name: &str,
-    id: Oid,
-    force: bool,
-    current_id_opt: Option<Oid>,
-    log_message: &str,
-) -> Result<Reference<'repo>> {
-    Ok(match current_id_opt {
-        None => repo.reference(name, id, force, log_message)?,
-        Some(current_id) => repo.reference_matching(name, id, force, current_id, log_message)?,
-    })
+fn diff_empty(diff: &git2::Diff) -> bool {
+    diff.deltas().len() == 0
 }
 
-fn parents_from_ids(repo: &Repository, mut parents: Vec<Oid>) -> Result<Vec<Commit>> {
-    parents.sort();
-    parents.dedup();
-    parents.drain(..).map(|id| Ok(repo.find_commit(id)?)).collect()
+fn add(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let changes: Vec<_> = m.values_of("change").unwrap().collect();
+    series.add(&changes)
 }
 
-struct Internals<'repo> {
-    staged: TreeBuilder<'repo>,
-    working: TreeBuilder<'repo>,
+fn unadd(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let changes: Vec<_> = m.values_of("change").unwrap().collect();
+    series.unadd(&changes)
 }
 
-impl<'repo> Internals<'repo> {
-    fn read(repo: &'repo Repository) -> Result<Self> {
-        let shead = repo.find_reference(SHEAD_REF)?;
-        let series_name = shead_series_name(&shead)?;
-        let mut internals = Internals::read_series(repo, &series_name)?;
-        internals.update_series(repo)?;
-        Ok(internals)
-    }
-
-    fn read_series(repo: &'repo Repository, series_name: &str) -> Result<Self> {
-        let committed_id = notfound_to_none(repo.refname_to_id(&format!("{}{}", SERIES_PREFIX, series_name)))?;
-        let maybe_get_ref = |prefix: &str| -> Result<TreeBuilder<'repo>> {
-            match notfound_to_none(repo.refname_to_id(&format!("{}{}", prefix, series_name)))?.or(committed_id) {
-                Some(id) => {
-                    let c = repo.find_commit(id)?;
-                    let t = c.tree()?;
-                    Ok(repo.treebuilder(Some(&t))?)
-                }
-                None => Ok(repo.treebuilder(None)?),
-            }
-        };
-        Ok(Internals {
-            staged: maybe_get_ref(STAGED_PREFIX)?,
-            working: maybe_get_ref(WORKING_PREFIX)?,
-        })
-    }
-
-    fn exists(repo: &'repo Repository, series_name: &str) -> Result<bool> {
-        for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
-            let prefixed_name = format!("{}{}", prefix, series_name);
-            if notfound_to_none(repo.refname_to_id(&prefixed_name))?.is_some() {
-                return Ok(true);
-            }
-        }
-        Ok(false)
-    }
-
-    // Returns true if it had anything to copy.
-    fn copy(repo: &'repo Repository, source: &str, dest: &str) -> Result<bool> {
-        let mut copied_any = false;
-        for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
-            let prefixed_source = format!("{}{}", prefix, source);
-            if let Some(r) = notfound_to_none(repo.find_reference(&prefixed_source))? {
-                let oid = r.target()
-                    .ok_or(format!("Internal error: \"{}\" is a symbolic reference", prefixed_source))?;
-                let prefixed_dest = format!("{}{}", prefix, dest);
-                repo.reference(
-                    &prefixed_dest,
-                    oid,
-                    false,
-                    &format!("copied from {}", prefixed_source),
-                )?;
-                copied_any = true;
-            }
-        }
-        Ok(copied_any)
-    }
-
-    // Returns true if it had anything to delete.
-    fn delete(repo: &'repo Repository, series_name: &str) -> Result<bool> {
-        let mut deleted_any = false;
-        for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
-            let prefixed_name = format!("{}{}", prefix, series_name);
-            if let Some(mut r) = notfound_to_none(repo.find_reference(&prefixed_name))? {
-                r.delete()?;
-                deleted_any = true;
-            }
-        }
-        Ok(deleted_any)
-    }
-
-    fn update_series(&mut self, repo: &'repo Repository) -> Result<()> {
-        let head_id = repo.refname_to_id("HEAD")?;
-        self.working.insert("series", head_id, GIT_FILEMODE_COMMIT as i32)?;
-        Ok(())
-    }
-
-    fn write(&self, repo: &'repo Repository) -> Result<()> {
-        let config = repo.config()?;
-        let author = get_signature(&config, "AUTHOR")?;
-        let committer = get_signature(&config, "COMMITTER")?;
-
-        let shead = repo.find_reference(SHEAD_REF)?;
-        let series_name = shead_series_name(&shead)?;
-        let maybe_commit = |prefix: &str, tb: &TreeBuilder| -> Result<()> {
-            let tree_id = tb.write()?;
-            let refname = format!("{}{}", prefix, series_name);
-            let old_commit_id = notfound_to_none(repo.refname_to_id(&refname))?;
-            if let Some(id) = old_commit_id {
-                let c = repo.find_commit(id)?;
-                if c.tree_id() == tree_id {
-                    return Ok(());
-                }
-            }
-            let tree = repo.find_tree(tree_id)?;
-            let mut parents = Vec::new();
-            // Include all commits from tree, to keep them reachable and fetchable. Include base,
-            // because series might not have it as an ancestor; we don't enforce that until commit.
-            for e in tree.iter() {
-                if e.kind() == Some(ObjectType::Commit) {
-                    parents.push(e.id());
-                }
-            }
-            let parents = parents_from_ids(repo, parents)?;
-            let parents_ref: Vec<&_> = parents.iter().collect();
-            let commit_id = repo.commit(None, &author, &committer, &refname, &tree, &parents_ref)?;
-            repo.reference_ensure_log(&refname)?;
-            reference_matching_opt(
-                repo,
-                &refname,
-                commit_id,
-                true,
-                old_commit_id,
-                &format!("commit: {}", refname),
-            )?;
-            Ok(())
-        };
-        maybe_commit(STAGED_PREFIX, &self.staged)?;
-        maybe_commit(WORKING_PREFIX, &self.working)?;
-        Ok(())
-    }
+fn json_format(m: &ArgMatches) -> bool {
+    m.value_of("format") == Some("json")
 }
 
-fn diff_empty(diff: &Diff) -> bool {
-    diff.deltas().len() == 0
+fn write_json<T: serde::Serialize>(out: &mut Output, value: &T) -> Result<()> {
+    serde_json::to_writer_pretty(&mut *out, value).map_err(|e| e.to_string())?;
+    writeln!(out)?;
+    Ok(())
 }
 
-fn add(repo: &Repository, m: &ArgMatches) -> Result<()> {
-    let mut internals = Internals::read(repo)?;
-    for file in m.values_of_os("change").unwrap() {
-        match internals.working.get(file)? {
-            Some(entry) => {
-                internals.staged.insert(file, entry.id(), entry.filemode())?;
-            }
-            None => {
-                if internals.staged.get(file)?.is_some() {
-                    internals.staged.remove(file)?;
-                }
-            }
-        }
-    }
-    internals.write(repo)
+// Describe how a local series commit relates to a remote-tracking series, like "git branch -vv".
+fn sync_summary(repo: &Repository, local: git2::Oid, remote: &RemoteSeries) -> Result<String> {
+    let status = match sync_status(repo, local, remote.id)? {
+        SyncStatus::UpToDate => String::new(),
+        SyncStatus::Ahead(n) => format!(": ahead {}", n),
+        SyncStatus::Behind(n) => format!(": behind {}", n),
+        SyncStatus::Diverged { ahead, behind } => format!(": ahead {}, behind {}", ahead, behind),
+    };
+    Ok(format!("{}{}", remote.shorthand(), status))
 }
 
-fn unadd(repo: &Repository, m: &ArgMatches) -> Result<()> {
-    let shead = repo.find_reference(SHEAD_REF)?;
-    let started = {
-        let shead_target = shead.symbolic_target().ok_or("SHEAD not a symbolic reference")?;
-        notfound_to_none(repo.find_reference(shead_target))?.is_some()
+// Summarize the tip, base, and number of patches of a git-series tree, for "git series -v".
+fn tree_summary(repo: &Repository, base: Option<git2::Oid>, series: Option<git2::Oid>) -> Result<String> {
+    let tip = match series {
+        Some(id) => commit_summarize(repo, id)?,
+        None => "(no series)".to_string(),
     };
-
-    let mut internals = Internals::read(repo)?;
-    if started {
-        let shead_commit = shead.peel_to_commit()?;
-        let shead_tree = shead_commit.tree()?;
-
-        for file in m.values_of("change").unwrap() {
-            match shead_tree.get_name(file) {
-                Some(entry) => {
-                    internals.staged.insert(file, entry.id(), entry.filemode())?;
-                }
-                None => {
-                    internals.staged.remove(file)?;
-                }
-            }
-        }
-    } else {
-        for file in m.values_of("change").unwrap() {
-            internals.staged.remove(file)?
+    Ok(match (base, series) {
+        (Some(base), Some(series)) => {
+            let npatches = git_series::get_commits(repo, base, series)?.len();
+            let (base_short_id, _) = commit_summarize_components(repo, base)?;
+            format!("{} (base {}, {} patch{})", tip, base_short_id, npatches, if npatches == 1 { "" } else { "es" })
         }
-    }
-    internals.write(repo)
-}
-
-fn shead_series_name(shead: &Reference) -> Result<String> {
-    let shead_target = shead.symbolic_target().ok_or("SHEAD not a symbolic reference")?;
-    if !shead_target.starts_with(SERIES_PREFIX) {
-        return Err(format!("SHEAD does not start with {}", SERIES_PREFIX).into());
-    }
-    Ok(shead_target[SERIES_PREFIX.len()..].to_string())
+        _ => format!("{} (no base)", tip),
+    })
 }
 
-fn series(out: &mut Output, repo: &Repository) -> Result<()> {
-    let mut refs = Vec::new();
-    for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
-        let l = prefix.len();
-        for r in repo.references_glob(&[prefix, "*"].concat())?.names() {
-            refs.push(r?[l..].to_string());
-        }
+fn series(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    if json_format(m) {
+        return write_json(out, &SeriesRecord::list(repo)?);
     }
-    let shead_target = if let Some(shead) = notfound_to_none(repo.find_reference(SHEAD_REF))? {
-        Some(shead_series_name(&shead)?)
-    } else {
-        None
-    };
-    refs.extend(shead_target.clone().into_iter());
-    refs.sort();
-    refs.dedup();
+    let refs = git_series::list_series(repo)?;
+    let remote_series = list_remote_series(repo)?;
+    let shead_target = current_series_name(repo)?;
+    let verbose = m.is_present("verbose");
 
     let config = repo.config()?.snapshot()?;
     out.auto_pager(&config, "branch", false)?;
     let color_current = out.get_color(&config, "branch", "current", "green")?;
     let color_plain = out.get_color(&config, "branch", "plain", "normal")?;
+    let color_remote = out.get_color(&config, "branch", "remote", "red")?;
+    let width = refs.iter().map(|name| name.len())
+        .chain(remote_series.iter().map(|r| "remotes/".len() + r.shorthand().len()))
+        .max().unwrap_or(0);
     for name in refs.iter() {
         let (star, color) = if Some(name) == shead_target.as_ref() {
             ('*', color_current)
         } else {
             (' ', color_plain)
         };
-        let new = if notfound_to_none(repo.refname_to_id(&format!("{}{}", SERIES_PREFIX, name)))?.is_none() {
+        let series = Series::find(repo, name)?;
+        let head = series.head()?;
+        let new = if head.is_none() {
             " (new, no commits yet)"
         } else {
             ""
         };
-        writeln!(out, "{} {}{}", star, color.paint(name as &str), new)?;
+        let mut sync = Vec::new();
+        if let Some(ref head) = head {
+            for remote in remote_series.iter().filter(|r| &r.name == name) {
+                sync.push(sync_summary(repo, head.id(), remote)?);
+            }
+        }
+        let sync = if sync.is_empty() { String::new() } else { format!(" [{}]", sync.join(", ")) };
+        if verbose {
+            let internals = series.internals()?;
+            let base = internals.working.get("base")?.map(|e| e.id());
+            let series_id = internals.working.get("series")?.map(|e| e.id());
+            let name = format!("{:width$}", name, width = width);
+            writeln!(out, "{} {}{} {}{}", star, color.paint(name), sync, tree_summary(repo, base, series_id)?, new)?;
+        } else {
+            writeln!(out, "{} {}{}{}", star, color.paint(name as &str), new, sync)?;
+        }
+    }
+    for remote in remote_series.iter() {
+        let name = format!("remotes/{}", remote.shorthand());
+        if verbose {
+            let version = SeriesVersion::find(repo, remote.id)?;
+            let name = format!("{:width$}", name, width = width);
+            writeln!(out, "  {} {}", color_remote.paint(name), tree_summary(repo, version.base(), version.series())?)?;
+        } else {
+            writeln!(out, "  {}", color_remote.paint(name))?;
+        }
     }
-    if refs.is_empty() {
+    if refs.is_empty() && remote_series.is_empty() {
         writeln!(out, "No series; use \"git series start <name>\" to start")?;
     }
     Ok(())
@@ -373,19 +190,7 @@ fn start(repo: &Repository, m: &ArgMatches) -> Result<()> {
     let head_id = head_commit.as_object().id();
 
     let name = m.value_of("name").unwrap();
-    if Internals::exists(repo, name)? {
-        return Err(format!("Series {} already exists.\nUse checkout to resume working on an existing patch series.", name).into());
-    }
-    let prefixed_name = &[SERIES_PREFIX, name].concat();
-    repo.reference_symbolic(
-        SHEAD_REF,
-        &prefixed_name,
-        true,
-        &format!("git series start {}", name),
-    )?;
-
-    let internals = Internals::read(repo)?;
-    internals.write(repo)?;
+    Series::start(repo, name)?;
 
     // git status parses this reflog string; the prefix must remain "checkout: moving from ".
     repo.reference(
@@ -394,7 +199,7 @@ fn start(repo: &Repository, m: &ArgMatches) -> Result<()> {
         true,
         &format!("checkout: moving from {} to {} (git series start {})", head_id, head_id, name),
     )?;
-    println!("HEAD is now detached at {}", commit_summarize(&repo, head_id)?);
+    println!("HEAD is now detached at {}", commit_summarize(repo, head_id)?);
     Ok(())
 }
 
@@ -465,12 +270,12 @@ fn checkout(repo: &Repository, m: &ArgMatches) -> Result<()> {
     let head = repo.head()?;
     let head_commit = head.peel_to_commit()?;
     let head_id = head_commit.as_object().id();
-    println!("Previous HEAD position was {}", commit_summarize(&repo, head_id)?);
+    println!("Previous HEAD position was {}", commit_summarize(repo, head_id)?);
 
     let prefixed_name = &[SERIES_PREFIX, name].concat();
     repo.reference_symbolic(
         SHEAD_REF,
-        &prefixed_name,
+        prefixed_name,
         true,
         &format!("git series checkout {}", name),
     )?;
@@ -483,311 +288,123 @@ fn checkout(repo: &Repository, m: &ArgMatches) -> Result<()> {
         true,
         &format!("checkout: moving from {} to {} (git series checkout {})", head_id, new_head_id, name),
     )?;
-    println!("HEAD is now detached at {}", commit_summarize(&repo, new_head_id)?);
+    println!("HEAD is now detached at {}", commit_summarize(repo, new_head_id)?);
 
     Ok(())
 }
 
 fn base(repo: &Repository, m: &ArgMatches) -> Result<()> {
-    let mut internals = Internals::read(repo)?;
-
-    let current_base_id = match internals.working.get("base")? {
-        Some(entry) => entry.id(),
-        _ => Oid::zero(),
-    };
+    let series = Series::require_current(repo)?;
+    let current_base = series.base()?;
 
     if !m.is_present("delete") && !m.is_present("base") {
-        if current_base_id.is_zero() {
-            return Err("Patch series has no base set".into());
-        } else {
-            println!("{}", current_base_id);
-            return Ok(());
+        match current_base {
+            None => return Err("Patch series has no base set".into()),
+            Some(id) => {
+                println!("{}", id);
+                return Ok(());
+            }
         }
     }
 
-    let new_base_id = if m.is_present("delete") {
-        Oid::zero()
+    let new_base = if m.is_present("delete") {
+        None
     } else {
         let base = m.value_of("base").unwrap();
         let base_object = repo.revparse_single(base)?;
         let base_commit = base_object.peel(ObjectType::Commit)?;
-        let base_id = base_commit.id();
-        let s_working_series = internals.working.get("series")?
-            .ok_or("Could not find entry \"series\" in working vesion of current series")?;
-        if base_id != s_working_series.id()
-            && !repo.graph_descendant_of(s_working_series.id(), base_id)?
-        {
-            return Err(format!(
-                "Cannot set base to {}: not an ancestor of the patch series {}",
-                base,
-                s_working_series.id(),
-            ).into());
-        }
-        base_id
+        Some(base_commit.id())
     };
 
-    if current_base_id == new_base_id {
+    if current_base == new_base {
         println!("Base unchanged");
         return Ok(());
     }
 
-    if !current_base_id.is_zero() {
-        println!("Previous base was {}", commit_summarize(&repo, current_base_id)?);
+    series.set_base(new_base)?;
+
+    if let Some(id) = current_base {
+        println!("Previous base was {}", commit_summarize(repo, id)?);
     }
 
-    if new_base_id.is_zero() {
-        internals.working.remove("base")?;
-        internals.write(repo)?;
-        println!("Cleared patch series base");
-    } else {
-        internals.working.insert("base", new_base_id, GIT_FILEMODE_COMMIT as i32)?;
-        internals.write(repo)?;
-        println!("Set patch series base to {}", commit_summarize(&repo, new_base_id)?);
+    match new_base {
+        None => println!("Cleared patch series base"),
+        Some(id) => println!("Set patch series base to {}", commit_summarize(repo, id)?),
     }
 
     Ok(())
 }
 
 fn detach(repo: &Repository) -> Result<()> {
-    match repo.find_reference(SHEAD_REF) {
-        Ok(mut r) => r.delete()?,
-        Err(_) => return Err("No current patch series to detach from.".into()),
-    }
-    Ok(())
+    git_series::detach(repo)
 }
 
-fn delete(repo: &Repository, m: &ArgMatches) -> Result<()> {
-    let name = m.value_of("name").unwrap();
-    if let Ok(shead) = repo.find_reference(SHEAD_REF) {
-        let shead_target = shead_series_name(&shead)?;
-        if shead_target == name {
-            return Err(format!(
-                "Cannot delete the current series \"{}\"; detach first.",
-                name,
-            ).into());
+fn series_config(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let mut config = series.config()?;
+    if m.is_present("list") {
+        for (key, value) in config.entries.iter() {
+            writeln!(out, "{}={}", key, value)?;
         }
+        return Ok(());
     }
-    if Internals::delete(repo, name)? == false {
-        return Err(format!("Nothing to delete: series \"{}\" does not exist.", name).into());
-    }
-    Ok(())
-}
-
-fn do_diff(out: &mut Output, repo: &Repository) -> Result<()> {
-    let internals = Internals::read(&repo)?;
-    let config = repo.config()?.snapshot()?;
-    out.auto_pager(&config, "diff", true)?;
-    let diffcolors = DiffColors::new(out, &config)?;
-
-    let working_tree = repo.find_tree(internals.working.write()?)?;
-    let staged_tree = repo.find_tree(internals.staged.write()?)?;
-
-    write_series_diff(out, repo, &diffcolors, Some(&staged_tree), Some(&working_tree))
-}
-
-fn get_editor(config: &Config) -> Result<OsString> {
-    if let Some(e) = env::var_os("GIT_EDITOR") {
-        return Ok(e);
-    }
-    if let Ok(e) = config.get_path("core.editor") {
-        return Ok(e.into());
-    }
-    let terminal_is_dumb = match env::var_os("TERM") {
-        None => true,
-        Some(t) => t.as_os_str() == "dumb",
-    };
-    if !terminal_is_dumb {
-        if let Some(e) = env::var_os("VISUAL") {
-            return Ok(e);
+    let key = m.value_of("key").unwrap();
+    if m.is_present("unset") {
+        if !config.unset(key) {
+            return Err(format!("Series configuration {} is not set", key).into());
         }
-    }
-    if let Some(e) = env::var_os("EDITOR") {
-        return Ok(e);
-    }
-    if terminal_is_dumb {
-        return Err("TERM unset or \"dumb\" but EDITOR unset".into());
-    }
-    Ok("vi".into())
-}
-
-// Get the pager to use; with for_cmd set, get the pager for use by the
-// specified git command.  If get_pager returns None, don't use a pager.
-fn get_pager(config: &Config, for_cmd: &str, default: bool) -> Option<OsString> {
-    if !atty::is(atty::Stream::Stdout) {
-        return None;
-    }
-    // pager.cmd can contain a boolean (if false, force no pager) or a
-    // command-specific pager; only treat it as a command if it doesn't parse
-    // as a boolean.
-    let maybe_pager = config.get_path(&format!("pager.{}", for_cmd)).ok();
-    let (cmd_want_pager, cmd_pager) = maybe_pager.map_or((default, None), |p|
-            if let Ok(b) = Config::parse_bool(&p) {
-                (b, None)
-            } else {
-                (true, Some(p))
-            }
-        );
-    if !cmd_want_pager {
-        return None;
-    }
-    let pager = if let Some(e) = env::var_os("GIT_PAGER") {
-        Some(e)
-    } else if let Some(p) = cmd_pager {
-        Some(p.into())
-    } else if let Ok(e) = config.get_path("core.pager") {
-        Some(e.into())
-    } else if let Some(e) = env::var_os("PAGER") {
-        Some(e)
-    } else {
-        Some("less".into())
-    };
-    pager.and_then(|p| if p.is_empty() || p == "cat" { None } else { Some(p) })
-}
-
-/// Construct a Command, using the shell if the command contains shell metachars
-fn cmd_maybe_shell<S: AsRef<OsStr>>(program: S, args: bool) -> Command {
-    if program.as_ref().to_string_lossy().contains(|c| SHELL_METACHARS.contains(c)) {
-        let mut cmd = Command::new("sh");
-        cmd.arg("-c");
-        if args {
-            let mut program_with_args = program.as_ref().to_os_string();
-            program_with_args.push(" \"$@\"");
-            cmd.arg(program_with_args).arg(program);
+    } else if let Some(value) = m.value_of("value") {
+        if m.is_present("add") {
+            config.add(key, value)?;
         } else {
-            cmd.arg(program);
+            config.set(key, value)?;
         }
-        cmd
     } else {
-        Command::new(program)
-    }
-}
-
-fn run_editor<S: AsRef<OsStr>>(config: &Config, filename: S) -> Result<()> {
-    let editor = get_editor(&config)?;
-    let editor_status = cmd_maybe_shell(editor, true).arg(&filename).status()?;
-    if !editor_status.success() {
-        return Err(format!("Editor exited with status {}", editor_status).into());
-    }
-    Ok(())
-}
-
-struct Output {
-    pager: Option<std::process::Child>,
-    include_stderr: bool,
-}
-
-impl Output {
-    fn new() -> Self {
-        Output { pager: None, include_stderr: false }
-    }
-
-    fn auto_pager(&mut self, config: &Config, for_cmd: &str, default: bool) -> Result<()> {
-        if let Some(pager) = get_pager(config, for_cmd, default) {
-            let mut cmd = cmd_maybe_shell(pager, false);
-            cmd.stdin(std::process::Stdio::piped());
-            if env::var_os("LESS").is_none() {
-                cmd.env("LESS", "FRX");
-            }
-            if env::var_os("LV").is_none() {
-                cmd.env("LV", "-c");
-            }
-            let child = cmd.spawn()?;
-            self.pager = Some(child);
-            self.include_stderr = atty::is(atty::Stream::Stderr);
-        }
-        Ok(())
-    }
-
-    // Get a color to write text with, taking git configuration into account.
-    //
-    // config: the configuration to determine the color from.
-    // command: the git command to act like.
-    // slot: the color "slot" of that git command to act like.
-    // default: the color to use if not configured.
-    fn get_color(
-        &self,
-        config: &Config,
-        command: &str,
-        slot: &str,
-        default: &str,
-    ) -> Result<Style> {
-        if !cfg!(unix) {
-            return Ok(Style::new());
-        }
-        let color_ui = notfound_to_none(config.get_str("color.ui"))?.unwrap_or("auto");
-        let color_cmd = notfound_to_none(config.get_str(&format!("color.{}", command)))?.unwrap_or(color_ui);
-        if color_cmd == "never" || Config::parse_bool(color_cmd) == Ok(false) {
-            return Ok(Style::new());
-        }
-        if self.pager.is_some() {
-            let color_pager = notfound_to_none(config.get_bool("color.pager"))?.unwrap_or(true);
-            if !color_pager {
-                return Ok(Style::new());
-            }
-        } else if !atty::is(atty::Stream::Stdout) {
-            return Ok(Style::new());
+        let values = if m.is_present("get-all") { config.get_all(key) } else { config.get(key).into_iter().collect() };
+        if values.is_empty() {
+            return Err(format!("Series configuration {} is not set", key).into());
         }
-        let cfg = format!("color.{}.{}", command, slot);
-        let color = notfound_to_none(config.get_str(&cfg))?.unwrap_or(default);
-        colorparse::parse(color).map_err(|e| format!("Error parsing {}: {}", cfg, e).into())
-    }
-
-    fn write_err(&mut self, msg: &str) {
-        if self.include_stderr {
-            if write!(self, "{}", msg).is_err() {
-                eprint!("{}", msg);
-            }
-        } else {
-            eprint!("{}", msg);
+        for value in values {
+            writeln!(out, "{}", value)?;
         }
+        return Ok(());
     }
+    series.set_config(&config)
 }
 
-impl Drop for Output {
-    fn drop(&mut self) {
-        if let Some(ref mut child) = self.pager {
-            let status = child.wait().unwrap();
-            if !status.success() {
-                eprintln!("Pager exited with status {}", status);
-            }
-        }
+fn delete(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let name = m.value_of("name").unwrap();
+    if !Internals::exists(repo, name)? {
+        return Err(format!("Nothing to delete: series \"{}\" does not exist.", name).into());
     }
+    Series::find(repo, name)?.delete()
 }
 
-impl IoWrite for Output {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self.pager {
-            Some(ref mut child) => child.stdin.as_mut().unwrap().write(buf),
-            None => std::io::stdout().write(buf),
-        }
-    }
+fn do_diff(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let internals = series.internals()?;
+    let working_tree = repo.find_tree(internals.working.write()?)?;
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        match self.pager {
-            Some(ref mut child) => child.stdin.as_mut().unwrap().flush(),
-            None => std::io::stdout().flush(),
-        }
+    // With no revisions, diff staged to working; with one, diff that version to working.
+    let (old_tree, new_tree) = match (m.value_of("old"), m.value_of("new")) {
+        (None, _) => (repo.find_tree(internals.staged.write()?)?, working_tree),
+        (Some(old), None) => (series.resolve_version(old)?.tree().clone(), working_tree),
+        (Some(old), Some(new)) => (
+            series.resolve_version(old)?.tree().clone(),
+            series.resolve_version(new)?.tree().clone(),
+        ),
+    };
+
+    let opts = range_diff_options(m)?;
+    if json_format(m) {
+        return write_json(out, &SeriesDiffRecord::new(repo, &opts, Some(&old_tree), Some(&new_tree))?);
     }
-}
 
-fn get_signature(config: &Config, which: &str) -> Result<git2::Signature<'static>> {
-    let name_var = ["GIT_", which, "_NAME"].concat();
-    let email_var = ["GIT_", which, "_EMAIL"].concat();
-    let which_lc = which.to_lowercase();
-    let name = env::var(&name_var)
-        .or_else(|_| config.get_string("user.name"))
-        .or_else(|_| Err(format!(
-            "Could not determine {} name: checked ${} and user.name in git config",
-            which_lc, name_var,
-        )))?;
-    let email = env::var(&email_var)
-        .or_else(|_| config.get_string("user.email"))
-        .or_else(|_| env::var("EMAIL"))
-        .or_else(|_| Err(format!(
-            "Could not determine {} email: checked ${}, user.email in git config, and $EMAIL",
-            which_lc, email_var,
-        )))?;
-    Ok(git2::Signature::now(&name, &email)?)
+    let config = repo.config()?.snapshot()?;
+    out.auto_pager(&config, "diff", true)?;
+    let diffcolors = DiffColors::new(out, &config)?;
+
+    write_series_diff(out, repo, &diffcolors, &opts, Some(&old_tree), Some(&new_tree))
 }
 
 fn commit_status(
@@ -797,14 +414,20 @@ fn commit_status(
     do_status: bool,
 ) -> Result<()> {
     let config = repo.config()?.snapshot()?;
-    let shead = match notfound_to_none(repo.find_reference(SHEAD_REF))? {
+    let series = Series::current(repo)?;
+    if do_status && json_format(m) {
+        return match series {
+            Some(series) => write_json(out, &StatusRecord::new(&series)?),
+            None => write_json(out, &()),
+        };
+    }
+    let series = match series {
         None => {
             println!("No series; use \"git series start <name>\" to start");
             return Ok(());
         }
-        Some(result) => result,
+        Some(series) => series,
     };
-    let series_name = shead_series_name(&shead)?;
 
     if do_status {
         out.auto_pager(&config, "status", false)?;
@@ -823,81 +446,65 @@ fn commit_status(
 
     let write_status = |
         status: &mut Vec<ansi_term::ANSIString>,
-        diff: &Diff,
+        changes: &[EntryChange],
         heading: &str,
         color: &Style,
         show_hints: bool,
         hints: &[&str],
-    | -> Result<bool> {
-        let mut changes = false;
-
-        diff.foreach(&mut |delta, _| {
-            if !changes {
-                changes = true;
-                status.push(color_header.paint(format!("{}\n", heading.to_string())));
-                if show_hints {
-                    for hint in hints {
-                        status.push(color_header.paint(format!("  ({})\n", hint)));
-                    }
-                }
-                status.push(color_normal.paint("\n"));
+    | -> bool {
+        if changes.is_empty() {
+            return false;
+        }
+        status.push(color_header.paint(format!("{}\n", heading)));
+        if show_hints {
+            for hint in hints {
+                status.push(color_header.paint(format!("  ({})\n", hint)));
             }
+        }
+        status.push(color_normal.paint("\n"));
+        for change in changes {
             status.push(color_normal.paint("        "));
-            status.push(color.paint(format!(
-                "{:?}:   {}\n",
-                delta.status(),
-                delta.old_file().path().unwrap().to_str().unwrap(),
-            )));
-            true
-        }, None, None, None)?;
-
-        if changes {
-            status.push(color_normal.paint("\n"));
+            status.push(color.paint(format!("{:?}:   {}\n", change.status, change.entry)));
         }
-
-        Ok(changes)
+        status.push(color_normal.paint("\n"));
+        true
     };
 
     let mut status = Vec::new();
-    status.push(color_header.paint(format!("On series {}\n", series_name)));
-
-    let mut internals = Internals::read(repo)?;
-    let working_tree = repo.find_tree(internals.working.write()?)?;
-    let staged_tree = repo.find_tree(internals.staged.write()?)?;
+    status.push(color_header.paint(format!("On series {}\n", series.name())));
 
-    let shead_commit = match notfound_to_none(shead.resolve())? {
-        Some(r) => Some(r.peel_to_commit()?),
-        None => {
-            status.push(color_header.paint("\nInitial series commit\n"));
-            None
+    let series_status = series.status()?;
+    if series_status.initial {
+        status.push(color_header.paint("\nInitial series commit\n"));
+    }
+    if let Some(merging) = series_status.merging {
+        status.push(color_header.paint(format!("\nYou are merging {}\n", commit_summarize(repo, merging)?)));
+        if do_status {
+            status.push(color_header.paint("  (use \"git series commit -a\" to conclude the merge)\n"));
+            status.push(color_header.paint("  (use \"git series merge --abort\" to abort the merge)\n"));
         }
-    };
-    let shead_tree = match shead_commit {
-        Some(ref c) => Some(c.tree()?),
-        None => None,
-    };
+        status.push(color_normal.paint("\n"));
+    }
 
     let commit_all = m.is_present("all");
 
-    let (changes, tree) = if commit_all {
-        let diff = repo.diff_tree_to_tree(shead_tree.as_ref(), Some(&working_tree), None)?;
+    let changes = if commit_all {
         let changes = write_status(
             &mut status,
-            &diff,
+            &series_status.all,
             "Changes to be committed:",
             &color_normal,
             false,
             &[],
-        )?;
+        );
         if !changes {
             status.push(color_normal.paint("nothing to commit; series unchanged\n"));
         }
-        (changes, working_tree)
+        changes
     } else {
-        let diff = repo.diff_tree_to_tree(shead_tree.as_ref(), Some(&staged_tree), None)?;
         let changes_to_be_committed = write_status(
             &mut status,
-            &diff,
+            &series_status.staged,
             "Changes to be committed:",
             &color_updated,
             do_status,
@@ -905,17 +512,16 @@ fn commit_status(
                 "use \"git series commit\" to commit",
                 "use \"git series unadd <file>...\" to undo add",
             ],
-        )?;
+        );
 
-        let diff_not_staged = repo.diff_tree_to_tree(Some(&staged_tree), Some(&working_tree), None)?;
         let changes_not_staged = write_status(
             &mut status,
-            &diff_not_staged,
+            &series_status.unstaged,
             "Changes not staged for commit:",
             &color_changed,
             do_status,
             &["use \"git series add <file>...\" to update what will be committed"],
-        )?;
+        );
 
         if !changes_to_be_committed {
             if changes_not_staged {
@@ -925,51 +531,25 @@ fn commit_status(
             }
         }
 
-        (changes_to_be_committed, staged_tree)
+        changes_to_be_committed
     };
 
     let status = ansi_term::ANSIStrings(&status).to_string();
     if do_status {
         write!(out, "{}", status)?;
         return Ok(());
-    } else if !changes {
+    } else if !changes && series_status.merging.is_none() {
         return Err(status.into());
     }
 
-    // Check that the commit includes the series
-    let series_id = match tree.get_name("series") {
-        None => {
-            return Err(concat!(
-                "Cannot commit: initial commit must include \"series\"\n",
-                "Use \"git series add series\" or \"git series commit -a\"",
-            ).into());
-        }
-        Some(series) => series.id(),
-    };
-
-    // Check that the base is still an ancestor of the series
-    if let Some(base) = tree.get_name("base") {
-        if base.id() != series_id && !repo.graph_descendant_of(series_id, base.id())? {
-            let (base_short_id, base_summary) = commit_summarize_components(&repo, base.id())?;
-            let (series_short_id, series_summary) = commit_summarize_components(&repo, series_id)?;
-            return Err(format!(
-                concat!(
-                    "Cannot commit: base {} is not an ancestor of patch series {}\n",
-                    "base   {} {}\n",
-                    "series {} {}"
-                ),
-                base_short_id, series_short_id,
-                base_short_id, base_summary,
-                series_short_id, series_summary,
-            ).into());
-        }
-    }
-
     let msg = match m.value_of("m") {
         Some(s) => s.to_string(),
         None => {
             let filename = repo.path().join("SCOMMIT_EDITMSG");
             let mut file = File::create(&filename)?;
+            if let Ok(merge_msg) = std::fs::read_to_string(repo.path().join("SMERGE_MSG")) {
+                write!(file, "{}", merge_msg)?;
+            }
             write!(file, "{}", COMMIT_MESSAGE_COMMENT)?;
             for line in status.lines() {
                 if line.is_empty() {
@@ -980,12 +560,14 @@ fn commit_status(
             }
             if m.is_present("verbose") {
                 writeln!(file, "{}\n{}", SCISSOR_LINE, SCISSOR_COMMENT)?;
+                let head = series.head()?;
                 write_series_diff(
                     &mut file,
                     repo,
                     &DiffColors::plain(),
-                    shead_tree.as_ref(),
-                    Some(&tree),
+                    &RangeDiffOptions::default(),
+                    head.as_ref().map(|h| h.tree()),
+                    Some(&series.commit_tree(commit_all)?),
                 )?;
             }
             drop(file);
@@ -999,61 +581,49 @@ fn commit_status(
             git2::message_prettify(msg, git2::DEFAULT_COMMENT_CHAR)?
         }
     };
-    if msg.is_empty() {
-        return Err("Aborting series commit due to empty commit message.".into());
-    }
-
-    let author = get_signature(&config, "AUTHOR")?;
-    let committer = get_signature(&config, "COMMITTER")?;
-    let mut parents: Vec<Oid> = Vec::new();
-    // Include all commits from tree, to keep them reachable and fetchable.
-    for e in tree.iter() {
-        if e.kind() == Some(ObjectType::Commit) && e.name().unwrap() != "base" {
-            parents.push(e.id())
-        }
-    }
-    let parents = parents_from_ids(repo, parents)?;
-    let parents_ref: Vec<&_> = shead_commit.iter().chain(parents.iter()).collect();
-    let new_commit_oid = repo.commit(Some(SHEAD_REF), &author, &committer, &msg, &tree, &parents_ref)?;
 
-    if commit_all {
-        internals.staged = repo.treebuilder(Some(&tree))?;
-        internals.write(repo)?;
+    let new_commit_oid = series.commit(&msg, commit_all)?;
+    if series_status.merging.is_some() {
+        let _ = std::fs::remove_file(repo.path().join("SMERGE_MSG"));
     }
 
-    let (new_commit_short_id, new_commit_summary) = commit_summarize_components(&repo, new_commit_oid)?;
-    writeln!(out, "[{} {}] {}", series_name, new_commit_short_id, new_commit_summary)?;
+    let (new_commit_short_id, new_commit_summary) = commit_summarize_components(repo, new_commit_oid)?;
+    writeln!(out, "[{} {}] {}", series.name(), new_commit_short_id, new_commit_summary)?;
 
     Ok(())
 }
 
 fn cover(repo: &Repository, m: &ArgMatches) -> Result<()> {
-    let mut internals = Internals::read(repo)?;
-
-    let (working_cover_id, working_cover_content) = match internals.working.get("cover")? {
-        None => (Oid::zero(), String::new()),
-        Some(entry) => (entry.id(), std::str::from_utf8(repo.find_blob(entry.id())?.content())?.to_string()),
-    };
+    let series = Series::require_current(repo)?;
+    let working_cover = series.cover()?;
 
     if m.is_present("delete") {
-        if working_cover_id.is_zero() {
+        if working_cover.is_none() {
             return Err("No cover to delete".into());
         }
-        internals.working.remove("cover")?;
-        internals.write(repo)?;
+        series.set_cover(None)?;
         println!("Deleted cover letter");
         return Ok(());
     }
 
+    let config = repo.config()?;
+    let template = match m.value_of_os("template") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if working_cover.is_none() => config.get_path("series.coverTemplate").ok(),
+        None => None,
+    };
     let filename = repo.path().join("COVER_EDITMSG");
     let mut file = File::create(&filename)?;
-    if working_cover_content.is_empty() {
-        write!(file, "{}", COVER_LETTER_COMMENT)?;
-    } else {
-        write!(file, "{}", working_cover_content)?;
+    match (template, working_cover) {
+        (Some(path), _) => {
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read cover letter template {}: {}", path.display(), e))?;
+            write!(file, "{}{}", content, COVER_LETTER_COMMENT)?
+        }
+        (None, Some(ref content)) if !content.is_empty() => write!(file, "{}", content)?,
+        _ => write!(file, "{}", COVER_LETTER_COMMENT)?,
     }
     drop(file);
-    let config = repo.config()?;
     run_editor(&config, &filename)?;
     let mut file = File::open(&filename)?;
     let mut msg = String::new();
@@ -1063,805 +633,744 @@ fn cover(repo: &Repository, m: &ArgMatches) -> Result<()> {
         return Err("Empty cover letter; not changing.\n(To delete the cover letter, use \"git series cover -d\".)".into());
     }
 
-    let new_cover_id = repo.blob(msg.as_bytes())?;
-    if new_cover_id == working_cover_id {
-        println!("Cover letter unchanged");
-    } else {
-        internals.working.insert("cover", new_cover_id, GIT_FILEMODE_BLOB as i32)?;
-        internals.write(repo)?;
+    if series.set_cover(Some(&msg))? {
         println!("Updated cover letter");
+    } else {
+        println!("Cover letter unchanged");
     }
 
     Ok(())
 }
 
 fn cp_mv(repo: &Repository, m: &ArgMatches, mv: bool) -> Result<()> {
-    let shead_target = if let Some(shead) = notfound_to_none(repo.find_reference(SHEAD_REF))? {
-        Some(shead_series_name(&shead)?)
-    } else {
-        None
-    };
     let mut source_dest = m.values_of("source_dest").unwrap();
     let dest = source_dest.next_back().unwrap();
-    let (update_shead, source) = match source_dest.next_back().map(String::from) {
-        Some(name) => (shead_target.as_ref() == Some(&name), name),
-        None => (true, shead_target.ok_or("No current series")?),
+    let source = match source_dest.next_back() {
+        Some(name) => name.to_string(),
+        None => current_series_name(repo)?.ok_or("No current series")?,
     };
 
-    if Internals::exists(&repo, dest)? {
+    if Internals::exists(repo, dest)? {
         return Err(format!("The destination series \"{}\" already exists", dest).into());
     }
-    if !Internals::copy(&repo, &source, &dest)? {
+    if !Internals::exists(repo, &source)? {
         return Err(format!("The source series \"{}\" does not exist", source).into());
     }
+    let source = Series::find(repo, &source)?;
 
     if mv {
-        if update_shead {
-            let prefixed_dest = &[SERIES_PREFIX, dest].concat();
-            repo.reference_symbolic(
-                SHEAD_REF,
-                &prefixed_dest,
-                true,
-                &format!("git series mv {} {}", source, dest),
-            )?;
-        }
-        Internals::delete(&repo, &source)?;
+        source.rename(dest)?;
+    } else {
+        source.copy_to(dest)?;
     }
 
     Ok(())
 }
 
-fn date_822(t: git2::Time) -> String {
-    let offset = chrono::offset::fixed::FixedOffset::east(t.offset_minutes() * 60);
-    let datetime = offset.timestamp(t.seconds(), 0);
-    datetime.to_rfc2822()
+fn series_commits(n: usize) -> String {
+    format!("{} series commit{}", n, if n == 1 { "" } else { "s" })
 }
 
-fn shortlog(commits: &mut [Commit]) -> String {
-    let mut s = String::new();
-    let mut author_map = std::collections::HashMap::new();
-
-    for commit in commits {
-        let author = commit.author().name().unwrap().to_string();
-        author_map.entry(author).or_insert_with(Vec::new)
-            .push(commit.summary().unwrap().to_string());
-    }
-
-    let mut authors: Vec<_> = author_map.keys().collect();
-    authors.sort();
-    let mut first = true;
-    for author in authors {
-        if first {
-            first = false;
-        } else {
-            writeln!(s).unwrap();
-        }
-        let summaries = author_map.get(author).unwrap();
-        writeln!(s, "{} ({}):", author, summaries.len()).unwrap();
-        for summary in summaries {
-            writeln!(s, "  {}", summary).unwrap();
-        }
+fn fetch(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let remote = m.value_of("remote").unwrap();
+    let name = match m.value_of("name") {
+        Some(name) => name.to_string(),
+        None => Series::require_current(repo)?.name().to_string(),
+    };
+    let refs = fetch_refs(remote, &name, m.is_present("internals"));
+
+    // Only fetch the refs the remote has; the staged and working refs may not exist.
+    let output = Command::new("git").arg("ls-remote").arg(remote)
+        .args(refs.iter().map(|(src, _)| src))
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(format!("git ls-remote exited with status {}", output.status).into());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let available: Vec<&str> = stdout.lines().filter_map(|line| line.split('\t').nth(1)).collect();
+    if !available.contains(&refs[0].0.as_str()) {
+        return Err(format!("Remote {} has no series {}", remote, name).into());
+    }
+    let refspecs: Vec<String> = refs.iter()
+        .filter(|(src, _)| available.contains(&src.as_str()))
+        .map(|(src, dst)| format!("+{}:{}", src, dst))
+        .collect();
+    let status = Command::new("git").arg("fetch").arg(remote).args(&refspecs).status()?;
+    if !status.success() {
+        return Err(format!("git fetch exited with status {}", status).into());
     }
 
-    s
-}
-
-fn sanitize_summary(summary: &str) -> String {
-    let mut s = String::with_capacity(summary.len());
-    let mut prev_dot = false;
-    let mut need_space = false;
-    for c in summary.chars() {
-        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
-            if need_space {
-                s.push('-');
-                need_space = false;
-            }
-            if !(prev_dot && c == '.') {
-                s.push(c);
-            }
-        } else {
-            if !s.is_empty() {
-                need_space = true;
+    let remote_id = repo.refname_to_id(&refs[0].1)?;
+    let tracking_name = format!("{}/git-series/{}", remote, name);
+    if !Internals::exists(repo, &name)? {
+        let msg = format!("git series fetch {} {}", remote, name);
+        repo.reference(&[SERIES_PREFIX, &name].concat(), remote_id, false, &msg)?;
+        for ((src, dst), prefix) in refs.iter().skip(1).zip([STAGED_PREFIX, WORKING_PREFIX].iter()) {
+            if available.contains(&src.as_str()) {
+                repo.reference(&[prefix, name.as_str()].concat(), repo.refname_to_id(dst)?, false, &msg)?;
             }
         }
-        prev_dot = c == '.';
+        println!("Created series {} from {}", name, tracking_name);
+        return Ok(());
     }
-    let end = s.trim_end_matches(|c| c == '.' || c == '-').len();
-    s.truncate(end);
-    s
-}
 
-#[test]
-fn test_sanitize_summary() {
-    let tests = vec![
-        ("", ""),
-        ("!!!!!", ""),
-        ("Test", "Test"),
-        ("Test case", "Test-case"),
-        ("Test    case", "Test-case"),
-        ("    Test    case    ", "Test-case"),
-        ("...Test...case...", ".Test.case"),
-        ("...Test...case.!!", ".Test.case"),
-        (".!.Test.!.case.!.", ".-.Test.-.case"),
-    ];
-    for (summary, sanitized) in tests {
-        assert_eq!(sanitize_summary(summary), sanitized.to_string());
+    let local = match Series::find(repo, &name)?.head()? {
+        Some(head) => head.id(),
+        None => {
+            println!("Series {} has no series commits; fetched {}", name, tracking_name);
+            return Ok(());
+        }
+    };
+    match sync_status(repo, local, remote_id)? {
+        SyncStatus::UpToDate => println!("Series {} is up to date with {}", name, tracking_name),
+        SyncStatus::Ahead(n) => println!("Series {} is ahead of {} by {}", name, tracking_name, series_commits(n)),
+        SyncStatus::Behind(n) => println!(
+            "Series {} is behind {} by {}, and can fast-forward.\n  (use \"git series merge {}\" to update)",
+            name, tracking_name, series_commits(n), tracking_name,
+        ),
+        SyncStatus::Diverged { ahead, behind } => println!(
+            "Series {} and {} have diverged, and have {} and {} different series commits each.\n  (use \"git series merge {}\" to merge)",
+            name, tracking_name, ahead, behind, tracking_name,
+        ),
     }
+    Ok(())
 }
 
-fn split_message(message: &str) -> (&str, &str) {
-    let mut iter = message.splitn(2, '\n');
-    let subject = iter.next().unwrap().trim_end();
-    let body = iter.next().map(|s| s.trim_start()).unwrap_or("");
-    (subject, body)
-}
-
-struct DiffColors {
-    commit: Style,
-    meta: Style,
-    frag: Style,
-    func: Style,
-    context: Style,
-    old: Style,
-    new: Style,
-    series_old: Style,
-    series_new: Style,
-}
+fn format(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let config = repo.config()?.snapshot()?;
+    let to_stdout = m.is_present("stdout");
+    let send = m.is_present("send");
+    let dry_run = m.is_present("dry-run");
+
+    let series = Series::require_current(repo)?;
+    let version = series.require_head()?;
+    let sent_versions = series.versions()?;
+    let reroll_count = match m.value_of("reroll-count") {
+        Some(v) => Some(v.to_string()),
+        None => next_version(&sent_versions, version.id()),
+    };
+    let previous_sent = reroll_count.as_deref().and_then(|v| previous_version(&sent_versions, v));
+    let changes_since = if m.is_present("changelog") {
+        Some(previous_sent.ok_or("No previous version of the series recorded as sent; cannot use --changelog.")?)
+    } else if version.cover(repo)?.is_some_and(|cover| cover.contains("{changes_since}")) {
+        previous_sent
+    } else {
+        None
+    };
+    let compare_to = |option: &str| -> Result<Option<PreviousVersion>> {
+        if !m.is_present(option) {
+            return Ok(None);
+        }
+        let (label, previous) = match m.value_of(option) {
+            Some(spec) => (spec.to_string(), series.resolve_version(spec)?),
+            None => {
+                let sent = previous_sent.ok_or_else(|| format!(
+                    "No previous version of the series recorded as sent; use --{}=<version>.",
+                    option,
+                ))?;
+                (format!("v{}", sent.version), SeriesVersion::find(repo, sent.series_commit)?)
+            }
+        };
+        match (previous.base(), previous.series()) {
+            (Some(base), Some(series)) => Ok(Some(PreviousVersion { label, base, series })),
+            _ => Err(format!("Cannot compare against {}; it has no base.", label).into()),
+        }
+    };
+    let interdiff = compare_to("interdiff")?;
+    let range_diff = compare_to("range-diff")?;
+    let mut recipients = version.recipients(repo)?;
+    // Options default to the settings of the series, then to git config.
+    let series_config = version.config(repo)?;
+    let flag = |option: &str, key: &str| -> Result<bool> {
+        Ok(m.is_present(option) || series_config.get_bool(key)?.unwrap_or(false))
+    };
+    let subject_prefix = match m.value_of("subject-prefix").or_else(|| series_config.get("format.subjectPrefix")) {
+        Some(prefix) => Some(prefix.to_string()),
+        None => config.get_string("format.subjectPrefix").ok(),
+    };
+    let signoff = m.is_present("signoff") || match series_config.get_bool("format.signOff")? {
+        Some(signoff) => signoff,
+        None => config.get_bool("format.signOff").unwrap_or(false),
+    };
+    let mut to_addresses = config_values(&config, "format.to")?;
+    let mut cc_addresses = config_values(&config, "format.cc")?;
+    to_addresses.extend(series_config.get_all("format.to").into_iter().map(String::from));
+    cc_addresses.extend(series_config.get_all("format.cc").into_iter().map(String::from));
+    to_addresses.extend(m.values_of("to").into_iter().flatten().map(String::from));
+    cc_addresses.extend(m.values_of("cc").into_iter().flatten().map(String::from));
+    let mut cc_cmd = m.value_of("cc-cmd").or_else(|| series_config.get("format.ccCmd")).map(String::from);
+    let send_config = if send {
+        if cc_cmd.is_none() {
+            cc_cmd = config.get_string("sendemail.ccCmd").ok();
+        }
+        to_addresses.extend(config_values(&config, "sendemail.to")?);
+        cc_addresses.extend(config_values(&config, "sendemail.cc")?);
+        Some(SendEmailConfig::new(&config)?)
+    } else {
+        None
+    };
+    for address in to_addresses.iter() {
+        recipients.add_to(address);
+    }
+    for address in cc_addresses.iter() {
+        recipients.add_cc(address);
+    }
+    let opts = FormatOptions {
+        in_reply_to: m.value_of("in-reply-to"),
+        no_from: flag("no-from", "format.noFrom")?,
+        reroll_count: reroll_count.as_deref(),
+        rfc: m.is_present("rfc") || (!m.is_present("subject-prefix") && flag("rfc", "format.rfc")?),
+        subject_prefix: subject_prefix.as_deref(),
+        to: recipients.to,
+        cc: recipients.cc,
+        cc_trailers: flag("cc-trailers", "format.ccTrailers")?,
+        cc_cmd: cc_cmd.as_deref(),
+        signoff,
+        series_name: series.name(),
+        changes_since,
+        interdiff,
+        range_diff,
+        range_diff_options: range_diff_options(m)?,
+    };
 
-impl DiffColors {
-    fn plain() -> Self {
-        DiffColors {
-            commit: Style::new(),
-            meta: Style::new(),
-            frag: Style::new(),
-            func: Style::new(),
-            context: Style::new(),
-            old: Style::new(),
-            new: Style::new(),
-            series_old: Style::new(),
-            series_new: Style::new(),
-        }
-    }
-
-    fn new(out: &Output, config: &Config) -> Result<Self> {
-        let old = out.get_color(&config, "diff", "old", "red")?;
-        let new = out.get_color(&config, "diff", "new", "green")?;
-        Ok(DiffColors {
-            commit: out.get_color(&config, "diff", "commit", "yellow")?,
-            meta: out.get_color(&config, "diff", "meta", "bold")?,
-            frag: out.get_color(&config, "diff", "frag", "cyan")?,
-            func: out.get_color(&config, "diff", "func", "normal")?,
-            context: out.get_color(&config, "diff", "context", "normal")?,
-            old,
-            new,
-            series_old: old.reverse(),
-            series_new: new.reverse(),
-        })
+    if to_stdout {
+        out.auto_pager(&config, "format-patch", true)?;
     }
-}
-
-fn diffstat(diff: &Diff) -> Result<String> {
-    let stats = diff.stats()?;
-    let stats_buf = stats.to_buf(git2::DiffStatsFormat::FULL | git2::DiffStatsFormat::INCLUDE_SUMMARY, 72)?;
-    Ok(stats_buf.as_str().unwrap().to_string())
-}
+    let diffcolors = if to_stdout {
+        DiffColors::new(out, &config)?
+    } else {
+        DiffColors::plain()
+    };
 
-fn write_diff<W: IoWrite>(
-    f: &mut W,
-    colors: &DiffColors,
-    diff: &Diff,
-    simplify: bool,
-) -> Result<usize> {
-    let mut err = Ok(());
-    let mut lines = 0;
-    let normal = Style::new();
-    diff.print(git2::DiffFormat::Patch, |_, _, l| {
-        err = || -> Result<()> {
-            let o = l.origin();
-            let style = match o {
-                '-' | '<' => colors.old,
-                '+' | '>' => colors.new,
-                _ if simplify => normal,
-                ' ' | '=' => colors.context,
-                'F' => colors.meta,
-                'H' => colors.frag,
-                _ => normal,
-            };
-            let obyte = [o as u8];
-            let mut v = Vec::new();
-            if o == '+' || o == '-' || o == ' ' {
-                v.push(style.paint(&obyte[..]));
+    let mails = format_series(repo, &version, &opts, &diffcolors)?;
+    if let Some(send_config) = send_config {
+        if opts.to.is_empty() && opts.cc.is_empty() {
+            return Err("No recipients; use \"git series recipients --to\", --to, or sendemail.to.".into());
+        }
+        if dry_run {
+            for mail in mails.iter() {
+                writeln!(out, "Dry run: {} to {}", mail.file_name, mail_recipients(&mail.content).join(", "))?;
             }
-            if simplify {
-                if o == 'H' {
-                    v.push(normal.paint("@@\n".as_bytes()));
-                    lines += 1;
-                } else if o == 'F' {
-                    for line in l.content().split(|c| *c == b'\n') {
-                        if !line.is_empty()
-                            && !line.starts_with(b"diff --git")
-                            && !line.starts_with(b"index ")
-                        {
-                            v.push(normal.paint(line.to_owned()));
-                            v.push(normal.paint("\n".as_bytes()));
-                            lines += 1;
-                        }
-                    }
-                } else {
-                    v.push(style.paint(l.content()));
-                    lines += 1;
-                }
-            } else if o == 'H' {
-                // Split frag and func
-                let line = l.content();
-                let at = &|&(_, &c): &(usize, &u8)| c == b'@';
-                let not_at = &|&(_, &c): &(usize, &u8)| c != b'@';
-                match line
-                    .iter()
-                    .enumerate()
-                    .skip_while(at)
-                    .skip_while(not_at)
-                    .skip_while(at)
-                    .nth(1)
-                    .unwrap_or((0, &b'\n'))
-                {
-                    (_, &c) if c == b'\n' => v.push(style.paint(&line[..line.len() - 1])),
-                    (pos, _) => {
-                        v.push(style.paint(&line[..pos - 1]));
-                        v.push(normal.paint(" ".as_bytes()));
-                        v.push(colors.func.paint(&line[pos..line.len() - 1]));
-                    }
+            return Ok(());
+        }
+        let contents: Vec<&[u8]> = mails.iter().map(|mail| &mail.content[..]).collect();
+        send_mails(&send_config, &contents)?;
+        for mail in mails.iter() {
+            writeln!(out, "Sent {} to {}", mail.file_name, mail_recipients(&mail.content).join(", "))?;
+        }
+    } else {
+        for (n, mail) in mails.iter().enumerate() {
+            if to_stdout {
+                if n != 0 {
+                    writeln!(out)?;
                 }
-                v.push(normal.paint("\n".as_bytes()));
+                out.write_all(&mail.content)?;
             } else {
-                // The less pager resets ANSI colors at each newline, so emit colors separately for
-                // each line.
-                for (n, line) in l.content().split(|c| *c == b'\n').enumerate() {
-                    if n != 0 {
-                        v.push(normal.paint("\n".as_bytes()));
-                    }
-                    if !line.is_empty() {
-                        v.push(style.paint(line));
-                    }
-                }
+                println!("{}", mail.file_name);
+                File::create(&mail.file_name)?.write_all(&mail.content)?;
             }
-            ansi_term::ANSIByteStrings(&v).write_to(f)?;
-            Ok(())
-        }();
-        err.is_ok()
-    })?;
-    err?;
-    Ok(lines)
-}
+        }
+    }
 
-fn get_commits(repo: &Repository, base: Oid, series: Oid) -> Result<Vec<Commit>> {
-    let mut revwalk = repo.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
-    revwalk.push(series)?;
-    revwalk.hide(base)?;
-    revwalk.map(|c| {
-        let id = c?;
-        let commit = repo.find_commit(id)?;
-        Ok(commit)
-    }).collect()
+    if send || m.is_present("record") {
+        if let Some(first) = mails.first() {
+            series.record_version(SentVersion {
+                version: reroll_count.unwrap_or_else(|| "1".to_string()),
+                series_commit: version.id(),
+                message_id: first.message_id.clone(),
+            })?;
+        }
+    }
+
+    Ok(())
 }
 
-fn write_commit_range_diff<W: IoWrite>(
-    out: &mut W,
-    repo: &Repository,
-    colors: &DiffColors,
-    (base1, series1): (Oid, Oid),
-    (base2, series2): (Oid, Oid),
-) -> Result<()> {
-    let mut commits1 = get_commits(repo, base1, series1)?;
-    let mut commits2 = get_commits(repo, base2, series2)?;
-    for commit in commits1.iter().chain(commits2.iter()) {
-        if commit.parent_ids().count() > 1 {
-            writeln!(out, "(Diffs of series with merge commits ({}) not yet supported)", commit.id())?;
-            return Ok(());
-        }
+fn import(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    match repo.state() {
+        git2::RepositoryState::Clean => (),
+        s => return Err(format!("{:?} in progress; cannot import patch series", s).into()),
     }
-    let ncommon = commits1.iter().zip(commits2.iter())
-        .take_while(|&(ref c1, ref c2)| c1.id() == c2.id())
-        .count();
-    drop(commits1.drain(..ncommon));
-    drop(commits2.drain(..ncommon));
-    let ncommits1 = commits1.len();
-    let ncommits2 = commits2.len();
-    let n = ncommits1 + ncommits2;
-    if n == 0 {
-        return Ok(());
+    let name = m.value_of("name").unwrap();
+    if Internals::exists(repo, name)? {
+        return Err(format!("Series {} already exists.", name).into());
     }
-    let commit_text = &|commit: &Commit| {
-        let parent = commit.parent(0)?;
-        let author = commit.author();
-        let diff = repo.diff_tree_to_tree(
-            Some(&parent.tree().unwrap()),
-            Some(&commit.tree().unwrap()),
-            None,
-        )?;
-        let mut v = Vec::new();
-        v.write_all(b"From: ")?;
-        v.write_all(author.name_bytes())?;
-        v.write_all(b" <")?;
-        v.write_all(author.email_bytes())?;
-        v.write_all(b">\n\n")?;
-        v.write_all(commit.message_bytes())?;
-        v.write_all(b"\n")?;
-        let lines = write_diff(&mut v, colors, &diff, true)?;
-        Ok((v, lines))
+    let path = std::path::Path::new(m.value_of("path").unwrap());
+    let (covers, patches): (Vec<_>, Vec<_>) = read_mails(path)?.into_iter().partition(|mail| mail.is_cover());
+    if patches.is_empty() {
+        return Err(format!("No patches found in {}", path.display()).into());
+    }
+    let base = match m.value_of("base") {
+        Some(base) => repo.revparse_single(base)?.peel_to_commit()?.id(),
+        None => {
+            let base = covers.iter().chain(patches.iter())
+                .filter_map(|mail| mail.base_commit())
+                .next()
+                .ok_or("No \"base-commit:\" found in the patches; use --base to specify the base.")?;
+            let id = git2::Oid::from_str(base)?;
+            if repo.find_commit(id).is_err() {
+                return Err(format!(
+                    "Base commit {} not found in this repository.\nFetch it, or use --base to specify the base.",
+                    base,
+                ).into());
+            }
+            id
+        }
     };
-    let texts1: Vec<_> = commits1.iter().map(commit_text).collect::<Result<_>>()?;
-    let texts2: Vec<_> = commits2.iter().map(commit_text).collect::<Result<_>>()?;
 
-    let mut weights = Vec::with_capacity(n * n);
-    for i1 in 0..ncommits1 {
-        for i2 in 0..ncommits2 {
-            let patch = git2::Patch::from_buffers(&texts1[i1].0, None, &texts2[i2].0, None, None)?;
-            let (_, additions, deletions) = patch.line_stats()?;
-            weights.push(additions + deletions);
-        }
-        let w = texts1[i1].1 / 2;
-        for _ in ncommits2..n {
-            weights.push(w);
-        }
+    // Apply the patches before creating the series, so that a failure leaves nothing behind.
+    let head = repo.head()?;
+    let orig_head = match head.shorthand().filter(|_| head.is_branch()) {
+        Some(branch) => vec![branch.to_string()],
+        None => vec!["--detach".to_string(), head.peel_to_commit()?.id().to_string()],
+    };
+    drop(head);
+    let status = Command::new("git").args(["checkout", "--quiet", "--detach"]).arg(base.to_string()).status()?;
+    if !status.success() {
+        return Err(format!("git checkout exited with status {}", status).into());
     }
-    for _ in ncommits1..n {
-        for i2 in 0..ncommits2 {
-            weights.push(texts2[i2].1 / 2);
-        }
-        for _ in ncommits2..n {
-            weights.push(0);
-        }
+    let dir = tempdir::TempDir::new_in(repo.path(), "series-import")?;
+    let mbox_path = dir.path().join("patches.mbox");
+    let mut mbox = File::create(&mbox_path)?;
+    for mail in patches.iter() {
+        mbox.write_all(&mail.raw)?;
+        mbox.write_all(b"\n")?;
+    }
+    drop(mbox);
+    let status = Command::new("git").arg("am").arg("--quiet").arg(&mbox_path).status()?;
+    if !status.success() {
+        Command::new("git").args(["am", "--abort"]).status()?;
+        Command::new("git").args(["checkout", "--quiet"]).args(&orig_head).status()?;
+        return Err(format!(
+            concat!(
+                "git am exited with status {}; did not import series {}.\n",
+                "Use --base to apply the patches to a different base.",
+            ),
+            status, name,
+        ).into());
     }
-    let mut weight_matrix = munkres::WeightMatrix::from_row_vec(n, weights);
-    let result = munkres::solve_assignment(&mut weight_matrix)?;
 
-    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-    enum CommitState { Unhandled, Handled, Deleted }
-    let mut commits2_from1: Vec<_> = std::iter::repeat(None).take(ncommits2).collect();
-    let mut commits1_state: Vec<_> = std::iter::repeat(CommitState::Unhandled).take(ncommits1).collect();
-    let mut commit_pairs = Vec::with_capacity(n);
-    for munkres::Position { row: i1, column: i2 } in result {
-        if i1 < ncommits1 {
-            if i2 < ncommits2 {
-                commits2_from1[i2] = Some(i1);
-            } else {
-                commits1_state[i1] = CommitState::Deleted;
-            }
-        }
+    let series = Series::start(repo, name)?;
+    series.set_base(Some(base))?;
+    if let Some(cover) = covers.first() {
+        series.set_cover(Some(&cover.cover_text()))?;
     }
 
-    // Show matching or new commits sorted by the new commit order. Show deleted commits after
-    // showing all of their prerequisite commits.
-    let mut commits1_state_index = 0;
-    for (i2, opt_i1) in commits2_from1.iter().enumerate() {
-        while commits1_state_index < ncommits1 {
-            match commits1_state[commits1_state_index] {
-                CommitState::Unhandled => { break }
-                CommitState::Handled => {}
-                CommitState::Deleted => {
-                    commit_pairs.push((Some(commits1_state_index), None));
-                }
-            }
-            commits1_state_index += 1;
-        }
-        if let &Some(i1) = opt_i1 {
-            commit_pairs.push((Some(i1), Some(i2)));
-            commits1_state[i1] = CommitState::Handled;
+    let msg = format!("Import {}", path.display());
+    let new_commit_oid = series.commit(&msg, true)?;
+    let (short_id, summary) = commit_summarize_components(repo, new_commit_oid)?;
+    writeln!(out, "[{} {}] {}", name, short_id, summary)?;
+    Ok(())
+}
+
+fn log(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let config = repo.config()?.snapshot()?;
+    let history = Series::require_current(repo)?.history()?;
+    let show_diff = m.is_present("patch");
+
+    if json_format(m) {
+        let records = history.iter()
+            .map(|version| VersionRecord::new(repo, version, show_diff))
+            .collect::<Result<Vec<_>>>()?;
+        return write_json(out, &records);
+    }
+
+    out.auto_pager(&config, "log", true)?;
+    let diffcolors = DiffColors::new(out, &config)?;
+
+    let mut first = true;
+    for version in history {
+        if first {
+            first = false;
         } else {
-            commit_pairs.push((None, Some(i2)));
-        }
-    }
-    for i1 in commits1_state_index..ncommits1 {
-        if commits1_state[i1] == CommitState::Deleted {
-            commit_pairs.push((Some(i1), None));
-        }
-    }
-
-    let normal = Style::new();
-    let nl = |v: &mut Vec<_>| { v.push(normal.paint("\n".as_bytes())); };
-    let mut v = Vec::new();
-    v.push(colors.meta.paint("diff --series".as_bytes()));
-    nl(&mut v);
-
-    let offset = ncommon + 1;
-    let nwidth = max(ncommits1 + offset, ncommits2 + offset).to_string().len();
-    let commits1_summaries: Vec<_> = commits1.iter_mut().map(commit_obj_summarize_components).collect::<Result<_>>()?;
-    let commits2_summaries: Vec<_> = commits2.iter_mut().map(commit_obj_summarize_components).collect::<Result<_>>()?;
-    let idwidth = commits1_summaries.iter().chain(commits2_summaries.iter())
-        .map(|&(ref short_id, _)| short_id.len())
-        .max().unwrap();
-    for commit_pair in commit_pairs {
-        match commit_pair {
-            (None, None) => unreachable!(),
-            (Some(i1), None) => {
-                let (ref c1_short_id, ref c1_summary) = commits1_summaries[i1];
-                v.push(colors.old.paint(format!(
-                    "{:nwidth$}: {:idwidth$} < {:-<nwidth$}: {:-<idwidth$} {}",
-                    i1 + offset, c1_short_id, "", "", c1_summary, nwidth=nwidth, idwidth=idwidth,
-                ).as_bytes().to_owned()));
-                nl(&mut v);
-            }
-            (None, Some(i2)) => {
-                let (ref c2_short_id, ref c2_summary) = commits2_summaries[i2];
-                v.push(colors.new.paint(format!(
-                    "{:-<nwidth$}: {:-<idwidth$} > {:nwidth$}: {:idwidth$} {}",
-                    "", "", i2 + offset, c2_short_id, c2_summary, nwidth=nwidth, idwidth=idwidth,
-                ).as_bytes().to_owned()));
-                nl(&mut v);
+            writeln!(out)?;
+        }
+        let commit = version.commit();
+        let author = commit.author();
+
+        writeln!(out, "{}", diffcolors.commit.paint(format!("commit {}", version.id())))?;
+        writeln!(out, "Author: {} <{}>", author.name().unwrap(), author.email().unwrap())?;
+        writeln!(out, "Date:   {}\n", git_series::date_822(author.when()))?;
+        for line in commit.message().unwrap().lines() {
+            writeln!(out, "    {}", line)?;
+        }
+
+        if show_diff {
+            let parent_ids = version.history_parents();
+
+            writeln!(out)?;
+            if parent_ids.is_empty() {
+                write_series_diff(out, repo, &diffcolors, &RangeDiffOptions::default(), None, Some(version.tree()))?;
             }
-            (Some(i1), Some(i2)) => {
-                let mut patch = git2::Patch::from_buffers(&texts1[i1].0, None, &texts2[i2].0, None, None)?;
-                let (old, ch, new) = if let Delta::Unmodified = patch.delta().status() {
-                    (colors.commit, '=', colors.commit)
-                } else {
-                    (colors.series_old, '!', colors.series_new)
-                };
-                let (ref c1_short_id, _) = commits1_summaries[i1];
-                let (ref c2_short_id, ref c2_summary) = commits2_summaries[i2];
-                v.push(old.paint(format!("{:nwidth$}: {:idwidth$}", i1 + offset, c1_short_id, nwidth=nwidth, idwidth=idwidth).as_bytes().to_owned()));
-                v.push(colors.commit.paint(format!(" {} ", ch).as_bytes().to_owned()));
-                v.push(new.paint(format!("{:nwidth$}: {:idwidth$}", i2 + offset, c2_short_id, nwidth=nwidth, idwidth=idwidth).as_bytes().to_owned()));
-                v.push(colors.commit.paint(format!(" {}", c2_summary).as_bytes().to_owned()));
-                nl(&mut v);
-                patch.print(&mut |_, _, l| {
-                    let o = l.origin();
-                    let style = match o {
-                        '-' | '<' => old,
-                        '+' | '>' => new,
-                        _ => normal,
-                    };
-                    if o == '+' || o == '-' || o == ' ' {
-                        v.push(style.paint(vec![o as u8]));
-                    }
-                    let style = if o == 'H' { colors.frag } else { normal };
-                    if o != 'F' {
-                        v.push(style.paint(l.content().to_owned()));
+            // Show a series merge commit as a diff from each of its parents in turn.
+            for (n, parent_id) in parent_ids.iter().enumerate() {
+                if parent_ids.len() > 1 {
+                    if n != 0 {
+                        writeln!(out)?;
                     }
-                    true
-                })?;
+                    let summary = format!("Diff from parent {}: {}", n + 1, commit_summarize(repo, *parent_id)?);
+                    writeln!(out, "{}", diffcolors.meta.paint(summary))?;
+                }
+                let parent_tree = repo.find_commit(*parent_id)?.tree()?;
+                write_series_diff(out, repo, &diffcolors, &RangeDiffOptions::default(), Some(&parent_tree), Some(version.tree()))?;
             }
         }
     }
 
-    ansi_term::ANSIByteStrings(&v).write_to(out)?;
     Ok(())
 }
 
-fn write_series_diff<W: IoWrite>(
-    out: &mut W,
-    repo: &Repository,
-    colors: &DiffColors,
-    tree1: Option<&Tree>,
-    tree2: Option<&Tree>,
-) -> Result<()> {
-    let diff = repo.diff_tree_to_tree(tree1, tree2, None)?;
-    write_diff(out, colors, &diff, false)?;
-
-    let base1 = tree1.and_then(|t| t.get_name("base"));
-    let series1 = tree1.and_then(|t| t.get_name("series"));
-    let base2 = tree2.and_then(|t| t.get_name("base"));
-    let series2 = tree2.and_then(|t| t.get_name("series"));
-
-    if let (Some(base1), Some(series1), Some(base2), Some(series2)) = (base1, series1, base2, series2) {
-        write_commit_range_diff(
-            out,
-            repo,
-            colors,
-            (base1.id(), series1.id()),
-            (base2.id(), series2.id()),
-        )?;
-    } else {
-        writeln!(out, "Can't diff series: both versions must have base and series to diff")?;
+// Check out a series commit's `series` as a detached HEAD, if HEAD does not already match it.
+fn checkout_series(repo: &Repository, new_head_id: git2::Oid, action: &str) -> Result<()> {
+    let head_id = repo.refname_to_id("HEAD")?;
+    if head_id == new_head_id {
+        return Ok(());
     }
-
+    checkout_tree(repo, &repo.find_commit(new_head_id)?.into_object())?;
+    // git status parses this reflog string; the prefix must remain "checkout: moving from ".
+    repo.reference(
+        "HEAD",
+        new_head_id,
+        true,
+        &format!("checkout: moving from {} to {} ({})", head_id, new_head_id, action),
+    )?;
+    println!("HEAD is now detached at {}", commit_summarize(repo, new_head_id)?);
     Ok(())
 }
 
-fn mail_signature() -> String {
-    format!("-- \ngit-series {}", clap::crate_version!())
-}
+fn merge(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let merge_msg_path = repo.path().join("SMERGE_MSG");
 
-fn ensure_space(s: &str) -> &'static str {
-    if s.is_empty() || s.ends_with(' ') {
-        ""
-    } else {
-        " "
+    if m.is_present("abort") {
+        series.abort_merge()?;
+        let _ = std::fs::remove_file(&merge_msg_path);
+        if let Some(series_id) = series.require_head()?.series() {
+            checkout_series(repo, series_id, "git series merge --abort")?;
+        }
+        return Ok(());
     }
-}
 
-fn ensure_nl(s: &str) -> &'static str {
-    if !s.ends_with('\n') {
-        "\n"
-    } else {
-        ""
+    match repo.state() {
+        git2::RepositoryState::Clean => (),
+        s => return Err(format!("{:?} in progress; cannot merge patch series", s).into()),
+    }
+    if series.merging()?.is_some() {
+        return Err(concat!(
+            "You have not concluded your series merge.\n",
+            "Use \"git series commit -a\" to conclude it, or \"git series merge --abort\" to abort it.",
+        ).into());
+    }
+    if !series.status()?.all.is_empty() {
+        return Err(concat!(
+            "Cannot merge: the series has uncommitted changes.\n",
+            "Use \"git series commit -a\" to commit them first.",
+        ).into());
     }
-}
-
-fn format(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
-    let config = repo.config()?.snapshot()?;
-    let to_stdout = m.is_present("stdout");
-    let no_from = m.is_present("no-from");
 
-    let shead_commit = repo.find_reference(SHEAD_REF)?.resolve()?.peel_to_commit()?;
-    let stree = shead_commit.tree()?;
+    let spec = m.value_of("series").unwrap();
+    let ours = series.require_head()?;
+    let theirs = match git_series::notfound_to_none(repo.refname_to_id(&[SERIES_PREFIX, spec].concat()))? {
+        Some(id) => SeriesVersion::find(repo, id)?,
+        None => series.resolve_version(spec)?,
+    };
 
-    let series = stree.get_name("series")
-        .ok_or("Internal error: series did not contain \"series\"")?;
-    let base = stree.get_name("base")
-        .ok_or("Cannot format series; no base set.\nUse \"git series base\" to set base.")?;
+    match merge_series(repo, &ours, &theirs)? {
+        MergeOutcome::UpToDate => {
+            println!("Already up to date.");
+        }
+        MergeOutcome::FastForward => {
+            let series_id = theirs.series().ok_or("Could not find entry \"series\" in series to merge")?;
+            checkout_series(repo, series_id, "git series merge")?;
+            series.fast_forward(&theirs)?;
+            println!("Fast-forward to {}", commit_summarize(repo, theirs.id())?);
+        }
+        MergeOutcome::Merged { tree, conflicts } => {
+            let series_id = repo.find_tree(tree)?.get_name("series")
+                .ok_or("Could not find entry \"series\" in merged series")?
+                .id();
+            checkout_series(repo, series_id, "git series merge")?;
+            series.start_merge(theirs.id(), tree)?;
+
+            let msg = match m.value_of("m") {
+                Some(msg) => msg.to_string(),
+                None => format!("Merge series {}", spec),
+            };
+            if conflicts.is_empty() {
+                let new_commit_oid = series.commit(&msg, true)?;
+                let (short_id, summary) = commit_summarize_components(repo, new_commit_oid)?;
+                println!("[{} {}] {}", series.name(), short_id, summary);
+                return Ok(());
+            }
 
-    let mut revwalk = repo.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
-    revwalk.push(series.id())?;
-    revwalk.hide(base.id())?;
-    let mut commits: Vec<Commit> = revwalk.map(|c| {
-        let id = c?;
-        let commit = repo.find_commit(id)?;
-        if commit.parent_ids().count() > 1 {
-            return Err(format!(
-                "Error: cannot format merge commit as patch:\n{}",
-                commit_summarize(repo, id)?,
-            ).into());
+            let mut file = File::create(&merge_msg_path)?;
+            writeln!(file, "{}\n\n# Conflicts:", msg)?;
+            let mut err = String::new();
+            for conflict in conflicts.iter() {
+                writeln!(file, "#\t{}", conflict.entry)?;
+                writeln!(err, "CONFLICT ({}): Merge conflict in {}", conflict.kind, conflict.entry).unwrap();
+            }
+            err.push_str(concat!(
+                "Automatic series merge failed; the working version keeps the current series and base\n",
+                "for conflicting entries. Fix the conflicts, then use \"git series commit -a\" to\n",
+                "commit the merge, or \"git series merge --abort\" to abort it.",
+            ));
+            return Err(err.into());
         }
-        Ok(commit)
-    }).collect::<Result<_>>()?;
-    if commits.is_empty() {
-        return Err("No patches to format; series and base identical.".into());
-    }
-
-    let committer = get_signature(&config, "COMMITTER")?;
-    let committer_name = committer.name().unwrap();
-    let committer_email = committer.email().unwrap();
-    let message_id_suffix = format!(
-        "{}.git-series.{}",
-        committer.when().seconds(),
-        committer_email,
-    );
-
-    let cover_entry = stree.get_name("cover");
-    let mut in_reply_to_message_id = m.value_of("in-reply-to")
-        .map(|v| format!(
-            "{}{}{}",
-            if v.starts_with('<') { "" } else { "<" },
-            v,
-            if v.ends_with('>') { "" } else { ">" },
-        ));
-
-    let version = m.value_of("reroll-count");
-    let subject_prefix = if m.is_present("rfc") {
-        "RFC PATCH"
-    } else {
-        m.value_of("subject-prefix").unwrap_or("PATCH")
-    };
-    let subject_patch = version.map_or(
-        subject_prefix.to_string(),
-        |n| format!("{}{}v{}", subject_prefix, ensure_space(&subject_prefix), n),
-    );
-    let file_prefix = version.map_or("".to_string(), |n| format!("v{}-", n));
-
-    let num_width = commits.len().to_string().len();
+    }
+    Ok(())
+}
 
-    let signature = mail_signature();
+fn note(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let commit = repo.revparse_single(m.value_of("commit").unwrap_or("HEAD"))?.peel_to_commit()?;
+    if commit.parent_count() != 1 {
+        return Err(format!("Cannot add a note to a merge or root commit:\n{}", commit_summarize(repo, commit.id())?).into());
+    }
+    let working_note = series.note(&commit)?;
 
-    if to_stdout {
-        out.auto_pager(&config, "format-patch", true)?;
+    if m.is_present("delete") {
+        if working_note.is_none() {
+            return Err("No note to delete".into());
+        }
+        series.set_note(&commit, None)?;
+        println!("Deleted note");
+        return Ok(());
     }
-    let diffcolors = if to_stdout {
-        DiffColors::new(out, &config)?
-    } else {
-        DiffColors::plain()
+
+    let msg = match m.value_of("m") {
+        Some(msg) => git2::message_prettify(msg, None)?,
+        None => {
+            let filename = repo.path().join("NOTE_EDITMSG");
+            let mut file = File::create(&filename)?;
+            match working_note {
+                Some(ref note) => write!(file, "{}{}", note.text, NOTE_COMMENT)?,
+                None => write!(file, "{}", NOTE_COMMENT)?,
+            }
+            writeln!(file, "#\n# {}", commit_summarize(repo, commit.id())?)?;
+            drop(file);
+            let config = repo.config()?;
+            run_editor(&config, &filename)?;
+            let mut file = File::open(&filename)?;
+            let mut msg = String::new();
+            file.read_to_string(&mut msg)?;
+            git2::message_prettify(msg, git2::DEFAULT_COMMENT_CHAR)?
+        }
     };
-    let mut out: Box<dyn IoWrite> = if to_stdout {
-        Box::new(out)
+    if msg.is_empty() {
+        return Err("Empty note; not changing.\n(To delete the note, use \"git series note -d\".)".into());
+    }
+
+    if series.set_note(&commit, Some(&msg))? {
+        println!("Updated note");
     } else {
-        Box::new(std::io::stdout())
-    };
-    let patch_file = |name: &str| -> Result<Box<dyn IoWrite>> {
-        let name = format!("{}{}", file_prefix, name);
-        println!("{}", name);
-        Ok(Box::new(File::create(name)?))
-    };
+        println!("Note unchanged");
+    }
 
-    if let Some(ref entry) = cover_entry {
-        let cover_blob = repo.find_blob(entry.id())?;
-        let content = std::str::from_utf8(cover_blob.content())?.to_string();
-        let (subject, body) = split_message(&content);
-
-        let series_tree = repo.find_commit(series.id())?.tree().unwrap();
-        let base_tree = repo.find_commit(base.id())?.tree().unwrap();
-        let diff = repo.diff_tree_to_tree(Some(&base_tree), Some(&series_tree), None)?;
-        let stats = diffstat(&diff)?;
-
-        if !to_stdout {
-            out = patch_file("0000-cover-letter.patch")?;
-        }
-        writeln!(out, "From {} Mon Sep 17 00:00:00 2001", shead_commit.id())?;
-        let cover_message_id = format!("<cover.{}.{}>", shead_commit.id(), message_id_suffix);
-        writeln!(out, "Message-Id: {}", cover_message_id)?;
-        if let Some(ref message_id) = in_reply_to_message_id {
-            writeln!(out, "In-Reply-To: {}", message_id)?;
-            writeln!(out, "References: {}", message_id)?;
-        }
-        in_reply_to_message_id = Some(cover_message_id);
-        writeln!(out, "From: {} <{}>", committer_name, committer_email)?;
-        writeln!(out, "Date: {}", date_822(committer.when()))?;
-        writeln!(
-            out,
-            "Subject: [{}{}{:0>num_width$}/{}] {}\n",
-            subject_patch,
-            ensure_space(&subject_patch),
-            0,
-            commits.len(),
-            subject,
-            num_width=num_width,
-        )?;
-        if !body.is_empty() {
-            writeln!(out, "{}", body)?;
-        }
-        writeln!(out, "{}", shortlog(&mut commits))?;
-        writeln!(out, "{}", stats)?;
-        writeln!(out, "base-commit: {}", base.id())?;
-        writeln!(out, "{}", signature)?;
-    }
-
-    for (commit_num, commit) in commits.iter().enumerate() {
-        let first_mail = commit_num == 0 && cover_entry.is_none();
-        if to_stdout && !first_mail {
-            writeln!(out)?;
-        }
+    Ok(())
+}
 
-        let message = commit.message().unwrap();
-        let (subject, body) = split_message(message);
-        let commit_id = commit.id();
-        let commit_author = commit.author();
-        let commit_author_name = commit_author.name().unwrap();
-        let commit_author_email = commit_author.email().unwrap();
-        let summary_sanitized = sanitize_summary(&subject);
-        let this_message_id = format!("<{}.{}>", commit_id, message_id_suffix);
-        let parent = commit.parent(0)?;
-        let diff = repo.diff_tree_to_tree(
-            Some(&parent.tree().unwrap()),
-            Some(&commit.tree().unwrap()),
-            None,
-        )?;
-        let stats = diffstat(&diff)?;
-
-        if !to_stdout {
-            out = patch_file(&format!("{:04}-{}.patch", commit_num + 1, summary_sanitized))?;
-        }
-        writeln!(out, "From {} Mon Sep 17 00:00:00 2001", commit_id)?;
-        writeln!(out, "Message-Id: {}", this_message_id)?;
-        if let Some(ref message_id) = in_reply_to_message_id {
-            writeln!(out, "In-Reply-To: {}", message_id)?;
-            writeln!(out, "References: {}", message_id)?;
-        }
-        if first_mail {
-            in_reply_to_message_id = Some(this_message_id);
-        }
-        if no_from {
-            writeln!(out, "From: {} <{}>", commit_author_name, commit_author_email)?;
+fn push(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let remote = m.value_of("remote").unwrap();
+    let series = match m.value_of("name") {
+        Some(name) => Series::find(repo, name)?,
+        None => Series::require_current(repo)?,
+    };
+    series.require_head()?;
+    let name = series.name();
+    let refspecs = push_refspecs(repo, name, m.is_present("internals"), m.is_present("force"))?;
+
+    let output = Command::new("git").arg("push").arg("--porcelain").arg(remote).args(&refspecs)
+        .stderr(Stdio::inherit())
+        .output()?;
+    let mut rejected = false;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<&str> = line.splitn(3, '\t').collect();
+        if fields.len() != 3 {
+            continue;
+        }
+        let dst = fields[1].rsplit(':').next().unwrap();
+        let what = if dst.starts_with(STAGED_PREFIX) {
+            format!("staged version of series {}", name)
+        } else if dst.starts_with(WORKING_PREFIX) {
+            format!("working version of series {}", name)
         } else {
-            writeln!(out, "From: {} <{}>", committer_name, committer_email)?;
-        }
-        writeln!(out, "Date: {}", date_822(commit_author.when()))?;
-        let prefix = if commits.len() == 1 && cover_entry.is_none() {
-            if subject_patch.is_empty() {
-                "".to_string()
-            } else {
-                format!("[{}] ", subject_patch)
+            format!("series {}", name)
+        };
+        let result = match fields[0] {
+            " " => "fast-forward".to_string(),
+            "+" => "forced update".to_string(),
+            "*" => "new".to_string(),
+            "=" => "up to date".to_string(),
+            "!" => {
+                rejected = true;
+                format!("rejected {}", fields[2].trim_start_matches("[rejected] "))
             }
-        } else {
-            format!(
-                "[{}{}{:0>num_width$}/{}] ",
-                subject_patch,
-                ensure_space(&subject_patch),
-                commit_num + 1,
-                commits.len(),
-                num_width=num_width,
-            )
+            _ => fields[2].to_string(),
         };
-        writeln!(out, "Subject: {}{}\n", prefix, subject)?;
-
-        if !no_from && (commit_author_name, commit_author_email) != (committer_name, committer_email) {
-            writeln!(out, "From: {} <{}>\n", commit_author_name, commit_author_email)?;
-        }
-        if !body.is_empty() {
-            write!(out, "{}{}", body, ensure_nl(&body))?;
-        }
-        writeln!(out, "---")?;
-        writeln!(out, "{}", stats)?;
-        write_diff(&mut out, &diffcolors, &diff, false)?;
-        if first_mail {
-            writeln!(out, "\nbase-commit: {}", base.id())?;
-        }
-        writeln!(out, "{}", signature)?;
+        println!("{} {}: {}", remote, what, result);
+    }
+    if rejected {
+        return Err(format!(
+            concat!(
+                "The series {} on {} has series commits that the local series does not.\n",
+                "Use \"git series fetch {} {}\" and \"git series merge {}/git-series/{}\" to merge it,\n",
+                "or \"git series push --force\" to overwrite it.",
+            ),
+            name, remote, remote, name, remote, name,
+        ).into());
+    }
+    if !output.status.success() {
+        return Err(format!("git push exited with status {}", output.status).into());
     }
-
     Ok(())
 }
 
-fn log(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
-    let config = repo.config()?.snapshot()?;
-    out.auto_pager(&config, "log", true)?;
-    let diffcolors = DiffColors::new(out, &config)?;
-
-    let shead_id = repo.refname_to_id(SHEAD_REF)?;
-    let mut hidden_ids = std::collections::HashSet::new();
-    let mut commit_stack = Vec::new();
-    commit_stack.push(shead_id);
-    while let Some(oid) = commit_stack.pop() {
-        let commit = repo.find_commit(oid)?;
-        let tree = commit.tree()?;
-        for parent_id in commit.parent_ids() {
-            if tree.get_id(parent_id).is_some() {
-                hidden_ids.insert(parent_id);
-            } else {
-                commit_stack.push(parent_id);
-            }
-        }
+fn parse_range(repo: &Repository, spec: &str) -> Result<(git2::Oid, git2::Oid)> {
+    let revspec = repo.revparse(spec).map_err(|e| format!("Invalid range {}: {}", spec, e.message()))?;
+    if !revspec.mode().contains(git2::RevparseMode::RANGE) || revspec.mode().contains(git2::RevparseMode::MERGE_BASE) {
+        return Err(format!("Not a range: {}; use <base>..<tip>", spec).into());
     }
+    let commit = |obj: Option<&Object>| -> Result<git2::Oid> {
+        Ok(obj.ok_or_else(|| format!("Invalid range: {}", spec))?.peel_to_commit()?.id())
+    };
+    Ok((commit(revspec.from())?, commit(revspec.to())?))
+}
 
-    let mut revwalk = repo.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TOPOLOGICAL);
-    revwalk.push(shead_id)?;
-    for id in hidden_ids {
-        revwalk.hide(id)?;
+fn range_diff(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let range1 = parse_range(repo, m.value_of("range1").unwrap())?;
+    let range2 = parse_range(repo, m.value_of("range2").unwrap())?;
+    let opts = range_diff_options(m)?;
+
+    if json_format(m) {
+        let range_diff = RangeDiff::new(repo, &DiffColors::plain(), &opts, range1, range2)?;
+        return write_json(out, &RangeDiffRecord::new(&range_diff));
     }
 
-    let show_diff = m.is_present("patch");
+    let config = repo.config()?.snapshot()?;
+    out.auto_pager(&config, "range-diff", true)?;
+    let diffcolors = DiffColors::new(out, &config)?;
+    RangeDiff::new(repo, &diffcolors, &opts, range1, range2)?.write_pairs(out, &diffcolors)
+}
 
-    let mut first = true;
-    for oid in revwalk {
-        if first {
-            first = false;
-        } else {
-            writeln!(out)?;
+fn run_rebase(repo: &Repository, rebase: Rebase) -> Result<()> {
+    while let Some(step) = rebase.step().map_err(|e| format!("{}\nFix the problem. {}", e, REBASE_RESUME))? {
+        let stopped = matches!(step, RebaseStep::Stopped(_));
+        if let Some(msg) = rebase_step_message(repo, step)? {
+            return Err(msg.into());
+        } else if stopped {
+            return Ok(());
         }
-        let oid = oid?;
-        let commit = repo.find_commit(oid)?;
-        let author = commit.author();
+    }
+    let series = Series::find(repo, &rebase.series)?;
+    let mut old = previous_ranges(&series)?;
+    if let Some(base) = series.base()? {
+        old.insert(0, (base, rebase.orig_head));
+    }
+    let onto = rebase.onto;
+    let head = rebase.finish()?;
+    println!("Successfully rebased series onto {}", commit_summarize(repo, onto)?);
+    print_restacks(repo, &restack_dependents(repo, series.name(), &old, (onto, head))?)
+}
 
-        writeln!(out, "{}", diffcolors.commit.paint(format!("commit {}", oid)))?;
-        writeln!(out, "Author: {} <{}>", author.name().unwrap(), author.email().unwrap())?;
-        writeln!(out, "Date:   {}\n", date_822(author.when()))?;
-        for line in commit.message().unwrap().lines() {
-            writeln!(out, "    {}", line)?;
+fn print_restacks(repo: &Repository, restacks: &[Restack]) -> Result<()> {
+    for restack in restacks {
+        match restack.status {
+            RestackStatus::Restacked(_) => {
+                println!("Restacked series {} onto {}", restack.name, commit_summarize(repo, restack.new_base)?);
+            }
+            RestackStatus::Failed(commit) => {
+                println!(
+                    "Could not restack series {}: {} does not apply onto {}",
+                    restack.name,
+                    commit_summarize(repo, commit)?,
+                    commit_summarize(repo, restack.new_base)?,
+                );
+                println!("Use \"git series checkout {}\" and \"git series rebase\" to rebase it.", restack.name);
+            }
         }
+    }
+    Ok(())
+}
 
-        if show_diff {
-            let tree = commit.tree()?;
-            let parent_ids: Vec<_> = commit.parent_ids().take_while(|parent_id| tree.get_id(*parent_id).is_none()).collect();
+fn restack(repo: &Repository) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let internals = series.internals()?;
+    let tip = internals.working.get("series")?
+        .ok_or("Could not find entry \"series\" in working index")?.id();
+    let base = internals.working.get("base")?
+        .ok_or("Series has no base set.\nUse \"git series base\" to set base.")?.id();
+    let restacks = restack_dependents(repo, series.name(), &previous_ranges(&series)?, (base, tip))?;
+    if restacks.is_empty() {
+        println!("No series based on a previous version of {} to restack.", series.name());
+    }
+    print_restacks(repo, &restacks)
+}
 
-            writeln!(out)?;
-            if parent_ids.len() > 1 {
-                writeln!(out, "(Diffs of series merge commits not yet supported)")?;
-            } else {
-                let parent_tree = if parent_ids.is_empty() {
-                    None
-                } else {
-                    Some(repo.find_commit(parent_ids[0])?.tree()?)
-                };
-                write_series_diff(out, repo, &diffcolors, parent_tree.as_ref(), Some(&tree))?;
+// Report a step of a rebase, returning an error message if the rebase stopped on a failure.
+fn rebase_step_message(repo: &Repository, step: RebaseStep) -> Result<Option<String>> {
+    match step {
+        RebaseStep::Applied { .. } | RebaseStep::Dropped(_) | RebaseStep::Exec(_) => Ok(None),
+        RebaseStep::Empty(old) => {
+            println!("Dropping {} -- patch contents already in the new base", commit_summarize(repo, old)?);
+            Ok(None)
+        }
+        RebaseStep::Stopped(RebaseStop::Edit { new, .. }) => {
+            println!("Stopped at {}", commit_summarize(repo, new)?);
+            println!("You can amend the commit now, with \"git commit --amend\".");
+            println!("Then run \"git series rebase --continue\".");
+            Ok(None)
+        }
+        RebaseStep::Stopped(RebaseStop::Conflict(old)) => {
+            let mut msg = format!("Could not apply {}\n", commit_summarize(repo, old)?);
+            for conflict in repo.index()?.conflicts()? {
+                let conflict = conflict?;
+                if let Some(entry) = conflict.our.or(conflict.their).or(conflict.ancestor) {
+                    writeln!(msg, "CONFLICT: {}", String::from_utf8_lossy(&entry.path)).unwrap();
+                }
             }
+            write!(msg, "Resolve the conflicts and mark them resolved with \"git add\".\n{}", REBASE_RESUME).unwrap();
+            Ok(Some(msg))
+        }
+        RebaseStep::Stopped(RebaseStop::ExecFailed(command)) => {
+            Ok(Some(format!("Command failed: {}\nFix the problem. {}", command, REBASE_RESUME)))
         }
     }
-
-    Ok(())
 }
 
 fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let in_progress = Rebase::in_progress(repo)?;
+    let resume = ["continue", "skip", "abort"].iter().filter(|&&arg| m.is_present(arg)).count();
+    if resume > 1 || (resume == 1 && (m.is_present("onto") || m.is_present("interactive") || m.is_present("autosquash"))) {
+        return Err("--continue, --skip, and --abort cannot be combined with each other or with starting a rebase.".into());
+    } else if resume == 1 {
+        let rebase = in_progress.ok_or("No series rebase in progress.")?;
+        if m.is_present("abort") {
+            let orig_head = rebase.orig_head;
+            rebase.abort()?;
+            println!("Aborted rebase; HEAD is now at {}", commit_summarize(repo, orig_head)?);
+            return Ok(());
+        } else if m.is_present("skip") {
+            rebase.skip()?;
+        } else if let Some(step) = rebase.resume()? {
+            let stopped = matches!(step, RebaseStep::Stopped(_));
+            if let Some(msg) = rebase_step_message(repo, step)? {
+                return Err(msg.into());
+            } else if stopped {
+                return Ok(());
+            }
+        }
+        return run_rebase(repo, rebase);
+    }
+
+    if in_progress.is_some() {
+        return Err(concat!(
+            "git series rebase already in progress.\n",
+            "Use \"git series rebase --continue\", \"--skip\", or \"--abort\".",
+        ).into());
+    }
     match repo.state() {
         git2::RepositoryState::Clean => (),
-        git2::RepositoryState::RebaseMerge
-            if repo.path().join("rebase-merge").join("git-series").exists()
-        => {
-            return Err(concat!(
-                "git series rebase already in progress.\n",
-                "Use \"git rebase --continue\" or \"git rebase --abort\".",
-            ).into());
-        }
         s => return Err(format!("{:?} in progress; cannot rebase", s).into()),
     }
 
@@ -1916,7 +1425,24 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
 
     let interactive = m.is_present("interactive");
     let onto = match m.value_of("onto") {
-        None => None,
+        None => {
+            // Restack onto the current version of the series this one depends on, if any.
+            let mut tips = Vec::new();
+            for dependency in Series::require_current(repo)?.dependencies()? {
+                if let Dependency::Series(name) = dependency {
+                    let dep_internals = Series::find(repo, &name)?.internals()?;
+                    let tip = dep_internals.working.get("series")?
+                        .ok_or_else(|| format!("Prerequisite series {} has no commits", name))?;
+                    tips.push(tip.id());
+                }
+            }
+            if tips.len() > 1 && !interactive {
+                return Err("Series depends on more than one series; specify a commit to rebase onto.".into());
+            } else if tips.is_empty() && !interactive && !m.is_present("autosquash") {
+                return Err("Nothing to rebase onto; specify a commit to rebase onto, or use -i.".into());
+            }
+            if tips.len() == 1 { tips.pop() } else { None }
+        }
         Some(onto) => {
             let obj = repo.revparse_single(onto)?;
             let commit = obj.peel(ObjectType::Commit)?;
@@ -1925,64 +1451,61 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
     };
 
     let newbase = onto.unwrap_or(base.id());
-    if newbase == base.id() && !interactive {
+
+    let mut todo: Vec<TodoItem> = commits.iter()
+        .map(|commit| TodoItem::new("pick", commit.id(), commit.summary().unwrap_or("")))
+        .collect();
+    if m.is_present("drop-applied") {
+        let include_modified = m.is_present("drop-modified");
+        let applied: Vec<_> = upstream_status(repo, base.id(), series.id(), newbase)?.into_iter()
+            .filter(|patch| patch.status.is_applied(include_modified))
+            .map(|patch| patch.commit.id())
+            .collect();
+        for item in todo.iter_mut().filter(|item| applied.contains(&item.id)) {
+            item.action = "drop";
+        }
+    }
+    let autosquash_config = interactive && repo.config()?.get_bool("rebase.autoSquash").unwrap_or(false);
+    if m.is_present("autosquash") || autosquash_config {
+        todo = autosquash(todo);
+    }
+
+    if newbase == base.id() && !interactive && todo.iter().all(|item| item.action == "pick") {
         println!("Nothing to do: base unchanged and not rebasing interactively");
         return Ok(());
     }
 
-    let (base_short, _) = commit_summarize_components(&repo, base.id())?;
-    let (newbase_short, _) = commit_summarize_components(&repo, newbase)?;
-    let (series_short, _) = commit_summarize_components(&repo, series.id())?;
+    let (base_short, _) = commit_summarize_components(repo, base.id())?;
+    let (newbase_short, _) = commit_summarize_components(repo, newbase)?;
+    let (series_short, _) = commit_summarize_components(repo, series.id())?;
 
     let newbase_obj = repo.find_commit(newbase)?.into_object();
 
-    let dir = tempdir::TempDir::new_in(repo.path(), "rebase-merge")?;
-    let final_path = repo.path().join("rebase-merge");
-    let mut create = std::fs::OpenOptions::new();
-    create.write(true).create_new(true);
-
-    create.open(dir.path().join("git-series"))?;
-    create.open(dir.path().join("quiet"))?;
-    create.open(dir.path().join("interactive"))?;
-
-    let mut head_name_file = create.open(dir.path().join("head-name"))?;
-    writeln!(head_name_file, "detached HEAD")?;
-
-    let mut onto_file = create.open(dir.path().join("onto"))?;
-    writeln!(onto_file, "{}", newbase)?;
-
-    let mut orig_head_file = create.open(dir.path().join("orig-head"))?;
-    writeln!(orig_head_file, "{}", series.id())?;
-
-    let git_rebase_todo_filename = dir.path().join("git-rebase-todo");
-    let mut git_rebase_todo = create.open(&git_rebase_todo_filename)?;
-    for mut commit in commits {
-        writeln!(git_rebase_todo, "pick {}", commit_obj_summarize(&mut commit)?)?;
-    }
-    if let Some(onto) = onto {
-        writeln!(git_rebase_todo, "exec git series base {}", onto)?;
-    }
-    writeln!(git_rebase_todo, "\n# Rebase {}..{} onto {}", base_short, series_short, newbase_short)?;
-    write!(git_rebase_todo, "{}", REBASE_COMMENT)?;
-    drop(git_rebase_todo);
-
     // Interactive editor
     if interactive {
+        let dir = tempdir::TempDir::new_in(repo.path(), "series-rebase")?;
+        let todo_filename = dir.path().join("git-rebase-todo");
+        let mut todo_file = File::create(&todo_filename)?;
+        write!(todo_file, "{}", write_todo(repo, &todo)?)?;
+        writeln!(todo_file, "\n# Rebase {}..{} onto {}", base_short, series_short, newbase_short)?;
+        write!(todo_file, "{}", REBASE_COMMENT)?;
+        drop(todo_file);
         let config = repo.config()?;
-        run_editor(&config, &git_rebase_todo_filename)?;
-        let mut file = File::open(&git_rebase_todo_filename)?;
-        let mut todo = String::new();
-        file.read_to_string(&mut todo)?;
-        let todo = git2::message_prettify(todo, git2::DEFAULT_COMMENT_CHAR)?;
+        run_editor(&config, &todo_filename)?;
+        let mut edited = String::new();
+        File::open(&todo_filename)?.read_to_string(&mut edited)?;
+        todo = parse_todo(repo, &edited)?;
         if todo.is_empty() {
             return Err("Nothing to do".into());
         }
+        if let Some(item) = todo.iter().find(|item| item.action != "exec" && item.action != "drop") {
+            if item.action == "squash" || item.action == "fixup" {
+                return Err(format!("Cannot {} without a previous commit: {}", item.action, item.summary).into());
+            }
+        }
     }
 
-    // Avoid races by not calling .into_path until after the rename succeeds.
-    std::fs::rename(dir.path(), final_path)?;
-    dir.into_path();
-
+    // Only record the rebase once HEAD is at the new base, so a failed checkout leaves no state.
     checkout_tree(repo, &newbase_obj)?;
     repo.reference(
         "HEAD",
@@ -1990,172 +1513,228 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
         true,
         &format!("rebase -i (start): checkout {}", newbase),
     )?;
+    let rebase = Rebase::start(repo, internals.name(), newbase, series.id(), &todo)?;
 
-    let status = Command::new("git").arg("rebase").arg("--continue").status()?;
-    if !status.success() {
-        return Err(format!("git rebase --continue exited with status {}", status).into());
+    run_rebase(repo, rebase)
+}
+
+fn depends_on(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let mut dependencies = series.dependencies()?;
+    if !(m.is_present("series") || m.is_present("commit") || m.is_present("delete")) {
+        for dependency in dependencies.iter() {
+            match dependency {
+                Dependency::Series(name) => writeln!(out, "series {}", name)?,
+                Dependency::Commit(id) => writeln!(out, "commit {}", commit_summarize(repo, *id)?)?,
+            }
+        }
+        return Ok(());
     }
 
-    Ok(())
+    for spec in m.values_of("delete").into_iter().flatten() {
+        let len = dependencies.len();
+        dependencies.retain(|d| match d {
+            Dependency::Series(name) => name != spec,
+            Dependency::Commit(_) => true,
+        });
+        if dependencies.len() == len {
+            if let Ok(obj) = repo.revparse_single(spec) {
+                let id = obj.peel(ObjectType::Commit)?.id();
+                dependencies.retain(|d| d != &Dependency::Commit(id));
+            }
+        }
+        if dependencies.len() == len {
+            return Err(format!("{} is not a prerequisite of this series", spec).into());
+        }
+    }
+    for name in m.values_of("series").into_iter().flatten() {
+        if name == series.name() {
+            return Err("A series cannot depend on itself".into());
+        }
+        Series::find(repo, name)?;
+        let dependency = Dependency::Series(name.to_string());
+        if !dependencies.contains(&dependency) {
+            dependencies.push(dependency);
+        }
+    }
+    for spec in m.values_of("commit").into_iter().flatten() {
+        let id = repo.revparse_single(spec)?.peel(ObjectType::Commit)?.id();
+        let dependency = Dependency::Commit(id);
+        if !dependencies.contains(&dependency) {
+            dependencies.push(dependency);
+        }
+    }
+    series.set_dependencies(&dependencies)
+}
+
+fn recipients(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let mut recipients = series.recipients()?;
+    if !(m.is_present("to") || m.is_present("cc") || m.is_present("delete")) {
+        for address in recipients.to.iter() {
+            writeln!(out, "To: {}", address)?;
+        }
+        for address in recipients.cc.iter() {
+            writeln!(out, "Cc: {}", address)?;
+        }
+        return Ok(());
+    }
+
+    for address in m.values_of("delete").into_iter().flatten() {
+        if !recipients.remove(address) {
+            return Err(format!("{} is not a recipient of this series", address).into());
+        }
+    }
+    for address in m.values_of("to").into_iter().flatten() {
+        recipients.add_to(address);
+    }
+    for address in m.values_of("cc").into_iter().flatten() {
+        recipients.add_cc(address);
+    }
+    series.set_recipients(&recipients)
 }
 
 fn req(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
     let config = repo.config()?.snapshot()?;
-    let shead = repo.find_reference(SHEAD_REF)?;
-    let shead_commit = shead.resolve()?.peel_to_commit()?;
-    let stree = shead_commit.tree()?;
-
-    let series = stree.get_name("series")
-        .ok_or("Internal error: series did not contain \"series\"")?;
-    let series_id = series.id();
-    let mut series_commit = repo.find_commit(series_id)?;
-    let base = stree.get_name("base")
-        .ok_or("Cannot request pull; no base set.\nUse \"git series base\" to set base.")?;
-    let mut base_commit = repo.find_commit(base.id())?;
-
-    let (cover_content, subject, cover_body) = if let Some(entry) = stree.get_name("cover") {
-        let cover_blob = repo.find_blob(entry.id())?;
-        let content = std::str::from_utf8(cover_blob.content())?.to_string();
-        let (subject, body) = split_message(&content);
-        (Some(content.to_string()), subject.to_string(), Some(body.to_string()))
-    } else {
-        (None, shead_series_name(&shead)?, None)
+    let series = Series::require_current(repo)?;
+    let version = series.require_head()?;
+
+    let series_config = version.config(repo)?;
+    let mut url_tag = m.values_of("url_tag").unwrap();
+    let tag = url_tag.next_back().unwrap();
+    let url = match url_tag.next_back() {
+        Some(url) => url,
+        None => series_config.get("req.url")
+            .ok_or("No repository URL given, and the series has no req.url set.\nUse \"git series config req.url <url>\" to set one.")?,
     };
+    let patch = m.is_present("patch") || series_config.get_bool("req.patch")?.unwrap_or(false);
+    let mut pull_request = PullRequest::new(repo, series.name(), &version, url, tag)?;
 
-    let url = m.value_of("url").unwrap();
-    let tag = m.value_of("tag").unwrap();
-    let full_tag = format!("refs/tags/{}", tag);
-    let full_tag_peeled = format!("{}^{{}}", full_tag);
-    let full_head = format!("refs/heads/{}", tag);
-    let mut remote = repo.remote_anonymous(url)?;
-    remote.connect(git2::Direction::Fetch)
-        .map_err(|e| format!("Could not connect to remote repository {}\n{}", url, e))?;
-    let remote_heads = remote.list()?;
-
-    /* Find the requested name as either a tag or head */
-    let mut opt_remote_tag = None;
-    let mut opt_remote_tag_peeled = None;
-    let mut opt_remote_head = None;
-    for h in remote_heads {
-        if h.name() == full_tag {
-            opt_remote_tag = Some(h.oid());
-        } else if h.name() == full_tag_peeled {
-            opt_remote_tag_peeled = Some(h.oid());
-        } else if h.name() == full_head {
-            opt_remote_head = Some(h.oid());
-        }
-    }
-    let (msg, extra_body, remote_pull_name) = match (opt_remote_tag, opt_remote_tag_peeled, opt_remote_head) {
-        (Some(remote_tag), Some(remote_tag_peeled), _) => {
-            if remote_tag_peeled != series_id {
-                return Err(format!(
-                    "Remote tag {} does not refer to series {}",
-                    tag, series_id,
-                ).into());
-            }
-            let local_tag = repo.find_tag(remote_tag)
-                .map_err(|e| format!(
-                    "Could not find remote tag {} ({}) in local repository: {}",
-                    tag, remote_tag, e,
-                ))?;
-            let mut local_tag_msg = local_tag.message().unwrap().to_string();
-            if let Some(sig_index) = local_tag_msg.find("-----BEGIN PGP ") {
-                local_tag_msg.truncate(sig_index);
-            }
-            let extra_body = match cover_content {
-                Some(ref content) if !local_tag_msg.contains(content) => cover_body,
-                _ => None,
-            };
-            (Some(local_tag_msg), extra_body, full_tag)
-        }
-        (Some(remote_tag), None, _) => {
-            if remote_tag != series_id {
-                return Err(format!(
-                    "Remote unannotated tag {} does not refer to series {}",
-                    tag, series_id,
-                ).into());
-            }
-            (cover_content, None, full_tag)
-        }
-        (_, _, Some(remote_head)) => {
-            if remote_head != series_id {
-                return Err(format!(
-                    "Remote branch {} does not refer to series {}",
-                    tag, series_id,
-                ).into());
+    out.auto_pager(&config, "request-pull", true)?;
+    let diffcolors = DiffColors::new(out, &config)?;
+    pull_request.write(out, repo, &diffcolors, patch)
+}
+
+fn upstream(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let current = series.upstream()?;
+
+    if !m.is_present("delete") && !m.is_present("upstream") {
+        match current {
+            None => return Err("Patch series has no upstream set".into()),
+            Some(upstream) => {
+                println!("{}", upstream);
+                return Ok(());
             }
-            (cover_content, None, full_head)
-        }
-        _ => {
-            return Err(format!("Remote does not have either a tag or branch named {}", tag).into())
         }
-    };
+    }
+
+    let new = m.value_of("upstream");
+    if let Some(upstream) = new {
+        repo.revparse_single(upstream)?.peel(ObjectType::Commit)?;
+    }
+    if !series.set_upstream(new)? {
+        println!("Upstream unchanged");
+        return Ok(());
+    }
+    match new {
+        None => println!("Cleared patch series upstream"),
+        Some(upstream) => println!("Set patch series upstream to {}", upstream),
+    }
+    Ok(())
+}
 
-    let commit_subject_date = |commit: &mut Commit| -> String {
-        let date = date_822(commit.author().when());
-        let summary = commit.summary().unwrap();
-        format!("  {} ({})", summary, date)
+fn do_upstream_status(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let series = Series::require_current(repo)?;
+    let internals = series.internals()?;
+    let series_id = internals.working.get("series")?
+        .ok_or("Could not find entry \"series\" in working index")?.id();
+    let base = internals.working.get("base")?
+        .ok_or("Series has no base set.\nUse \"git series base\" to set base.")?.id();
+    let upstream_name = match m.value_of("upstream") {
+        Some(upstream) => upstream.to_string(),
+        None => series.upstream()?
+            .ok_or("No upstream given, and the series has no upstream set.\nUse \"git series upstream\" to set one.")?,
     };
+    let upstream = repo.revparse_single(&upstream_name)?.peel_to_commit()?.id();
+    let patches = upstream_status(repo, base, series_id, upstream)?;
 
-    let mut revwalk = repo.revwalk()?;
-    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
-    revwalk.push(series_id)?;
-    revwalk.hide(base.id())?;
-    let mut commits: Vec<Commit> = revwalk
-        .map(|c| Ok(repo.find_commit(c?)?))
-        .collect::<Result<_>>()?;
-    if commits.is_empty() {
-        return Err("No patches to request pull of; series and base identical.".into());
-    }
-
-    let author = get_signature(&config, "AUTHOR")?;
-    let author_email = author.email().unwrap();
-    let message_id = format!(
-        "<pull.{}.{}.git-series.{}>",
-        shead_commit.id(),
-        author.when().seconds(),
-        author_email
-    );
-
-    let diff = repo.diff_tree_to_tree(
-        Some(&base_commit.tree().unwrap()),
-        Some(&series_commit.tree().unwrap()),
-        None,
-    )?;
-    let stats = diffstat(&diff)?;
+    if json_format(m) {
+        let records: Vec<_> = patches.iter().enumerate()
+            .map(|(i, patch)| UpstreamPatchRecord::new(i + 1, patch))
+            .collect();
+        return write_json(out, &records);
+    }
 
-    out.auto_pager(&config, "request-pull", true)?;
-    let diffcolors = DiffColors::new(out, &config)?;
+    let nwidth = patches.len().to_string().len();
+    for (i, patch) in patches.iter().enumerate() {
+        let mut commit = patch.commit.clone();
+        write!(out, "{:8} {:nwidth$}: {}", patch.status.name(), i + 1, commit_obj_summarize(&mut commit)?, nwidth = nwidth)?;
+        match patch.upstream {
+            Some(ref upstream) => writeln!(out, " (upstream {})", commit_summarize_components(repo, upstream.id())?.0)?,
+            None => writeln!(out)?,
+        }
+    }
 
-    writeln!(out, "From {} Mon Sep 17 00:00:00 2001", shead_commit.id())?;
-    writeln!(out, "Message-Id: {}", message_id)?;
-    writeln!(out, "From: {} <{}>", author.name().unwrap(), author_email)?;
-    writeln!(out, "Date: {}", date_822(author.when()))?;
-    writeln!(out, "Subject: [GIT PULL] {}\n", subject)?;
-    if let Some(extra_body) = extra_body {
-        writeln!(out, "{}", extra_body)?;
-    }
-    writeln!(out, "The following changes since commit {}:\n", base.id())?;
-    writeln!(out, "{}\n", commit_subject_date(&mut base_commit))?;
-    writeln!(out, "are available in the git repository at:\n")?;
-    writeln!(out, "  {} {}\n", url, remote_pull_name)?;
-    writeln!(out, "for you to fetch changes up to {}:\n", series.id())?;
-    writeln!(out, "{}\n", commit_subject_date(&mut series_commit))?;
-    writeln!(out, "----------------------------------------------------------------")?;
-    if let Some(msg) = msg {
-        writeln!(out, "{}", msg)?;
-        writeln!(out, "----------------------------------------------------------------")?;
-    }
-    writeln!(out, "{}", shortlog(&mut commits))?;
-    writeln!(out, "{}", stats)?;
-    if m.is_present("patch") {
-        write_diff(out, &diffcolors, &diff, false)?;
-    }
-    writeln!(out, "{}", mail_signature())?;
+    if m.is_present("drop-applied") {
+        let include_modified = m.is_present("drop-modified");
+        let applied = patches.iter().take_while(|p| p.status.is_applied(include_modified)).count();
+        if applied == 0 {
+            writeln!(out, "No applied patches at the start of the series; base unchanged")?;
+        } else {
+            let new_base = patches[applied - 1].commit.id();
+            series.set_base(Some(new_base))?;
+            writeln!(out, "Moved base past {} applied patch{}: {}",
+                     applied, if applied == 1 { "" } else { "es" }, commit_summarize(repo, new_base)?)?;
+        }
+        if patches[applied..].iter().any(|p| p.status.is_applied(include_modified)) {
+            writeln!(
+                out,
+                "Patches applied later in the series remain; use \"git series rebase --drop-applied {}{}\" to drop them.",
+                if include_modified { "--drop-modified " } else { "" },
+                upstream_name,
+            )?;
+        }
+    }
 
     Ok(())
 }
 
+fn versions(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let versions = Series::require_current(repo)?.versions()?;
+    if json_format(m) {
+        return write_json(out, &versions.iter().map(SentVersionRecord::new).collect::<Vec<_>>());
+    }
+    for sent in versions.iter() {
+        writeln!(out, "v{} {} {}", sent.version, commit_summarize(repo, sent.series_commit)?, sent.message_id)?;
+    }
+    Ok(())
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::from_usage("--format=[format] 'Output format: \"text\" (default) or \"json\"'")
+        .possible_values(&["text", "json"])
+}
+
+fn range_diff_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::from_usage("--creation-factor=[percent] 'Cost of showing a commit as added or deleted rather than matching it, as a percentage of its diff (default: 50)'"),
+        Arg::from_usage("--ignore-authors 'Match commits regardless of their authors'"),
+        Arg::from_usage("--ignore-messages 'Match commits regardless of their commit messages'"),
+    ]
+}
+
+fn range_diff_options(m: &ArgMatches) -> Result<RangeDiffOptions> {
+    let mut opts = RangeDiffOptions::default();
+    if let Some(factor) = m.value_of("creation-factor") {
+        opts.creation_factor = factor.parse().map_err(|_| format!("Invalid --creation-factor: {}", factor))?;
+    }
+    opts.ignore_authors = m.is_present("ignore-authors");
+    opts.ignore_messages = m.is_present("ignore-messages");
+    opts.dual_color = m.is_present("dual-color");
+    Ok(opts)
+}
+
 fn main() {
     let m = App::new("git-series")
             .bin_name("git series")
@@ -2165,10 +1744,12 @@ fn main() {
             .global_setting(AppSettings::ColoredHelp)
             .global_setting(AppSettings::UnifiedHelpMessage)
             .global_setting(AppSettings::VersionlessSubcommands)
+            .arg(format_arg())
+            .arg_from_usage("-v, --verbose 'Show the series tip, base, and number of patches of each series'")
             .subcommands(vec![
                 SubCommand::with_name("add")
                     .about("Add changes to the index for the next series commit")
-                    .arg_from_usage("<change>... 'Changes to add (\"series\", \"base\", \"cover\")'"),
+                    .arg_from_usage("<change>... 'Changes to add (\"series\", \"base\", \"config\", \"cover\", \"depends-on\", \"notes\", \"recipients\", \"upstream\", \"versions\")'"),
                 SubCommand::with_name("base")
                     .about("Get or set the base commit for the patch series")
                     .arg(Arg::with_name("base").help("Base commit").conflicts_with("delete"))
@@ -2181,53 +1762,149 @@ fn main() {
                     .arg_from_usage("-a, --all 'Commit all changes'")
                     .arg_from_usage("-m [msg] 'Commit message'")
                     .arg_from_usage("-v, --verbose 'Show diff when preparing commit message'"),
+                SubCommand::with_name("config")
+                    .about("Get or set the settings of the patch series")
+                    .arg(Arg::from_usage("<key> 'Setting, such as format.subjectPrefix'").required_unless("list"))
+                    .arg(Arg::from_usage("[value] 'New value'").conflicts_with_all(&["get-all", "unset"]))
+                    .arg(Arg::from_usage("--add 'Add a value without replacing the existing ones'").requires("value"))
+                    .arg_from_usage("--get-all 'Print all values of the setting'")
+                    .arg(Arg::from_usage("-l, --list 'List all settings'").conflicts_with_all(&["key", "add", "get-all", "unset"]))
+                    .arg_from_usage("--unset 'Remove all values of the setting'"),
                 SubCommand::with_name("cover")
                     .about("Create or edit the cover letter for the patch series")
-                    .arg_from_usage("-d, --delete 'Delete cover letter'"),
+                    .arg_from_usage("-d, --delete 'Delete cover letter'")
+                    .arg(Arg::from_usage("--template=[file] 'Start from the cover letter template in <file>, replacing the current cover letter'").conflicts_with("delete")),
                 SubCommand::with_name("cp")
                     .about("Copy a patch series")
                     .arg(Arg::with_name("source_dest").required(true).min_values(1).max_values(2).help("source (default: current series) and destination (required)")),
                 SubCommand::with_name("delete")
                     .about("Delete a patch series")
                     .arg_from_usage("<name> 'Patch series to delete'"),
+                SubCommand::with_name("depends-on")
+                    .about("Get or set the series and commits the patch series depends on")
+                    .arg(Arg::from_usage("--series [name]... 'Add a series this series builds on'").number_of_values(1))
+                    .arg(Arg::from_usage("--commit [commit]... 'Add a prerequisite commit'").number_of_values(1))
+                    .arg(Arg::from_usage("-d, --delete [dependency]... 'Remove a series or commit'").number_of_values(1)),
                 SubCommand::with_name("detach")
                     .about("Stop working on any patch series"),
                 SubCommand::with_name("diff")
-                    .about("Show changes in the patch series"),
+                    .about("Show changes in the patch series")
+                    .arg_from_usage("[old] 'Old version of the series (vN, @{N}, or a series commit); default: staged'")
+                    .arg_from_usage("[new] 'New version of the series; default: working'")
+                    .args(&range_diff_args())
+                    .arg_from_usage("--dual-color 'Color the changes within each commit as well as the changes between commits'")
+                    .arg(format_arg()),
+                SubCommand::with_name("fetch")
+                    .about("Fetch a patch series from a remote repository")
+                    .arg_from_usage("<remote> 'Remote repository to fetch from'")
+                    .arg_from_usage("[name] 'Patch series to fetch (default: current series)'")
+                    .arg_from_usage("-i, --internals 'Also fetch the staged and working versions of the series'"),
                 SubCommand::with_name("format")
                     .about("Prepare patch series for email")
+                    .arg(Arg::from_usage("--cc [address]... 'Also Cc the specified address'").number_of_values(1))
+                    .arg_from_usage("--cc-cmd [command] 'Cc each patch to the addresses printed by running command on it'")
+                    .arg_from_usage("--changelog 'Add the series commit messages since the previous version sent to the cover letter'")
+                    .arg_from_usage("--cc-trailers 'Cc each patch to the addresses in its Signed-off-by, Reviewed-by, Acked-by, Tested-by, and Cc trailers'")
+                    .arg(Arg::from_usage("--dry-run 'With --send, show what would be sent without sending it'").requires("send"))
+                    .arg(Arg::with_name("interdiff").long("interdiff").value_name("version")
+                         .takes_value(true).min_values(0).require_equals(true)
+                         .help("Add the diff from a previous version (default: the previous version sent) to the cover letter"))
                     .arg_from_usage("--in-reply-to [Message-Id] 'Make the first mail a reply to the specified Message-Id'")
                     .arg_from_usage("--no-from 'Don't include in-body \"From:\" headers when formatting patches authored by others'")
-                    .arg_from_usage("-v, --reroll-count=[N] 'Mark the patch series as PATCH vN'")
+                    .arg(Arg::with_name("range-diff").long("range-diff").value_name("version")
+                         .takes_value(true).min_values(0).require_equals(true)
+                         .help("Add a range diff against a previous version (default: the previous version sent) to the cover letter"))
+                    .args(&range_diff_args())
+                    .arg_from_usage("-v, --reroll-count=[N] 'Mark the patch series as PATCH vN (default: next version after the last one sent)'")
+                    .arg(Arg::from_usage("--record 'Record the version as sent, for mails sent by other means'").conflicts_with("send"))
                     .arg(Arg::from_usage("--rfc 'Use [RFC PATCH] instead of the standard [PATCH] prefix'").conflicts_with("subject-prefix"))
+                    .arg_from_usage("-s, --signoff 'Add a Signed-off-by trailer for yourself to each patch'")
+                    .arg(Arg::from_usage("--send 'Send the patches by email, as configured by sendemail.*, rather than writing files'").conflicts_with("stdout"))
                     .arg_from_usage("--stdout 'Write patches to stdout rather than files'")
-                    .arg_from_usage("--subject-prefix [prefix] 'Use [prefix] instead of the standard [PATCH] prefix'"),
+                    .arg_from_usage("--subject-prefix [prefix] 'Use [prefix] instead of the standard [PATCH] prefix'")
+                    .arg(Arg::from_usage("--to [address]... 'Also send to the specified address'").number_of_values(1)),
+                SubCommand::with_name("import")
+                    .about("Create a patch series from an mbox or a directory of patch files")
+                    .visible_alias("apply")
+                    .arg_from_usage("<name> 'Patch series name'")
+                    .arg_from_usage("<path> 'mbox file, or directory of *.patch files, such as from \"git series format\"'")
+                    .arg_from_usage("--base [base] 'Base commit to apply the patches to (default: from \"base-commit:\")'"),
                 SubCommand::with_name("log")
                     .about("Show the history of the patch series")
-                    .arg_from_usage("-p, --patch 'Include a patch for each change committed to the series'"),
+                    .arg_from_usage("-p, --patch 'Include a patch for each change committed to the series'")
+                    .arg(format_arg()),
+                SubCommand::with_name("merge")
+                    .about("Merge another version of the patch series into the current series")
+                    .arg(Arg::from_usage("[series] 'Series name, or revision of the series commit to merge'").required_unless("abort"))
+                    .arg(Arg::from_usage("-m [msg] 'Merge commit message'").conflicts_with("abort"))
+                    .arg(Arg::from_usage("--abort 'Abort the series merge in progress'").conflicts_with("series")),
                 SubCommand::with_name("mv")
                     .about("Move (rename) a patch series")
                     .visible_alias("rename")
                     .arg(Arg::with_name("source_dest").required(true).min_values(1).max_values(2).help("source (default: current series) and destination (required)")),
+                SubCommand::with_name("note")
+                    .about("Create or edit the note for a patch, shown below the \"---\" line of its mail")
+                    .arg_from_usage("[commit] 'Patch to annotate (default: HEAD)'")
+                    .arg(Arg::from_usage("-d, --delete 'Delete the note'").conflicts_with("m"))
+                    .arg_from_usage("-m [note] 'Note text, rather than running an editor'"),
+                SubCommand::with_name("push")
+                    .about("Push a patch series to a remote repository")
+                    .arg_from_usage("<remote> 'Remote repository to push to'")
+                    .arg_from_usage("[name] 'Patch series to push (default: current series)'")
+                    .arg_from_usage("-f, --force 'Push even if the remote series has diverged'")
+                    .arg_from_usage("-i, --internals 'Also push the staged and working versions of the series'"),
+                SubCommand::with_name("range-diff")
+                    .about("Show the correspondence between the commits of two commit ranges")
+                    .arg_from_usage("<range1> 'Old commit range, as <base>..<tip>'")
+                    .arg_from_usage("<range2> 'New commit range, as <base>..<tip>'")
+                    .args(&range_diff_args())
+                    .arg_from_usage("--dual-color 'Color the changes within each commit as well as the changes between commits'")
+                    .arg(format_arg()),
                 SubCommand::with_name("rebase")
                     .about("Rebase the patch series")
                     .arg_from_usage("[onto] 'Commit to rebase onto'")
                     .arg_from_usage("-i, --interactive 'Interactively edit the list of commits'")
-                    .group(ArgGroup::with_name("action").args(&["onto", "interactive"]).multiple(true).required(true)),
+                    .arg_from_usage("--autosquash 'Move fixup! and squash! commits after the commits they fix up, and mark them to fix up or squash'")
+                    .arg(Arg::from_usage("--drop-applied 'Drop commits that onto already contains, by patch ID'").requires("onto"))
+                    .arg(Arg::from_usage("--drop-modified 'With --drop-applied, also drop commits that onto contains with changes'").requires("drop-applied"))
+                    .arg_from_usage("--continue 'Continue a rebase that stopped'")
+                    .arg_from_usage("--skip 'Skip the commit a rebase stopped at, and continue'")
+                    .arg_from_usage("--abort 'Abandon a rebase that stopped, restoring the series'"),
+                SubCommand::with_name("recipients")
+                    .about("Get or set the To and Cc addresses for the patch series")
+                    .arg(Arg::from_usage("--cc [address]... 'Add a Cc address'").number_of_values(1))
+                    .arg(Arg::from_usage("-d, --delete [address]... 'Remove an address'").number_of_values(1))
+                    .arg(Arg::from_usage("--to [address]... 'Add a To address'").number_of_values(1)),
                 SubCommand::with_name("req")
                     .about("Generate a mail requesting a pull of the patch series")
                     .visible_aliases(&["pull-request", "request-pull"])
                     .arg_from_usage("-p, --patch 'Include patch in the mail'")
-                    .arg_from_usage("<url> 'Repository URL to request pull of'")
-                    .arg_from_usage("<tag> 'Tag or branch name to request pull of'"),
+                    .arg(Arg::with_name("url_tag").required(true).min_values(1).max_values(2)
+                         .help("Repository URL to request pull of (default: req.url of the series), and tag or branch name")),
+                SubCommand::with_name("restack")
+                    .about("Rebase series based on a previous version of this series onto the current version"),
                 SubCommand::with_name("status")
-                    .about("Show the status of the patch series"),
+                    .about("Show the status of the patch series")
+                    .arg(format_arg()),
                 SubCommand::with_name("start")
                     .about("Start a new patch series")
                     .arg_from_usage("<name> 'Patch series name'"),
                 SubCommand::with_name("unadd")
                     .about("Undo \"git series add\", removing changes from the next series commit")
-                    .arg_from_usage("<change>... 'Changes to remove (\"series\", \"base\", \"cover\")'"),
+                    .arg_from_usage("<change>... 'Changes to remove (\"series\", \"base\", \"config\", \"cover\", \"depends-on\", \"notes\", \"recipients\", \"upstream\", \"versions\")'"),
+                SubCommand::with_name("upstream")
+                    .about("Get or set the upstream branch the patch series targets")
+                    .arg(Arg::with_name("upstream").help("Upstream branch, such as origin/main").conflicts_with("delete"))
+                    .arg_from_usage("-d, --delete 'Clear patch series upstream'"),
+                SubCommand::with_name("upstream-status")
+                    .about("Show which patches of the series upstream has applied")
+                    .arg_from_usage("[upstream] 'Upstream branch or commit to look for the patches in, such as origin/main; default: the series upstream'")
+                    .arg_from_usage("--drop-applied 'Move the base past the applied patches at the start of the series'")
+                    .arg(Arg::from_usage("--drop-modified 'With --drop-applied, also move the base past patches applied with changes'").requires("drop-applied"))
+                    .arg(format_arg()),
+                SubCommand::with_name("versions")
+                    .about("List the versions of the patch series sent with \"git series format\"")
+                    .arg(format_arg()),
             ]).get_matches();
 
     let mut out = Output::new();
@@ -2235,24 +1912,37 @@ fn main() {
     let err = || -> Result<()> {
         let repo = Repository::discover(".")?;
         match m.subcommand() {
-            ("", _) => series(&mut out, &repo),
-            ("add", Some(ref sm)) => add(&repo, &sm),
-            ("base", Some(ref sm)) => base(&repo, &sm),
-            ("checkout", Some(ref sm)) => checkout(&repo, &sm),
-            ("commit", Some(ref sm)) => commit_status(&mut out, &repo, &sm, false),
-            ("cover", Some(ref sm)) => cover(&repo, &sm),
-            ("cp", Some(ref sm)) => cp_mv(&repo, &sm, false),
-            ("delete", Some(ref sm)) => delete(&repo, &sm),
+            ("", _) => series(&mut out, &repo, &m),
+            ("add", Some(sm)) => add(&repo, sm),
+            ("base", Some(sm)) => base(&repo, sm),
+            ("checkout", Some(sm)) => checkout(&repo, sm),
+            ("commit", Some(sm)) => commit_status(&mut out, &repo, sm, false),
+            ("config", Some(sm)) => series_config(&mut out, &repo, sm),
+            ("cover", Some(sm)) => cover(&repo, sm),
+            ("cp", Some(sm)) => cp_mv(&repo, sm, false),
+            ("delete", Some(sm)) => delete(&repo, sm),
+            ("depends-on", Some(sm)) => depends_on(&mut out, &repo, sm),
             ("detach", _) => detach(&repo),
-            ("diff", _) => do_diff(&mut out, &repo),
-            ("format", Some(ref sm)) => format(&mut out, &repo, &sm),
-            ("log", Some(ref sm)) => log(&mut out, &repo, &sm),
-            ("mv", Some(ref sm)) => cp_mv(&repo, &sm, true),
-            ("rebase", Some(ref sm)) => rebase(&repo, &sm),
-            ("req", Some(ref sm)) => req(&mut out, &repo, &sm),
-            ("start", Some(ref sm)) => start(&repo, &sm),
-            ("status", Some(ref sm)) => commit_status(&mut out, &repo, &sm, true),
-            ("unadd", Some(ref sm)) => unadd(&repo, &sm),
+            ("diff", Some(sm)) => do_diff(&mut out, &repo, sm),
+            ("fetch", Some(sm)) => fetch(&repo, sm),
+            ("format", Some(sm)) => format(&mut out, &repo, sm),
+            ("import", Some(sm)) => import(&mut out, &repo, sm),
+            ("log", Some(sm)) => log(&mut out, &repo, sm),
+            ("merge", Some(sm)) => merge(&repo, sm),
+            ("mv", Some(sm)) => cp_mv(&repo, sm, true),
+            ("note", Some(sm)) => note(&repo, sm),
+            ("push", Some(sm)) => push(&repo, sm),
+            ("range-diff", Some(sm)) => range_diff(&mut out, &repo, sm),
+            ("rebase", Some(sm)) => rebase(&repo, sm),
+            ("recipients", Some(sm)) => recipients(&mut out, &repo, sm),
+            ("req", Some(sm)) => req(&mut out, &repo, sm),
+            ("restack", Some(_)) => restack(&repo),
+            ("start", Some(sm)) => start(&repo, sm),
+            ("status", Some(sm)) => commit_status(&mut out, &repo, sm, true),
+            ("unadd", Some(sm)) => unadd(&repo, sm),
+            ("upstream", Some(sm)) => upstream(&repo, sm),
+            ("upstream-status", Some(sm)) => do_upstream_status(&mut out, &repo, sm),
+            ("versions", Some(sm)) => versions(&mut out, &repo, sm),
             _ => unreachable!(),
         }
     }();
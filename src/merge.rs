@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+
+use git2::{Oid, Repository};
+
+use crate::error::Result;
+use crate::internals::GIT_FILEMODE_BLOB;
+use crate::series::{history_from, SeriesVersion};
+use crate::versions::{insert_version, parse_versions, write_versions};
+
+/// A git-series tree entry changed incompatibly by both sides of a merge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryConflict {
+    pub entry: String,
+    /// "content" if both sides modified the entry, "add/add" if both added it, or
+    /// "modify/delete" if one side deleted it.
+    pub kind: &'static str,
+}
+
+/// The result of merging another version of a series into the current version.
+pub enum MergeOutcome {
+    /// The other version is already part of the history of the current version.
+    UpToDate,
+    /// The current version is part of the history of the other version.
+    FastForward,
+    /// A git-series tree merging the two versions. Conflicting gitlink entries keep the current
+    /// version; a conflicting cover letter includes both versions between conflict markers.
+    Merged { tree: Oid, conflicts: Vec<EntryConflict> },
+}
+
+/// Find the most recent series commit in the history of both `one` and `two`, following only the
+/// history parents of series commits.
+pub fn series_merge_base(repo: &Repository, one: Oid, two: Oid) -> Result<Option<Oid>> {
+    let history1: HashSet<Oid> = history_from(repo, one)?.iter().map(|v| v.id()).collect();
+    Ok(history_from(repo, two)?.iter().map(|v| v.id()).find(|id| history1.contains(id)))
+}
+
+fn conflict_markers(ours: &[u8], theirs: &[u8]) -> Vec<u8> {
+    let mut v = Vec::new();
+    for (marker, content) in [(&b"<<<<<<< ours\n"[..], ours), (b"=======\n", theirs)].iter() {
+        v.extend_from_slice(marker);
+        v.extend_from_slice(content);
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            v.push(b'\n');
+        }
+    }
+    v.extend_from_slice(b">>>>>>> theirs\n");
+    v
+}
+
+/// Three-way merge the git-series trees of two versions of a series. The `versions` entries
+/// merge by taking the union of their records, and never conflict.
+pub fn merge_series(repo: &Repository, ours: &SeriesVersion, theirs: &SeriesVersion) -> Result<MergeOutcome> {
+    let ancestor = series_merge_base(repo, ours.id(), theirs.id())?;
+    if ancestor == Some(theirs.id()) {
+        return Ok(MergeOutcome::UpToDate);
+    } else if ancestor == Some(ours.id()) {
+        return Ok(MergeOutcome::FastForward);
+    }
+    let ancestor_tree = match ancestor {
+        Some(id) => repo.find_commit(id)?.tree()?,
+        None => repo.find_tree(repo.treebuilder(None)?.write()?)?,
+    };
+
+    let index = repo.merge_trees(&ancestor_tree, ours.tree(), theirs.tree(), None)?;
+    let mut tb = repo.treebuilder(None)?;
+    for entry in index.iter() {
+        // Conflicted entries have a nonzero stage.
+        if (entry.flags >> 12) & 3 == 0 {
+            tb.insert(&entry.path, entry.id, entry.mode as i32)?;
+        }
+    }
+
+    let mut conflicts = Vec::new();
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let path = conflict.our.as_ref()
+            .or(conflict.their.as_ref())
+            .or(conflict.ancestor.as_ref())
+            .map(|e| String::from_utf8_lossy(&e.path).into_owned())
+            .unwrap();
+        let our = conflict.our.map(|e| (e.id, e.mode));
+        let their = conflict.their.map(|e| (e.id, e.mode));
+        let blob = |id| -> Result<Vec<u8>> { Ok(repo.find_blob(id)?.content().to_vec()) };
+        let kind = match (conflict.ancestor.is_some(), our.is_some(), their.is_some()) {
+            (true, true, true) => "content",
+            (false, true, true) => "add/add",
+            _ => "modify/delete",
+        };
+
+        if path == "versions" {
+            let mut versions = match our {
+                Some((id, _)) => parse_versions(std::str::from_utf8(&blob(id)?)?)?,
+                None => Vec::new(),
+            };
+            if let Some((id, _)) = their {
+                for sent in parse_versions(std::str::from_utf8(&blob(id)?)?)? {
+                    if !versions.iter().any(|v| v.version == sent.version) {
+                        insert_version(&mut versions, sent);
+                    }
+                }
+            }
+            let id = repo.blob(write_versions(&versions).as_bytes())?;
+            tb.insert(&path, id, GIT_FILEMODE_BLOB as i32)?;
+            continue;
+        }
+
+        match (our, their) {
+            (Some((our_id, mode)), Some((their_id, _))) if mode == GIT_FILEMODE_BLOB => {
+                let id = repo.blob(&conflict_markers(&blob(our_id)?, &blob(their_id)?))?;
+                tb.insert(&path, id, mode as i32)?;
+            }
+            (Some((id, mode)), _) | (None, Some((id, mode))) => {
+                tb.insert(&path, id, mode as i32)?;
+            }
+            (None, None) => {}
+        }
+        conflicts.push(EntryConflict { entry: path, kind });
+    }
+
+    Ok(MergeOutcome::Merged { tree: tb.write()?, conflicts })
+}
+
+// Commit a first version of the series "topic" with one patch and a cover letter, then two
+// versions based on it: theirs, with the cover `their_cover`, and with `their_patch`, a second
+// patch; and ours, with the cover `our_cover` and a different second patch. Returns the first,
+// our and their series commits, and the `series` of each.
+#[cfg(test)]
+fn diverge(
+    t: &crate::testutil::TestRepo,
+    our_cover: &str,
+    their_cover: &str,
+    their_patch: bool,
+) -> ([Oid; 3], [Oid; 3]) {
+    use crate::series::Series;
+
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let tip = t.commit(&[("f", Some("2\n"))], "One");
+    let series = Series::start(&t.repo, "topic").unwrap();
+    series.set_base(Some(base)).unwrap();
+    series.set_cover(Some("Cover\n")).unwrap();
+    let v1 = series.commit("First version", true).unwrap();
+
+    let their_tip = if their_patch { t.commit(&[("g", Some("3\n"))], "Three") } else { tip };
+    series.set_cover(Some(their_cover)).unwrap();
+    let theirs = series.commit("Their version", true).unwrap();
+
+    t.repo.reference(&series.refname(), v1, true, "reset").unwrap();
+    t.checkout(tip);
+    let our_tip = t.commit(&[("h", Some("4\n"))], "Two");
+    series.set_cover(Some(our_cover)).unwrap();
+    let ours = series.commit("Our version", true).unwrap();
+    ([v1, ours, theirs], [tip, our_tip, their_tip])
+}
+
+#[cfg(test)]
+fn merged_entry(repo: &Repository, tree: Oid, entry: &str) -> Oid {
+    repo.find_tree(tree).unwrap().get_name(entry).unwrap().id()
+}
+
+#[test]
+fn test_merge_clean() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let ([v1, ours, theirs], [_, our_tip, _]) = diverge(&t, "Cover\n", "Better cover\n", false);
+    let version = |id| SeriesVersion::find(repo, id).unwrap();
+
+    assert!(matches!(merge_series(repo, &version(ours), &version(v1)).unwrap(), MergeOutcome::UpToDate));
+    assert!(matches!(merge_series(repo, &version(v1), &version(ours)).unwrap(), MergeOutcome::FastForward));
+    assert_eq!(series_merge_base(repo, ours, theirs).unwrap(), Some(v1));
+
+    let outcome = merge_series(repo, &version(ours), &version(theirs)).unwrap();
+    match outcome {
+        MergeOutcome::Merged { tree, conflicts } => {
+            assert_eq!(conflicts, vec![]);
+            assert_eq!(merged_entry(repo, tree, "series"), our_tip);
+            assert_eq!(repo.find_blob(merged_entry(repo, tree, "cover")).unwrap().content(), b"Better cover\n");
+        }
+        _ => panic!("expected a merge"),
+    }
+}
+
+#[test]
+fn test_merge_conflicts() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let ([_, ours, theirs], [_, our_tip, _]) = diverge(&t, "Our cover\n", "Their cover", true);
+    let version = |id| SeriesVersion::find(repo, id).unwrap();
+
+    let outcome = merge_series(repo, &version(ours), &version(theirs)).unwrap();
+    match outcome {
+        MergeOutcome::Merged { tree, conflicts } => {
+            let content = |entry: &str| EntryConflict { entry: entry.to_string(), kind: "content" };
+            assert_eq!(conflicts, vec![content("cover"), content("series")]);
+            assert_eq!(merged_entry(repo, tree, "series"), our_tip);
+            assert_eq!(
+                std::str::from_utf8(repo.find_blob(merged_entry(repo, tree, "cover")).unwrap().content()).unwrap(),
+                "<<<<<<< ours\nOur cover\n=======\nTheir cover\n>>>>>>> theirs\n",
+            );
+        }
+        _ => panic!("expected a merge"),
+    }
+}
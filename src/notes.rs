@@ -0,0 +1,108 @@
+use std::fmt::Write as FmtWrite;
+
+use git2::Oid;
+
+use crate::error::Result;
+
+/// A note attached to one patch of a series, such as a per-patch changelog. The `notes` entry of
+/// the git-series tree stores one record per note: a line with the patch ID and the subject of the
+/// patch, separated by a space, followed by the lines of the note, each indented with a tab.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatchNote {
+    pub patch_id: Oid,
+    pub subject: String,
+    pub text: String,
+}
+
+pub fn parse_notes(content: &str) -> Result<Vec<PatchNote>> {
+    let mut notes: Vec<PatchNote> = Vec::new();
+    for line in content.lines() {
+        if let Some(text) = line.strip_prefix('\t') {
+            let note = notes.last_mut().ok_or("Malformed \"notes\": note text before any patch")?;
+            note.text.push_str(text);
+            note.text.push('\n');
+        } else if !line.is_empty() {
+            let (id, subject) = match line.find(' ') {
+                Some(pos) => (&line[..pos], &line[pos + 1..]),
+                None => (line, ""),
+            };
+            notes.push(PatchNote {
+                patch_id: Oid::from_str(id).map_err(|_| format!("Malformed line in \"notes\": {}", line))?,
+                subject: subject.to_string(),
+                text: String::new(),
+            });
+        }
+    }
+    Ok(notes)
+}
+
+pub fn write_notes(notes: &[PatchNote]) -> String {
+    let mut s = String::new();
+    for note in notes {
+        writeln!(s, "{} {}", note.patch_id, note.subject).unwrap();
+        for line in note.text.lines() {
+            writeln!(s, "\t{}", line).unwrap();
+        }
+    }
+    s
+}
+
+/// Find the note for a patch, given the patch ID and subject of each patch of the series in
+/// `patches`: the note with its patch ID, or if none, the note with its subject, so a note
+/// survives both rebasing its patch and changing it. A subject only identifies a note if no other
+/// note or patch of the series has that subject, and no patch of the series has the patch ID of
+/// the note; otherwise, the note could belong to another patch.
+pub fn find_note<'a>(
+    notes: &'a [PatchNote],
+    patches: &[(Oid, String)],
+    patch_id: Oid,
+    subject: &str,
+) -> Option<&'a PatchNote> {
+    if let Some(note) = notes.iter().find(|n| n.patch_id == patch_id) {
+        return Some(note);
+    }
+    let mut matches = notes.iter().filter(|n| n.subject == subject);
+    let note = matches.next()?;
+    if matches.next().is_some()
+        || patches.iter().any(|(id, s)| *id != patch_id && s == subject)
+        || patches.iter().any(|(id, _)| *id == note.patch_id) {
+        return None;
+    }
+    Some(note)
+}
+
+#[test]
+fn test_notes() {
+    let id1 = Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+    let id2 = Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+    let id3 = Oid::from_str("3333333333333333333333333333333333333333").unwrap();
+    let id4 = Oid::from_str("4444444444444444444444444444444444444444").unwrap();
+    let content = concat!(
+        "1111111111111111111111111111111111111111 Fix a bug\n",
+        "\tv2: fixed typo\n",
+        "\t\n",
+        "\tv3: rebased\n",
+        "2222222222222222222222222222222222222222 Add a feature\n",
+        "\tv2: new\n",
+    );
+    let notes = parse_notes(content).unwrap();
+    assert_eq!(notes.len(), 2);
+    assert_eq!(notes[0].text, "v2: fixed typo\n\nv3: rebased\n");
+    assert_eq!(write_notes(&notes), content);
+    let patches = |p: &[(Oid, &str)]| p.iter().map(|&(id, s)| (id, s.to_string())).collect::<Vec<_>>();
+    let find = |patches: &[(Oid, String)], id, subject| find_note(&notes, patches, id, subject).map(|n| n.patch_id);
+    assert_eq!(find(&[], id2, "Fix a bug"), Some(id2));
+    assert_eq!(find(&[], id3, "Fix a bug"), Some(id1));
+    assert_eq!(find(&patches(&[(id3, "Fix a bug"), (id2, "Add a feature")]), id3, "Fix a bug"), Some(id1));
+    assert_eq!(find(&[], id3, "Other"), None);
+    // The patch with the note's patch ID keeps the note, even if its subject changed.
+    assert_eq!(find(&patches(&[(id1, "Fix the bug"), (id3, "Fix a bug")]), id3, "Fix a bug"), None);
+    // Another patch with the same subject makes the subject ambiguous.
+    assert_eq!(find(&patches(&[(id3, "Fix a bug"), (id4, "Fix a bug")]), id3, "Fix a bug"), None);
+    // So does another note with the same subject.
+    let mut notes = notes.clone();
+    notes.push(PatchNote { patch_id: id4, subject: "Fix a bug".to_string(), text: "v2: other\n".to_string() });
+    assert_eq!(find_note(&notes, &[], id3, "Fix a bug"), None);
+    assert_eq!(find_note(&notes, &[], id4, "Fix a bug").map(|n| n.patch_id), Some(id4));
+    assert!(parse_notes("\tno patch\n").is_err());
+}
@@ -0,0 +1,148 @@
+use std::env;
+use std::ffi::OsString;
+use std::io::Write as IoWrite;
+
+use ansi_term::Style;
+use git2::Config;
+
+use crate::error::Result;
+use crate::util::{cmd_maybe_shell, notfound_to_none};
+
+// Get the pager to use; with for_cmd set, get the pager for use by the
+// specified git command.  If get_pager returns None, don't use a pager.
+fn get_pager(config: &Config, for_cmd: &str, default: bool) -> Option<OsString> {
+    if !atty::is(atty::Stream::Stdout) {
+        return None;
+    }
+    // pager.cmd can contain a boolean (if false, force no pager) or a
+    // command-specific pager; only treat it as a command if it doesn't parse
+    // as a boolean.
+    let maybe_pager = config.get_path(&format!("pager.{}", for_cmd)).ok();
+    let (cmd_want_pager, cmd_pager) = maybe_pager.map_or((default, None), |p|
+            if let Ok(b) = Config::parse_bool(&p) {
+                (b, None)
+            } else {
+                (true, Some(p))
+            }
+        );
+    if !cmd_want_pager {
+        return None;
+    }
+    let pager = if let Some(e) = env::var_os("GIT_PAGER") {
+        Some(e)
+    } else if let Some(p) = cmd_pager {
+        Some(p.into())
+    } else if let Ok(e) = config.get_path("core.pager") {
+        Some(e.into())
+    } else if let Some(e) = env::var_os("PAGER") {
+        Some(e)
+    } else {
+        Some("less".into())
+    };
+    pager.and_then(|p| if p.is_empty() || p == "cat" { None } else { Some(p) })
+}
+
+pub struct Output {
+    pager: Option<std::process::Child>,
+    include_stderr: bool,
+}
+
+impl Output {
+    pub fn new() -> Self {
+        Output { pager: None, include_stderr: false }
+    }
+
+    pub fn auto_pager(&mut self, config: &Config, for_cmd: &str, default: bool) -> Result<()> {
+        if let Some(pager) = get_pager(config, for_cmd, default) {
+            let mut cmd = cmd_maybe_shell(pager, false);
+            cmd.stdin(std::process::Stdio::piped());
+            if env::var_os("LESS").is_none() {
+                cmd.env("LESS", "FRX");
+            }
+            if env::var_os("LV").is_none() {
+                cmd.env("LV", "-c");
+            }
+            let child = cmd.spawn()?;
+            self.pager = Some(child);
+            self.include_stderr = atty::is(atty::Stream::Stderr);
+        }
+        Ok(())
+    }
+
+    // Get a color to write text with, taking git configuration into account.
+    //
+    // config: the configuration to determine the color from.
+    // command: the git command to act like.
+    // slot: the color "slot" of that git command to act like.
+    // default: the color to use if not configured.
+    pub fn get_color(
+        &self,
+        config: &Config,
+        command: &str,
+        slot: &str,
+        default: &str,
+    ) -> Result<Style> {
+        if !cfg!(unix) {
+            return Ok(Style::new());
+        }
+        let color_ui = notfound_to_none(config.get_str("color.ui"))?.unwrap_or("auto");
+        let color_cmd = notfound_to_none(config.get_str(&format!("color.{}", command)))?.unwrap_or(color_ui);
+        if color_cmd == "never" || Config::parse_bool(color_cmd) == Ok(false) {
+            return Ok(Style::new());
+        }
+        if self.pager.is_some() {
+            let color_pager = notfound_to_none(config.get_bool("color.pager"))?.unwrap_or(true);
+            if !color_pager {
+                return Ok(Style::new());
+            }
+        } else if !atty::is(atty::Stream::Stdout) {
+            return Ok(Style::new());
+        }
+        let cfg = format!("color.{}.{}", command, slot);
+        let color = notfound_to_none(config.get_str(&cfg))?.unwrap_or(default);
+        colorparse::parse(color).map_err(|e| format!("Error parsing {}: {}", cfg, e).into())
+    }
+
+    pub fn write_err(&mut self, msg: &str) {
+        if self.include_stderr {
+            if write!(self, "{}", msg).is_err() {
+                eprint!("{}", msg);
+            }
+        } else {
+            eprint!("{}", msg);
+        }
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::new()
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Some(ref mut child) = self.pager {
+            let status = child.wait().unwrap();
+            if !status.success() {
+                eprintln!("Pager exited with status {}", status);
+            }
+        }
+    }
+}
+
+impl IoWrite for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.pager {
+            Some(ref mut child) => child.stdin.as_mut().unwrap().write(buf),
+            None => std::io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.pager {
+            Some(ref mut child) => child.stdin.as_mut().unwrap().flush(),
+            None => std::io::stdout().flush(),
+        }
+    }
+}
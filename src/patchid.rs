@@ -0,0 +1,154 @@
+use std::path::Path;
+
+use git2::{Commit, Delta, Oid, Patch, Repository, Tree};
+use sha1_smol::Sha1;
+
+use crate::error::Result;
+
+fn remove_space(data: &[u8]) -> Vec<u8> {
+    data.iter().copied().filter(|c| !c.is_ascii_whitespace()).collect()
+}
+
+// Add a file's hash into the patch ID: a 20-byte sum, with carry.
+fn flush_one_file(result: &mut [u8; 20], ctx: &mut Sha1) {
+    let hash = ctx.digest().bytes();
+    ctx.reset();
+    let mut carry = 0u16;
+    for i in 0..20 {
+        carry += result[i] as u16 + hash[i] as u16;
+        result[i] = carry as u8;
+        carry >>= 8;
+    }
+}
+
+fn mode_at(tree: &Tree, path: Option<&Path>) -> Result<u32> {
+    Ok(match path {
+        Some(path) => tree.get_path(path).map(|e| e.filemode() as u32).unwrap_or(0),
+        None => 0,
+    })
+}
+
+/// Compute the stable patch ID of the change from `old` to `new`, as `git format-patch --base`
+/// does for its "prerequisite-patch-id:" lines, and as `git patch-id --stable` does for text
+/// changes. Patch IDs ignore whitespace and line numbers, so a patch keeps its ID when rebased
+/// onto a different base without conflicts. With `renames`, a renamed file counts as a rename,
+/// as `git format-patch` does with `diff.renames` set; otherwise, it counts as a deletion and an
+/// addition.
+pub fn tree_patch_id(repo: &Repository, old: &Tree, new: &Tree, renames: bool) -> Result<Oid> {
+    let mut diff = repo.diff_tree_to_tree(Some(old), Some(new), None)?;
+    if renames {
+        diff.find_similar(None)?;
+    }
+    let mut result = [0u8; 20];
+    let mut ctx = Sha1::new();
+    for (idx, delta) in diff.deltas().enumerate() {
+        let (old_file, new_file) = (delta.old_file(), delta.new_file());
+        let old_path = old_file.path().or_else(|| new_file.path());
+        let new_path = new_file.path().or_else(|| old_file.path());
+        let path1 = remove_space(old_path.map(|p| p.to_string_lossy().into_owned()).unwrap_or_default().as_bytes());
+        let path2 = remove_space(new_path.map(|p| p.to_string_lossy().into_owned()).unwrap_or_default().as_bytes());
+        let mode1 = if delta.status() == Delta::Added { 0 } else { mode_at(old, old_path)? };
+        let mode2 = if delta.status() == Delta::Deleted { 0 } else { mode_at(new, new_path)? };
+
+        ctx.update(b"diff--git");
+        ctx.update(b"a/");
+        ctx.update(&path1);
+        ctx.update(b"b/");
+        ctx.update(&path2);
+        if mode1 == 0 {
+            ctx.update(b"newfilemode");
+            ctx.update(format!("{:06o}", mode2).as_bytes());
+        } else if mode2 == 0 {
+            ctx.update(b"deletedfilemode");
+            ctx.update(format!("{:06o}", mode1).as_bytes());
+        } else if mode1 != mode2 {
+            ctx.update(b"oldmode");
+            ctx.update(format!("{:06o}", mode1).as_bytes());
+            ctx.update(b"newmode");
+            ctx.update(format!("{:06o}", mode2).as_bytes());
+        }
+
+        let is_binary = |id: Oid| -> Result<bool> {
+            Ok(!id.is_zero() && repo.find_blob(id).map(|b| b.is_binary()).unwrap_or(false))
+        };
+        if is_binary(old_file.id())? || is_binary(new_file.id())? {
+            // A binary file contributes its blob IDs rather than its content.
+            ctx.update(old_file.id().to_string().as_bytes());
+            ctx.update(new_file.id().to_string().as_bytes());
+            flush_one_file(&mut result, &mut ctx);
+            continue;
+        }
+
+        if mode1 == 0 {
+            ctx.update(b"---/dev/null");
+        } else {
+            ctx.update(b"---a/");
+            ctx.update(&path1);
+        }
+        if mode2 == 0 {
+            ctx.update(b"+++/dev/null");
+        } else {
+            ctx.update(b"+++b/");
+            ctx.update(&path2);
+        }
+        if let Some(patch) = Patch::from_diff(&diff, idx)? {
+            for hunk in 0..patch.num_hunks() {
+                for line in 0..patch.num_lines_in_hunk(hunk)? {
+                    let line = patch.line_in_hunk(hunk, line)?;
+                    let origin = line.origin();
+                    if origin == ' ' || origin == '+' || origin == '-' {
+                        let mut text = vec![origin as u8];
+                        text.extend_from_slice(line.content());
+                        ctx.update(&remove_space(&text));
+                    }
+                }
+            }
+        }
+        flush_one_file(&mut result, &mut ctx);
+    }
+    Ok(Oid::from_bytes(&result)?)
+}
+
+/// Compute the stable patch ID of a commit, relative to its first parent, without detecting
+/// renames, as `git cherry` does.
+pub fn patch_id(repo: &Repository, commit: &Commit) -> Result<Oid> {
+    tree_patch_id(repo, &commit.parent(0)?.tree()?, &commit.tree()?, false)
+}
+
+// The expected IDs come from the "prerequisite-patch-id:" lines of "git format-patch --base"
+// for the same commits, with diff.renames false and true. For the text changes, the former
+// also match "git diff-tree -p --no-renames <commit> | git patch-id --stable".
+#[test]
+fn test_patch_id() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let t = crate::testutil::TestRepo::new();
+    let a: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+    t.commit(&[("a", Some(&a)), ("b", Some("b\n")), ("script", Some("#!/bin/sh\n"))], "Base");
+    let a = a.replace("line 2\n", "line two\n").replace("line 19\n", "line nineteen\n");
+    let mut commits = vec![("Multiple files and hunks", t.commit(&[("a", Some(&a)), ("b", Some("b\nb2\n"))], "1"))];
+    commits.push(("Rename", t.commit(&[("b", None), ("c", Some("b\nb2\n"))], "2")));
+    commits.push(("Rename with changes", t.commit(&[("c", None), ("d", Some("b\nb2\nb3\n"))], "3")));
+    commits.push(("Add binary", t.commit(&[("bin", Some("\0\u{1}\u{2}binary\n"))], "4")));
+    commits.push(("Change binary", t.commit(&[("bin", Some("\0\u{1}\u{3}binary\n"))], "5")));
+    fs::set_permissions(t.path().join("script"), fs::Permissions::from_mode(0o755)).unwrap();
+    commits.push(("Mode change", t.commit(&[("script", Some("#!/bin/sh\n"))], "6")));
+    commits.push(("Delete", t.commit(&[("script", None)], "7")));
+
+    let expected = [
+        ("04fe21d42d2da76b45207aeed4eee22919177919", "04fe21d42d2da76b45207aeed4eee22919177919"),
+        ("1d53cf2c67aa6ddc321630861e8b7577a6320c61", "721eef913c7f2068b12dbddfee2143c3bc294fa2"),
+        ("7e488eacbc30af397995dd44d506500823a175f8", "3064c7e72d25dab037614cdeeb7eccf7d60121a6"),
+        ("2db93897a49d3084e17f793079a13d795b38d70f", "2db93897a49d3084e17f793079a13d795b38d70f"),
+        ("a224674c30df59f0796ae460b253352c60f42fe5", "a224674c30df59f0796ae460b253352c60f42fe5"),
+        ("06d723f979d0ab09574af758f82abaa6af0447a9", "06d723f979d0ab09574af758f82abaa6af0447a9"),
+        ("9b061c3f34a32370942ed11c7772007b193b68ba", "9b061c3f34a32370942ed11c7772007b193b68ba"),
+    ];
+    for (&(name, id), &(plain, renames)) in commits.iter().zip(expected.iter()) {
+        let commit = t.repo.find_commit(id).unwrap();
+        let (old, new) = (commit.parent(0).unwrap().tree().unwrap(), commit.tree().unwrap());
+        assert_eq!(patch_id(&t.repo, &commit).unwrap().to_string(), plain, "{}", name);
+        assert_eq!(tree_patch_id(&t.repo, &old, &new, true).unwrap().to_string(), renames, "{} with renames", name);
+    }
+}
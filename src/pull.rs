@@ -0,0 +1,198 @@
+use std::io::Write as IoWrite;
+
+use git2::{Commit, Diff, Oid, Repository};
+
+use crate::diff::{diffstat, write_diff, DiffColors};
+use crate::error::Result;
+use crate::format::{mail_signature, shortlog};
+use crate::series::SeriesVersion;
+use crate::util::{date_822, get_signature, split_message};
+
+/// A mail requesting a pull of a patch series from a remote repository.
+pub struct PullRequest<'repo> {
+    version_id: Oid,
+    url: String,
+    remote_pull_name: String,
+    subject: String,
+    msg: Option<String>,
+    extra_body: Option<String>,
+    base_commit: Commit<'repo>,
+    series_commit: Commit<'repo>,
+    commits: Vec<Commit<'repo>>,
+    diff: Diff<'repo>,
+}
+
+impl<'repo> PullRequest<'repo> {
+    /// Prepare a pull request for `version`, checking that the remote repository at `url` has a
+    /// tag or branch named `tag` referring to the series.
+    pub fn new(
+        repo: &'repo Repository,
+        series_name: &str,
+        version: &SeriesVersion<'repo>,
+        url: &str,
+        tag: &str,
+    ) -> Result<Self> {
+        let stree = version.tree();
+
+        let series = stree.get_name("series")
+            .ok_or("Internal error: series did not contain \"series\"")?;
+        let series_id = series.id();
+        let series_commit = repo.find_commit(series_id)?;
+        let base = stree.get_name("base")
+            .ok_or("Cannot request pull; no base set.\nUse \"git series base\" to set base.")?;
+        let base_commit = repo.find_commit(base.id())?;
+
+        let (cover_content, subject, cover_body) = match version.cover(repo)? {
+            Some(content) => {
+                let (subject, body) = split_message(&content);
+                (Some(content.to_string()), subject.to_string(), Some(body.to_string()))
+            }
+            None => (None, series_name.to_string(), None),
+        };
+
+        let full_tag = format!("refs/tags/{}", tag);
+        let full_tag_peeled = format!("{}^{{}}", full_tag);
+        let full_head = format!("refs/heads/{}", tag);
+        let mut remote = repo.remote_anonymous(url)?;
+        remote.connect(git2::Direction::Fetch)
+            .map_err(|e| format!("Could not connect to remote repository {}\n{}", url, e))?;
+        let remote_heads = remote.list()?;
+
+        /* Find the requested name as either a tag or head */
+        let mut opt_remote_tag = None;
+        let mut opt_remote_tag_peeled = None;
+        let mut opt_remote_head = None;
+        for h in remote_heads {
+            if h.name() == full_tag {
+                opt_remote_tag = Some(h.oid());
+            } else if h.name() == full_tag_peeled {
+                opt_remote_tag_peeled = Some(h.oid());
+            } else if h.name() == full_head {
+                opt_remote_head = Some(h.oid());
+            }
+        }
+        let (msg, extra_body, remote_pull_name) = match (opt_remote_tag, opt_remote_tag_peeled, opt_remote_head) {
+            (Some(remote_tag), Some(remote_tag_peeled), _) => {
+                if remote_tag_peeled != series_id {
+                    return Err(format!(
+                        "Remote tag {} does not refer to series {}",
+                        tag, series_id,
+                    ).into());
+                }
+                let local_tag = repo.find_tag(remote_tag)
+                    .map_err(|e| format!(
+                        "Could not find remote tag {} ({}) in local repository: {}",
+                        tag, remote_tag, e,
+                    ))?;
+                let mut local_tag_msg = local_tag.message().unwrap().to_string();
+                if let Some(sig_index) = local_tag_msg.find("-----BEGIN PGP ") {
+                    local_tag_msg.truncate(sig_index);
+                }
+                let extra_body = match cover_content {
+                    Some(ref content) if !local_tag_msg.contains(content) => cover_body,
+                    _ => None,
+                };
+                (Some(local_tag_msg), extra_body, full_tag)
+            }
+            (Some(remote_tag), None, _) => {
+                if remote_tag != series_id {
+                    return Err(format!(
+                        "Remote unannotated tag {} does not refer to series {}",
+                        tag, series_id,
+                    ).into());
+                }
+                (cover_content, None, full_tag)
+            }
+            (_, _, Some(remote_head)) => {
+                if remote_head != series_id {
+                    return Err(format!(
+                        "Remote branch {} does not refer to series {}",
+                        tag, series_id,
+                    ).into());
+                }
+                (cover_content, None, full_head)
+            }
+            _ => {
+                return Err(format!("Remote does not have either a tag or branch named {}", tag).into())
+            }
+        };
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE);
+        revwalk.push(series_id)?;
+        revwalk.hide(base.id())?;
+        let commits: Vec<Commit> = revwalk
+            .map(|c| Ok(repo.find_commit(c?)?))
+            .collect::<Result<_>>()?;
+        if commits.is_empty() {
+            return Err("No patches to request pull of; series and base identical.".into());
+        }
+
+        let diff = repo.diff_tree_to_tree(
+            Some(&base_commit.tree().unwrap()),
+            Some(&series_commit.tree().unwrap()),
+            None,
+        )?;
+
+        Ok(PullRequest {
+            version_id: version.id(),
+            url: url.to_string(),
+            remote_pull_name,
+            subject,
+            msg,
+            extra_body,
+            base_commit,
+            series_commit,
+            commits,
+            diff,
+        })
+    }
+
+    /// Write the pull request mail, optionally including the combined patch of the series.
+    pub fn write<W: IoWrite>(&mut self, out: &mut W, repo: &Repository, colors: &DiffColors, patch: bool) -> Result<()> {
+        let config = repo.config()?.snapshot()?;
+        let author = get_signature(&config, "AUTHOR")?;
+        let author_email = author.email().unwrap();
+        let message_id = format!(
+            "<pull.{}.{}.git-series.{}>",
+            self.version_id,
+            author.when().seconds(),
+            author_email
+        );
+        let stats = diffstat(&self.diff)?;
+
+        let commit_subject_date = |commit: &Commit| -> String {
+            let date = date_822(commit.author().when());
+            let summary = commit.summary().unwrap();
+            format!("  {} ({})", summary, date)
+        };
+
+        writeln!(out, "From {} Mon Sep 17 00:00:00 2001", self.version_id)?;
+        writeln!(out, "Message-Id: {}", message_id)?;
+        writeln!(out, "From: {} <{}>", author.name().unwrap(), author_email)?;
+        writeln!(out, "Date: {}", date_822(author.when()))?;
+        writeln!(out, "Subject: [GIT PULL] {}\n", self.subject)?;
+        if let Some(ref extra_body) = self.extra_body {
+            writeln!(out, "{}", extra_body)?;
+        }
+        writeln!(out, "The following changes since commit {}:\n", self.base_commit.id())?;
+        writeln!(out, "{}\n", commit_subject_date(&self.base_commit))?;
+        writeln!(out, "are available in the git repository at:\n")?;
+        writeln!(out, "  {} {}\n", self.url, self.remote_pull_name)?;
+        writeln!(out, "for you to fetch changes up to {}:\n", self.series_commit.id())?;
+        writeln!(out, "{}\n", commit_subject_date(&self.series_commit))?;
+        writeln!(out, "----------------------------------------------------------------")?;
+        if let Some(ref msg) = self.msg {
+            writeln!(out, "{}", msg)?;
+            writeln!(out, "----------------------------------------------------------------")?;
+        }
+        writeln!(out, "{}", shortlog(&mut self.commits))?;
+        writeln!(out, "{}", stats)?;
+        if patch {
+            write_diff(out, colors, &self.diff, false)?;
+        }
+        writeln!(out, "{}", mail_signature())?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,616 @@
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::PathBuf;
+
+use git2::{Commit, ObjectType, Oid, Repository, ResetType};
+
+use crate::error::Result;
+use crate::internals::{Internals, GIT_FILEMODE_COMMIT};
+use crate::util::{cmd_maybe_shell, commit_summarize, get_signature, run_editor};
+
+/// The directory within the git directory that holds the state of a series rebase in progress.
+const REBASE_DIR: &str = "series-rebase";
+
+/// One item in the todo list of a series rebase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TodoItem {
+    /// The rebase command, such as "pick" or "drop".
+    pub action: &'static str,
+    /// The commit to apply; zero for "exec".
+    pub id: Oid,
+    /// The summary of the commit, or for "exec", the command to run.
+    pub summary: String,
+}
+
+impl TodoItem {
+    pub fn new(action: &'static str, id: Oid, summary: &str) -> Self {
+        TodoItem { action, id, summary: summary.to_string() }
+    }
+
+    /// An item running `command` with the shell.
+    pub fn exec(command: &str) -> Self {
+        TodoItem { action: "exec", id: Oid::zero(), summary: command.to_string() }
+    }
+}
+
+fn action_name(word: &str) -> Option<&'static str> {
+    Some(match word {
+        "p" | "pick" => "pick",
+        "r" | "reword" => "reword",
+        "e" | "edit" => "edit",
+        "s" | "squash" => "squash",
+        "f" | "fixup" => "fixup",
+        "x" | "exec" => "exec",
+        "d" | "drop" => "drop",
+        _ => return None,
+    })
+}
+
+/// Write a todo list in the format of `git rebase -i`, one item per line.
+pub fn write_todo(repo: &Repository, todo: &[TodoItem]) -> Result<String> {
+    let mut s = String::new();
+    for item in todo {
+        if item.action == "exec" {
+            writeln!(s, "exec {}", item.summary).unwrap();
+        } else {
+            writeln!(s, "{} {}", item.action, commit_summarize(repo, item.id)?).unwrap();
+        }
+    }
+    Ok(s)
+}
+
+// Write a todo list for the rebase state, with full commit IDs, which stay unambiguous as the
+// rebase creates commits.
+fn write_todo_state(todo: &[TodoItem]) -> String {
+    let mut s = String::new();
+    for item in todo {
+        if item.action == "exec" {
+            writeln!(s, "exec {}", item.summary).unwrap();
+        } else {
+            writeln!(s, "{} {} {}", item.action, item.id, item.summary).unwrap();
+        }
+    }
+    s
+}
+
+/// Parse a todo list in the format of `git rebase -i`, ignoring blank lines and comments.
+pub fn parse_todo(repo: &Repository, text: &str) -> Result<Vec<TodoItem>> {
+    let mut todo = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let (word, rest) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim_start()),
+            None => (line, ""),
+        };
+        let action = action_name(word).ok_or_else(|| format!("Unknown command in rebase todo list: {}", line))?;
+        if action == "exec" {
+            if rest.is_empty() {
+                return Err(format!("Missing command in rebase todo list: {}", line).into());
+            }
+            todo.push(TodoItem::exec(rest));
+            continue;
+        }
+        let rev = rest.split_whitespace().next()
+            .ok_or_else(|| format!("Missing commit in rebase todo list: {}", line))?;
+        let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+        todo.push(TodoItem::new(action, commit.id(), commit.summary().unwrap_or("")));
+    }
+    Ok(todo)
+}
+
+// Split a "fixup! " or "squash! " prefix from a summary, returning the action and the summary or
+// commit it refers to. Nested prefixes, as from fixing up a fixup, all refer to the same target.
+fn squash_target(summary: &str) -> Option<(&'static str, &str)> {
+    let (action, mut target) = if let Some(target) = summary.strip_prefix("fixup! ") {
+        ("fixup", target)
+    } else if let Some(target) = summary.strip_prefix("squash! ") {
+        ("squash", target)
+    } else {
+        return None;
+    };
+    while let Some(rest) = target.strip_prefix("fixup! ").or_else(|| target.strip_prefix("squash! ")) {
+        target = rest;
+    }
+    Some((action, target))
+}
+
+/// Reorder a todo list as `git rebase --autosquash` does: move each "fixup! " or "squash! " commit
+/// after the earlier commit it names, by summary, commit ID prefix, or summary prefix, and mark it
+/// "fixup" or "squash". Commits naming no earlier commit stay where they are.
+pub fn autosquash(items: Vec<TodoItem>) -> Vec<TodoItem> {
+    // For each item, the items to move after it.
+    let mut followers: Vec<Vec<(usize, &'static str)>> = vec![Vec::new(); items.len()];
+    let mut moved = vec![false; items.len()];
+    for (i, item) in items.iter().enumerate() {
+        let (action, target) = match squash_target(&item.summary) {
+            Some(t) => t,
+            None => continue,
+        };
+        let earlier = || items[..i].iter().enumerate().filter(|&(j, _)| !moved[j]);
+        let is_id = target.len() >= 4 && target.chars().all(|c| c.is_ascii_hexdigit());
+        let found = earlier().find(|(_, c)| c.summary == target)
+            .or_else(|| earlier().find(|(_, c)| is_id && c.id.to_string().starts_with(target)))
+            .or_else(|| earlier().find(|(_, c)| c.summary.starts_with(target)));
+        if let Some((j, _)) = found {
+            followers[j].push((i, action));
+            moved[i] = true;
+        }
+    }
+
+    let mut result = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        if moved[i] {
+            continue;
+        }
+        result.push(item.clone());
+        for &(f, action) in followers[i].iter() {
+            result.push(TodoItem { action, ..items[f].clone() });
+        }
+    }
+    result
+}
+
+/// Why a series rebase stopped before finishing its todo list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RebaseStop {
+    /// Applying this commit conflicted.
+    Conflict(Oid),
+    /// The todo list said to stop at this commit, applied as the commit `new`, to amend it.
+    Edit { old: Oid, new: Oid },
+    /// This exec command failed.
+    ExecFailed(String),
+}
+
+/// The result of applying one item of the todo list of a series rebase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RebaseStep {
+    /// Applied the commit `old` as the commit `new`.
+    Applied { old: Oid, new: Oid },
+    /// Dropped a commit, as the todo list said.
+    Dropped(Oid),
+    /// Dropped a commit that changed nothing on top of the commits before it.
+    Empty(Oid),
+    /// Ran an exec command successfully.
+    Exec(String),
+    Stopped(RebaseStop),
+}
+
+/// A series rebase in progress. This cherry-picks each commit of the todo list onto HEAD, keeping
+/// its state in the git directory so that it can stop, such as on a conflict, and resume.
+pub struct Rebase<'repo> {
+    repo: &'repo Repository,
+    dir: PathBuf,
+    /// The name of the series being rebased.
+    pub series: String,
+    pub onto: Oid,
+    /// The series commit before the rebase.
+    pub orig_head: Oid,
+}
+
+impl<'repo> Rebase<'repo> {
+    /// Record the state for rebasing the series named `series` from `orig_head` onto `onto`,
+    /// applying the todo list `todo`. The caller first checks out `onto` as HEAD, and then calls
+    /// `step`.
+    pub fn start(
+        repo: &'repo Repository,
+        series: &str,
+        onto: Oid,
+        orig_head: Oid,
+        todo: &[TodoItem],
+    ) -> Result<Self> {
+        let dir = repo.path().join(REBASE_DIR);
+        if dir.exists() {
+            return Err("A series rebase is already in progress.".into());
+        }
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("series"), format!("{}\n", series))?;
+        fs::write(dir.join("onto"), format!("{}\n", onto))?;
+        fs::write(dir.join("orig-head"), format!("{}\n", orig_head))?;
+        fs::write(dir.join("todo"), write_todo_state(todo))?;
+        Ok(Rebase { repo, dir, series: series.to_string(), onto, orig_head })
+    }
+
+    /// The series rebase in progress, if any.
+    pub fn in_progress(repo: &'repo Repository) -> Result<Option<Self>> {
+        let dir = repo.path().join(REBASE_DIR);
+        if !dir.exists() {
+            return Ok(None);
+        }
+        let read = |name: &str| -> Result<String> { Ok(fs::read_to_string(dir.join(name))?.trim().to_string()) };
+        let series = read("series")?;
+        let onto = Oid::from_str(&read("onto")?)?;
+        let orig_head = Oid::from_str(&read("orig-head")?)?;
+        Ok(Some(Rebase { repo, dir, series, onto, orig_head }))
+    }
+
+    /// The remaining todo list.
+    pub fn todo(&self) -> Result<Vec<TodoItem>> {
+        parse_todo(self.repo, &fs::read_to_string(self.dir.join("todo"))?)
+    }
+
+    fn set_todo(&self, todo: &[TodoItem]) -> Result<()> {
+        fs::write(self.dir.join("todo"), write_todo_state(todo))?;
+        Ok(())
+    }
+
+    /// Apply the next item of the todo list, or return None if none remain. If the item fails to
+    /// apply, it stays first in the todo list: `resume` then retries it, and `skip` drops it.
+    pub fn step(&self) -> Result<Option<RebaseStep>> {
+        if self.dir.join("stopped").exists() {
+            return Err("The rebase stopped at a commit; continue, skip it, or abort.".into());
+        }
+        let mut todo = self.todo()?;
+        if todo.is_empty() {
+            return Ok(None);
+        }
+        let failed = self.dir.join("failed");
+        if failed.exists() {
+            fs::remove_file(&failed)?;
+        }
+        let item = todo.remove(0);
+        let result = self.apply(&item, &todo);
+        // Once a commit has applied, --continue commits it; before that, it retries the item.
+        if result.is_err() && !self.dir.join("stopped").exists() {
+            fs::write(failed, "")?;
+        }
+        result.map(Some)
+    }
+
+    // Apply `item`, removing it from the todo list, leaving `rest`, once it has taken effect.
+    fn apply(&self, item: &TodoItem, rest: &[TodoItem]) -> Result<RebaseStep> {
+        match item.action {
+            "drop" => {
+                self.set_todo(rest)?;
+                Ok(RebaseStep::Dropped(item.id))
+            }
+            "exec" => {
+                let mut cmd = cmd_maybe_shell(&item.summary, false);
+                if let Some(workdir) = self.repo.workdir() {
+                    cmd.current_dir(workdir);
+                }
+                let status = cmd.status()?;
+                self.set_todo(rest)?;
+                if status.success() {
+                    Ok(RebaseStep::Exec(item.summary.clone()))
+                } else {
+                    Ok(RebaseStep::Stopped(RebaseStop::ExecFailed(item.summary.clone())))
+                }
+            }
+            action => {
+                if (action == "squash" || action == "fixup") && self.repo.refname_to_id("HEAD")? == self.onto {
+                    return Err(format!("Cannot {} without a previous commit: {}", action, item.summary).into());
+                }
+                let commit = self.repo.find_commit(item.id)?;
+                self.repo.cherrypick(&commit, None)?;
+                // From here on, --continue commits the result of the cherry-pick.
+                fs::write(self.dir.join("stopped"), format!("{} {}\n", action, item.id))?;
+                self.set_todo(rest)?;
+                if self.repo.index()?.has_conflicts() {
+                    return Ok(RebaseStep::Stopped(RebaseStop::Conflict(item.id)));
+                }
+                self.commit_applied(action, &commit)
+            }
+        }
+    }
+
+    fn edit_message(&self, message: &str) -> Result<String> {
+        let filename = self.dir.join("message");
+        fs::write(&filename, message)?;
+        run_editor(&self.repo.config()?, &filename)?;
+        let edited = git2::message_prettify(fs::read_to_string(&filename)?, git2::DEFAULT_COMMENT_CHAR)?;
+        // Keep the message as it was rather than losing the commit.
+        Ok(if edited.is_empty() { message.to_string() } else { edited })
+    }
+
+    // Commit the index as the result of applying `commit` with `action`.
+    fn commit_applied(&self, action: &'static str, commit: &Commit) -> Result<RebaseStep> {
+        let repo = self.repo;
+        let mut index = repo.index()?;
+        index.read(false)?;
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let head = repo.head()?.peel_to_commit()?;
+        let committer = get_signature(&repo.config()?, "COMMITTER")?;
+        let message = String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        let squash_edit = self.dir.join("squash-edit");
+        let mut new = if action == "squash" || action == "fixup" {
+            let head_message = String::from_utf8_lossy(head.message_bytes()).into_owned();
+            let message = if action == "squash" {
+                fs::write(&squash_edit, "")?;
+                format!("{}\n\n{}", head_message.trim_end(), message)
+            } else {
+                head_message
+            };
+            let parents: Vec<Commit> = head.parents().collect();
+            let parents: Vec<&Commit> = parents.iter().collect();
+            repo.commit(None, &head.author(), &committer, &message, &tree, &parents)?
+        } else {
+            if tree.id() == head.tree_id() {
+                repo.cleanup_state()?;
+                fs::remove_file(self.dir.join("stopped"))?;
+                return Ok(RebaseStep::Empty(commit.id()));
+            }
+            let message = if action == "reword" { self.edit_message(&message)? } else { message };
+            repo.commit(None, &commit.author(), &committer, &message, &tree, &[&head])?
+        };
+        repo.reference("HEAD", new, true, &format!("rebase ({}): {}", action, commit.summary().unwrap_or("")))?;
+        repo.cleanup_state()?;
+        fs::remove_file(self.dir.join("stopped"))?;
+
+        // After the last of a run of squashes and fixups, edit the combined message.
+        let next = self.todo()?.first().map(|item| item.action);
+        if squash_edit.exists() && next != Some("squash") && next != Some("fixup") {
+            fs::remove_file(&squash_edit)?;
+            let squashed = repo.find_commit(new)?;
+            let message = self.edit_message(&String::from_utf8_lossy(squashed.message_bytes()))?;
+            new = squashed.amend(Some("HEAD"), None, None, None, Some(&message), None)?;
+        }
+
+        if action == "edit" {
+            Ok(RebaseStep::Stopped(RebaseStop::Edit { old: commit.id(), new }))
+        } else {
+            Ok(RebaseStep::Applied { old: commit.id(), new })
+        }
+    }
+
+    /// Resume a stopped rebase. If it stopped on a conflict, commit the resolved changes in the
+    /// index; the caller then continues with `step`, which retries any item that failed to apply.
+    pub fn resume(&self) -> Result<Option<RebaseStep>> {
+        let repo = self.repo;
+        let mut index = repo.index()?;
+        index.read(false)?;
+        let stopped = match fs::read_to_string(self.dir.join("stopped")) {
+            Ok(stopped) => stopped,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if index.write_tree()? != repo.head()?.peel_to_commit()?.tree_id() {
+                    return Err(concat!(
+                        "You have staged changes in your index.\n",
+                        "Commit them, or amend the current commit with \"git commit --amend\", then continue.",
+                    ).into());
+                }
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut words = stopped.split_whitespace();
+        let action = words.next().and_then(action_name).ok_or("Corrupt series rebase state")?;
+        let id = Oid::from_str(words.next().ok_or("Corrupt series rebase state")?)?;
+        if index.has_conflicts() {
+            return Err("You must resolve the conflicts and mark them resolved with \"git add\" first.".into());
+        }
+        self.commit_applied(action, &repo.find_commit(id)?).map(Some)
+    }
+
+    /// Skip the commit the rebase stopped at, or the item that failed to apply, discarding any
+    /// changes from it; the caller then continues with `step`.
+    pub fn skip(&self) -> Result<()> {
+        let head = self.repo.head()?.peel(ObjectType::Commit)?;
+        self.repo.reset(&head, ResetType::Hard, None)?;
+        self.repo.cleanup_state()?;
+        let stopped = self.dir.join("stopped");
+        if stopped.exists() {
+            fs::remove_file(stopped)?;
+        }
+        let failed = self.dir.join("failed");
+        if failed.exists() {
+            let mut todo = self.todo()?;
+            if !todo.is_empty() {
+                todo.remove(0);
+            }
+            self.set_todo(&todo)?;
+            fs::remove_file(failed)?;
+        }
+        Ok(())
+    }
+
+    /// Abandon the rebase, resetting HEAD and the work tree to the series before the rebase.
+    pub fn abort(self) -> Result<()> {
+        let orig_head = self.repo.find_commit(self.orig_head)?.into_object();
+        self.repo.reset(&orig_head, ResetType::Hard, None)?;
+        self.repo.cleanup_state()?;
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    /// Finish the rebase once `step` has applied the whole todo list: set the working `series`
+    /// to HEAD and the working `base` to `onto`, and remove the rebase state. Returns HEAD.
+    pub fn finish(self) -> Result<Oid> {
+        let mut internals = Internals::read_series(self.repo, &self.series)?;
+        internals.update_series(self.repo)?;
+        internals.working.insert("base", self.onto, GIT_FILEMODE_COMMIT as i32)?;
+        internals.write(self.repo)?;
+        fs::remove_dir_all(&self.dir)?;
+        Ok(self.repo.refname_to_id("HEAD")?)
+    }
+}
+
+#[test]
+fn test_autosquash() {
+    let id = |n: u8| Oid::from_bytes(&[n; 20]).unwrap();
+    let items = vec![
+        TodoItem::new("pick", id(0xa1), "Add feature"),
+        TodoItem::new("pick", id(0xb2), "Fix bug"),
+        TodoItem::new("pick", id(0xc3), "fixup! Add feature"),
+        TodoItem::new("pick", id(0xd4), "squash! b2b2b2b2"),
+        TodoItem::new("pick", id(0xe5), "fixup! fixup! Add feat"),
+        TodoItem::new("pick", id(0xf6), "fixup! Unknown commit"),
+    ];
+    let actions: Vec<_> = autosquash(items).iter().map(|item| (item.action, item.id)).collect();
+    assert_eq!(actions, vec![
+        ("pick", id(0xa1)),
+        ("fixup", id(0xc3)),
+        ("fixup", id(0xe5)),
+        ("pick", id(0xb2)),
+        ("squash", id(0xd4)),
+        ("pick", id(0xf6)),
+    ]);
+}
+
+#[cfg(test)]
+fn run_to_end(rebase: &Rebase) -> Vec<RebaseStep> {
+    let mut steps = Vec::new();
+    while let Some(step) = rebase.step().unwrap() {
+        steps.push(step);
+    }
+    steps
+}
+
+#[test]
+fn test_rebase_actions() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("a", Some("1\n"))], "Base");
+    let c1 = t.commit(&[("a", Some("2\n"))], "One");
+    let c2 = t.commit(&[("b", Some("1\n"))], "Two");
+    let c3 = t.commit(&[("a", Some("3\n"))], "fixup! One");
+    let c4 = t.commit(&[("b", Some("2\n"))], "squash! Two");
+    let c5 = t.commit(&[("c", Some("1\n"))], "Three");
+    let c6 = t.commit(&[("d", Some("1\n"))], "Four");
+    let todo: Vec<TodoItem> = [c1, c2, c3, c4, c5, c6].iter()
+        .map(|&id| TodoItem::new("pick", id, repo.find_commit(id).unwrap().summary().unwrap()))
+        .collect();
+    let mut todo = autosquash(todo);
+    todo[2].action = "reword";
+    todo[4].action = "drop";
+    todo.insert(4, TodoItem::exec("test -f b && touch exec-ran"));
+    let actions: Vec<_> = todo.iter().map(|item| item.action).collect();
+    assert_eq!(actions, vec!["pick", "fixup", "reword", "squash", "exec", "drop", "pick"]);
+
+    t.checkout(base);
+    let rebase = Rebase::start(repo, "test", base, c6, &todo).unwrap();
+    let steps = run_to_end(&rebase);
+    assert_eq!(steps[4], RebaseStep::Exec("test -f b && touch exec-ran".to_string()));
+    assert_eq!(steps[5], RebaseStep::Dropped(c5));
+    assert!(t.path().join("exec-ran").exists());
+
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(t.summaries(base, head.id()), vec!["One", "Two", "Four"]);
+    let squashed = head.parent(0).unwrap();
+    assert_eq!(squashed.message().unwrap(), "Two\n\nsquash! Two\n");
+    let tree = head.tree().unwrap();
+    let content = |path: &str| {
+        let blob = repo.find_blob(tree.get_path(std::path::Path::new(path)).unwrap().id()).unwrap();
+        String::from_utf8(blob.content().to_vec()).unwrap()
+    };
+    assert_eq!((content("a"), content("b")), ("3\n".to_string(), "2\n".to_string()));
+    assert!(tree.get_name("c").is_none());
+}
+
+#[test]
+fn test_rebase_conflict() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let onto = t.commit(&[("f", Some("2\n"))], "Upstream change");
+    t.checkout(base);
+    let c1 = t.commit(&[("f", Some("3\n"))], "Conflicting change");
+    let c2 = t.commit(&[("g", Some("1\n"))], "Other change");
+    let todo = vec![TodoItem::new("pick", c1, "Conflicting change"), TodoItem::new("pick", c2, "Other change")];
+
+    // Continue after resolving the conflict.
+    t.checkout(onto);
+    let rebase = Rebase::start(repo, "test", onto, c2, &todo).unwrap();
+    // The saved todo list uses full commit IDs, which commits made by the rebase cannot make
+    // ambiguous.
+    assert_eq!(
+        fs::read_to_string(repo.path().join(REBASE_DIR).join("todo")).unwrap(),
+        format!("pick {} Conflicting change\npick {} Other change\n", c1, c2),
+    );
+    assert_eq!(rebase.step().unwrap(), Some(RebaseStep::Stopped(RebaseStop::Conflict(c1))));
+    assert!(rebase.step().is_err());
+    assert!(rebase.resume().is_err());
+    fs::write(t.path().join("f"), "4\n").unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(std::path::Path::new("f")).unwrap();
+    index.write().unwrap();
+    assert!(matches!(rebase.resume().unwrap(), Some(RebaseStep::Applied { old, .. }) if old == c1));
+    assert_eq!(run_to_end(&rebase).len(), 1);
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(t.summaries(onto, head.id()), vec!["Conflicting change", "Other change"]);
+    assert_eq!(fs::read_to_string(t.path().join("f")).unwrap(), "4\n");
+    fs::remove_dir_all(repo.path().join(REBASE_DIR)).unwrap();
+
+    // Skip the conflicting commit.
+    t.checkout(onto);
+    let rebase = Rebase::start(repo, "test", onto, c2, &todo).unwrap();
+    assert!(matches!(rebase.step().unwrap(), Some(RebaseStep::Stopped(_))));
+    rebase.skip().unwrap();
+    assert_eq!(run_to_end(&rebase).len(), 1);
+    assert_eq!(t.summaries(onto, repo.refname_to_id("HEAD").unwrap()), vec!["Other change"]);
+    fs::remove_dir_all(repo.path().join(REBASE_DIR)).unwrap();
+
+    // Abort, returning to the series as it was.
+    t.checkout(onto);
+    let rebase = Rebase::start(repo, "test", onto, c2, &todo).unwrap();
+    assert!(matches!(rebase.step().unwrap(), Some(RebaseStep::Stopped(_))));
+    rebase.abort().unwrap();
+    assert_eq!(repo.refname_to_id("HEAD").unwrap(), c2);
+    assert!(Rebase::in_progress(repo).unwrap().is_none());
+    assert_eq!(repo.state(), git2::RepositoryState::Clean);
+}
+
+#[test]
+fn test_rebase_failed_item() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let c1 = t.commit(&[("g", Some("1\n"))], "Add g");
+    let c2 = t.commit(&[("h", Some("1\n"))], "Add h");
+    let todo = vec![
+        TodoItem::exec("echo junk > g"),
+        TodoItem::new("pick", c1, "Add g"),
+        TodoItem::new("pick", c2, "Add h"),
+    ];
+
+    // The untracked file stops the pick; the commit stays in the todo list, for --continue.
+    t.checkout(base);
+    let rebase = Rebase::start(repo, "test", base, c2, &todo).unwrap();
+    assert!(matches!(rebase.step().unwrap(), Some(RebaseStep::Exec(_))));
+    assert!(rebase.step().is_err());
+    assert_eq!(rebase.todo().unwrap().len(), 2);
+    fs::remove_file(t.path().join("g")).unwrap();
+    assert_eq!(rebase.resume().unwrap(), None);
+    assert_eq!(run_to_end(&rebase).len(), 2);
+    assert_eq!(t.summaries(base, repo.refname_to_id("HEAD").unwrap()), vec!["Add g", "Add h"]);
+    fs::remove_dir_all(repo.path().join(REBASE_DIR)).unwrap();
+
+    // --skip drops the commit that failed.
+    t.checkout(base);
+    let rebase = Rebase::start(repo, "test", base, c2, &todo).unwrap();
+    rebase.step().unwrap();
+    assert!(rebase.step().is_err());
+    rebase.skip().unwrap();
+    assert_eq!(run_to_end(&rebase).len(), 1);
+    assert_eq!(t.summaries(base, repo.refname_to_id("HEAD").unwrap()), vec!["Add h"]);
+}
+
+#[test]
+fn test_rebase_edit_conflict() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    let onto = t.commit(&[("f", Some("2\n"))], "Upstream change");
+    t.checkout(base);
+    let c1 = t.commit(&[("f", Some("3\n"))], "Conflicting change");
+    let c2 = t.commit(&[("g", Some("1\n"))], "Other change");
+    let todo = vec![TodoItem::new("edit", c1, "Conflicting change"), TodoItem::new("pick", c2, "Other change")];
+
+    // Continuing after resolving the conflict still stops to edit the commit.
+    t.checkout(onto);
+    let rebase = Rebase::start(repo, "test", onto, c2, &todo).unwrap();
+    assert_eq!(rebase.step().unwrap(), Some(RebaseStep::Stopped(RebaseStop::Conflict(c1))));
+    fs::write(t.path().join("f"), "4\n").unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(std::path::Path::new("f")).unwrap();
+    index.write().unwrap();
+    let new = match rebase.resume().unwrap() {
+        Some(RebaseStep::Stopped(RebaseStop::Edit { old, new })) if old == c1 => new,
+        step => panic!("expected to stop to edit, not {:?}", step),
+    };
+    assert_eq!(repo.refname_to_id("HEAD").unwrap(), new);
+    assert_eq!(rebase.todo().unwrap().len(), 1);
+
+    // Amend the commit, then continue.
+    repo.find_commit(new).unwrap().amend(Some("HEAD"), None, None, None, Some("Edited change"), None).unwrap();
+    assert_eq!(rebase.resume().unwrap(), None);
+    assert_eq!(run_to_end(&rebase).len(), 1);
+    assert_eq!(t.summaries(onto, repo.refname_to_id("HEAD").unwrap()), vec!["Edited change", "Other change"]);
+}
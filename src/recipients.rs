@@ -0,0 +1,92 @@
+use std::fmt::Write as FmtWrite;
+
+use crate::error::Result;
+
+/// The addresses to send a series to. The `recipients` entry of the git-series tree stores one
+/// address per line, prefixed with "To: " or "Cc: ".
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recipients {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+}
+
+impl Recipients {
+    pub fn is_empty(&self) -> bool {
+        self.to.is_empty() && self.cc.is_empty()
+    }
+
+    /// Add an address to the To: list, moving it from the Cc: list if there. Returns true if
+    /// this changed the recipients.
+    pub fn add_to(&mut self, address: &str) -> bool {
+        let moved = self.remove_from_cc(address);
+        if self.to.iter().any(|a| a == address) {
+            return moved;
+        }
+        self.to.push(address.to_string());
+        true
+    }
+
+    /// Add an address to the Cc: list, unless already a recipient. Returns true if this changed
+    /// the recipients.
+    pub fn add_cc(&mut self, address: &str) -> bool {
+        if self.to.iter().chain(self.cc.iter()).any(|a| a == address) {
+            return false;
+        }
+        self.cc.push(address.to_string());
+        true
+    }
+
+    fn remove_from_cc(&mut self, address: &str) -> bool {
+        let len = self.cc.len();
+        self.cc.retain(|a| a != address);
+        self.cc.len() != len
+    }
+
+    /// Remove an address from both lists. Returns true if it was a recipient.
+    pub fn remove(&mut self, address: &str) -> bool {
+        let len = self.to.len();
+        self.to.retain(|a| a != address);
+        self.remove_from_cc(address) || self.to.len() != len
+    }
+}
+
+pub fn parse_recipients(content: &str) -> Result<Recipients> {
+    let mut recipients = Recipients::default();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        if let Some(address) = line.strip_prefix("To: ") {
+            recipients.to.push(address.trim().to_string());
+        } else if let Some(address) = line.strip_prefix("Cc: ") {
+            recipients.cc.push(address.trim().to_string());
+        } else {
+            return Err(format!("Malformed line in \"recipients\": {}", line).into());
+        }
+    }
+    Ok(recipients)
+}
+
+pub fn write_recipients(recipients: &Recipients) -> String {
+    let mut s = String::new();
+    for address in recipients.to.iter() {
+        writeln!(s, "To: {}", address).unwrap();
+    }
+    for address in recipients.cc.iter() {
+        writeln!(s, "Cc: {}", address).unwrap();
+    }
+    s
+}
+
+#[test]
+fn test_recipients() {
+    let mut recipients = parse_recipients("Cc: Jane Doe <jane@example.com>\nTo: list@example.com\n").unwrap();
+    assert!(!recipients.add_cc("list@example.com"));
+    assert!(recipients.add_cc("joe@example.com"));
+    assert!(recipients.add_to("Jane Doe <jane@example.com>"));
+    assert!(!recipients.add_to("list@example.com"));
+    assert_eq!(
+        write_recipients(&recipients),
+        "To: list@example.com\nTo: Jane Doe <jane@example.com>\nCc: joe@example.com\n",
+    );
+    assert!(recipients.remove("list@example.com"));
+    assert!(!recipients.remove("list@example.com"));
+    assert!(parse_recipients("Bcc: joe@example.com\n").is_err());
+}
@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+
+use git2::{Oid, Repository};
+
+use crate::error::Result;
+use crate::internals::{SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX};
+use crate::series::history_from;
+use crate::util::notfound_to_none;
+
+/// How a local version of a series relates to a remote version.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncStatus {
+    UpToDate,
+    /// The local version has this many series commits the remote version lacks.
+    Ahead(usize),
+    /// The remote version has this many series commits the local version lacks.
+    Behind(usize),
+    /// Each version has series commits the other lacks.
+    Diverged { ahead: usize, behind: usize },
+}
+
+/// Compare two series commits by counting the series commits in the history of each but not the
+/// other, following only the history parents of series commits.
+pub fn sync_status(repo: &Repository, local: Oid, remote: Oid) -> Result<SyncStatus> {
+    if local == remote {
+        return Ok(SyncStatus::UpToDate);
+    }
+    let local_history: HashSet<Oid> = history_from(repo, local)?.iter().map(|v| v.id()).collect();
+    let remote_history: HashSet<Oid> = history_from(repo, remote)?.iter().map(|v| v.id()).collect();
+    let ahead = local_history.difference(&remote_history).count();
+    let behind = remote_history.difference(&local_history).count();
+    Ok(match (ahead, behind) {
+        (0, 0) => SyncStatus::UpToDate,
+        (ahead, 0) => SyncStatus::Ahead(ahead),
+        (0, behind) => SyncStatus::Behind(behind),
+        (ahead, behind) => SyncStatus::Diverged { ahead, behind },
+    })
+}
+
+/// The remote-tracking ref for the series `name` fetched from `remote`, matching where git's
+/// default fetch refspec stores remote branches.
+pub fn remote_series_refname(remote: &str, name: &str) -> String {
+    format!("refs/remotes/{}/git-series/{}", remote, name)
+}
+
+/// A series fetched from a remote repository, as recorded by a remote-tracking ref.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteSeries {
+    pub remote: String,
+    pub name: String,
+    /// The series commit the remote-tracking ref points to.
+    pub id: Oid,
+}
+
+impl RemoteSeries {
+    /// The short name of the remote-tracking ref, such as "origin/git-series/NAME", which
+    /// revisions accept.
+    pub fn shorthand(&self) -> String {
+        format!("{}/git-series/{}", self.remote, self.name)
+    }
+}
+
+/// List all remote-tracking series refs, `refs/remotes/*/git-series/*`, sorted by remote and name.
+pub fn list_remote_series(repo: &Repository) -> Result<Vec<RemoteSeries>> {
+    let mut series = Vec::new();
+    for r in repo.references_glob("refs/remotes/*")? {
+        let r = r?;
+        let refname = match r.name() {
+            Some(refname) => refname,
+            None => continue,
+        };
+        let rest = &refname["refs/remotes/".len()..];
+        if let (Some(pos), Some(id)) = (rest.find("/git-series/"), r.target()) {
+            series.push(RemoteSeries {
+                remote: rest[..pos].to_string(),
+                name: rest[pos + "/git-series/".len()..].to_string(),
+                id,
+            });
+        }
+    }
+    series.sort_by(|a, b| (&a.remote, &a.name).cmp(&(&b.remote, &b.name)));
+    Ok(series)
+}
+
+/// The remote-tracking ref for the staged or working ref of a series fetched from `remote`;
+/// `prefix` is `STAGED_PREFIX` or `WORKING_PREFIX`.
+pub fn remote_internals_refname(remote: &str, prefix: &str, name: &str) -> String {
+    format!("refs/remotes/{}/{}{}", remote, &prefix["refs/".len()..], name)
+}
+
+/// The refspecs to push the series `name`, and with `internals` its staged and working refs if
+/// they exist. The staged and working refs never fast-forward, so they always force.
+pub fn push_refspecs(repo: &Repository, name: &str, internals: bool, force: bool) -> Result<Vec<String>> {
+    let series_ref = format!("{}{}", SERIES_PREFIX, name);
+    let mut refspecs = vec![format!("{}{}:{}", if force { "+" } else { "" }, series_ref, series_ref)];
+    if internals {
+        for prefix in [STAGED_PREFIX, WORKING_PREFIX].iter() {
+            let refname = format!("{}{}", prefix, name);
+            if notfound_to_none(repo.refname_to_id(&refname))?.is_some() {
+                refspecs.push(format!("+{}:{}", refname, refname));
+            }
+        }
+    }
+    Ok(refspecs)
+}
+
+/// The refs to fetch for the series `name` from `remote`, and with `internals` its staged and
+/// working refs, as pairs of the ref on the remote and the local remote-tracking ref.
+pub fn fetch_refs(remote: &str, name: &str, internals: bool) -> Vec<(String, String)> {
+    let mut refs = vec![(format!("{}{}", SERIES_PREFIX, name), remote_series_refname(remote, name))];
+    if internals {
+        for prefix in [STAGED_PREFIX, WORKING_PREFIX].iter() {
+            refs.push((format!("{}{}", prefix, name), remote_internals_refname(remote, prefix, name)));
+        }
+    }
+    refs
+}
+
+#[cfg(test)]
+fn push(repo: &Repository, url: &str, refspecs: &[String]) -> std::result::Result<Vec<String>, git2::Error> {
+    let mut updates = Vec::new();
+    {
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.push_update_reference(|refname, status| {
+            assert_eq!(status, None);
+            updates.push(refname.to_string());
+            Ok(())
+        });
+        let mut opts = git2::PushOptions::new();
+        opts.remote_callbacks(callbacks);
+        let refspecs: Vec<&str> = refspecs.iter().map(|s| s.as_str()).collect();
+        repo.remote_anonymous(url)?.push(&refspecs, Some(&mut opts))?;
+    }
+    Ok(updates)
+}
+
+#[cfg(test)]
+fn fetch(repo: &Repository, url: &str, refs: &[(String, String)]) {
+    let refspecs: Vec<String> = refs.iter().map(|(src, dst)| format!("+{}:{}", src, dst)).collect();
+    let refspecs: Vec<&str> = refspecs.iter().map(|s| s.as_str()).collect();
+    repo.remote_anonymous(url).unwrap().fetch(&refspecs, None, None).unwrap();
+}
+
+#[test]
+fn test_push_fetch() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    t.commit(&[("f", Some("2\n"))], "Change");
+    let series = crate::series::Series::start(repo, "topic").unwrap();
+    series.set_base(Some(base)).unwrap();
+    let v1 = series.commit("First version", true).unwrap();
+    let dir = tempdir::TempDir::new("git-series-remote").unwrap();
+    let bare = Repository::init_bare(dir.path()).unwrap();
+    let url = dir.path().to_str().unwrap();
+    let series_ref = "refs/heads/git-series/topic";
+    let (staged_ref, working_ref) = ("refs/git-series-internals/staged/topic", "refs/git-series-internals/working/topic");
+
+    // Push the series ref, and with internals the staged and working refs, always forced.
+    assert_eq!(push_refspecs(repo, "topic", false, false).unwrap(), [format!("{}:{}", series_ref, series_ref)]);
+    let refspecs = push_refspecs(repo, "topic", true, false).unwrap();
+    assert_eq!(refspecs, [
+        format!("{}:{}", series_ref, series_ref),
+        format!("+{}:{}", staged_ref, staged_ref),
+        format!("+{}:{}", working_ref, working_ref),
+    ]);
+    assert_eq!(push(repo, url, &refspecs).unwrap(), [series_ref, staged_ref, working_ref]);
+    for refname in [series_ref, staged_ref, working_ref].iter() {
+        assert_eq!(bare.refname_to_id(refname).unwrap(), repo.refname_to_id(refname).unwrap());
+    }
+
+    // Fetch them into remote-tracking refs.
+    let refs = fetch_refs("origin", "topic", true);
+    assert_eq!(refs, [
+        (series_ref.to_string(), "refs/remotes/origin/git-series/topic".to_string()),
+        (staged_ref.to_string(), "refs/remotes/origin/git-series-internals/staged/topic".to_string()),
+        (working_ref.to_string(), "refs/remotes/origin/git-series-internals/working/topic".to_string()),
+    ]);
+    assert_eq!(fetch_refs("origin", "topic", false), refs[..1]);
+    fetch(repo, url, &refs);
+    for (src, dst) in refs.iter() {
+        assert_eq!(repo.refname_to_id(dst).unwrap(), repo.refname_to_id(src).unwrap());
+    }
+    let tracking = |refname: &str| repo.refname_to_id(refname).unwrap();
+    let status = || sync_status(repo, repo.refname_to_id(series_ref).unwrap(), tracking(&refs[0].1)).unwrap();
+    assert_eq!(status(), SyncStatus::UpToDate);
+
+    // A new series commit fast-forwards the remote series.
+    let v2 = series.commit("Second version", true).unwrap();
+    assert_eq!(status(), SyncStatus::Ahead(1));
+    let refspecs = push_refspecs(repo, "topic", false, false).unwrap();
+    assert_eq!(push(repo, url, &refspecs).unwrap(), [series_ref]);
+    fetch(repo, url, &refs);
+    assert_eq!(tracking(&refs[0].1), v2);
+    assert_eq!(status(), SyncStatus::UpToDate);
+
+    // A series commit that does not build on the remote series diverges from it, and only a
+    // forced push replaces the remote series.
+    let v1 = repo.find_commit(v1).unwrap();
+    let sig = repo.signature().unwrap();
+    let other = repo.commit(None, &sig, &sig, "Other version", &v1.tree().unwrap(), &[&v1]).unwrap();
+    repo.reference(series_ref, other, true, "diverge").unwrap();
+    assert_eq!(status(), SyncStatus::Diverged { ahead: 1, behind: 1 });
+    assert_eq!(push(repo, url, &refspecs).unwrap_err().code(), git2::ErrorCode::NotFastForward);
+    assert_eq!(bare.refname_to_id(series_ref).unwrap(), v2);
+    let refspecs = push_refspecs(repo, "topic", false, true).unwrap();
+    assert_eq!(refspecs, [format!("+{}:{}", series_ref, series_ref)]);
+    assert_eq!(push(repo, url, &refspecs).unwrap(), [series_ref]);
+    fetch(repo, url, &refs);
+    assert_eq!(tracking(&refs[0].1), other);
+    assert_eq!(status(), SyncStatus::UpToDate);
+
+    // A series behind the remote series can fast-forward to it.
+    repo.reference(series_ref, v1.id(), true, "reset").unwrap();
+    assert_eq!(status(), SyncStatus::Behind(1));
+}
+
+#[test]
+fn test_sync_status() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    t.commit(&[("f", Some("2\n"))], "Change");
+    let series = crate::series::Series::start(repo, "topic").unwrap();
+    series.set_base(Some(base)).unwrap();
+    let v1 = series.commit("First version", true).unwrap();
+    t.commit(&[("f", Some("3\n"))], "Change again");
+    let v2 = series.commit("Second version", true).unwrap();
+    let sig = repo.signature().unwrap();
+    let series_commit = |message: &str, parents: &[Oid]| {
+        let parents: Vec<_> = parents.iter().map(|&id| repo.find_commit(id).unwrap()).collect();
+        let tree = parents[0].tree().unwrap();
+        repo.commit(None, &sig, &sig, message, &tree, &parents.iter().collect::<Vec<_>>()).unwrap()
+    };
+    let other1 = series_commit("Other version", &[v1]);
+    let other2 = series_commit("Another version", &[other1]);
+    let merged = series_commit("Merge", &[v2, other2]);
+
+    // Only series commits count, not the commits of the series they record.
+    assert_eq!(sync_status(repo, v2, v2).unwrap(), SyncStatus::UpToDate);
+    assert_eq!(sync_status(repo, v2, v1).unwrap(), SyncStatus::Ahead(1));
+    assert_eq!(sync_status(repo, v1, v2).unwrap(), SyncStatus::Behind(1));
+    assert_eq!(sync_status(repo, v2, other2).unwrap(), SyncStatus::Diverged { ahead: 1, behind: 2 });
+    // A series merge commit includes the history of each of its parents.
+    assert_eq!(sync_status(repo, merged, other2).unwrap(), SyncStatus::Ahead(2));
+    assert_eq!(sync_status(repo, v2, merged).unwrap(), SyncStatus::Behind(3));
+}
+
+#[test]
+fn test_list_remote_series() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let a = t.commit(&[("f", Some("1\n"))], "A");
+    let b = t.commit(&[("f", Some("2\n"))], "B");
+    for &(refname, id) in [
+        ("refs/remotes/origin/git-series/topic", a),
+        ("refs/remotes/origin/git-series/feature/x", b),
+        ("refs/remotes/my/fork/git-series/topic", b),
+        ("refs/remotes/alpha/git-series/topic", a),
+        // Other remote-tracking refs are not series.
+        ("refs/remotes/origin/master", a),
+        ("refs/remotes/origin/git-series-internals/staged/topic", a),
+        ("refs/heads/git-series/topic", a),
+    ].iter() {
+        repo.reference(refname, id, false, "test").unwrap();
+    }
+    let remote_series = |remote: &str, name: &str, id| RemoteSeries { remote: remote.to_string(), name: name.to_string(), id };
+    let series = list_remote_series(repo).unwrap();
+    assert_eq!(series, [
+        remote_series("alpha", "topic", a),
+        remote_series("my/fork", "topic", b),
+        remote_series("origin", "feature/x", b),
+        remote_series("origin", "topic", a),
+    ]);
+    assert_eq!(series[1].shorthand(), "my/fork/git-series/topic");
+    assert_eq!(remote_series_refname("my/fork", "topic"), "refs/remotes/my/fork/git-series/topic");
+    assert_eq!(
+        remote_internals_refname("my/fork", WORKING_PREFIX, "topic"),
+        "refs/remotes/my/fork/git-series-internals/working/topic",
+    );
+}
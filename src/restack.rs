@@ -0,0 +1,220 @@
+use std::collections::{HashSet, VecDeque};
+
+use git2::{Commit, Oid, Repository};
+
+use crate::diff::{get_commits, DiffColors, RangeDiff, RangeDiffOptions};
+use crate::error::Result;
+use crate::internals::GIT_FILEMODE_COMMIT;
+use crate::series::{list_series, Series};
+use crate::util::get_signature;
+
+/// What restacking did to a series based on another series.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RestackStatus {
+    /// The series now applies on top of the new base; this is its new last commit.
+    Restacked(Oid),
+    /// This commit of the series did not apply cleanly to the new base, or is a merge commit, so
+    /// the series remains on its old base.
+    Failed(Oid),
+}
+
+/// One series restacked onto the new version of a series it was based on.
+#[derive(Clone, Debug)]
+pub struct Restack {
+    pub name: String,
+    pub old_base: Oid,
+    pub new_base: Oid,
+    pub status: RestackStatus,
+}
+
+/// The `base..series` ranges of the committed versions of `series`, most recent first.
+pub fn previous_ranges(series: &Series) -> Result<Vec<(Oid, Oid)>> {
+    let mut ranges = Vec::new();
+    for version in series.history()? {
+        if let (Some(base), Some(tip)) = (version.base(), version.series()) {
+            if !ranges.contains(&(base, tip)) {
+                ranges.push((base, tip));
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+// Find the commit of `new` corresponding to `commit` in `old`: the commit a range diff pairs it
+// with, or failing that, the one paired with the closest commit before it. If no commit up to
+// `commit` has a counterpart, that is the base of `new`.
+fn corresponding_commit(repo: &Repository, old: (Oid, Oid), new: (Oid, Oid), commit: Oid) -> Result<Oid> {
+    let range_diff = RangeDiff::new(repo, &DiffColors::plain(), &RangeDiffOptions::default(), old, new)?;
+    let position = range_diff.commits1.iter().position(|c| c.id() == commit)
+        .ok_or("Internal error: commit not found in range")?;
+    for i in (0..=position).rev() {
+        let paired = range_diff.pairs.iter().find(|p| p.old == Some(i)).and_then(|p| p.new);
+        if let Some(j) = paired {
+            return Ok(range_diff.commits2[j].id());
+        }
+    }
+    Ok(new.0)
+}
+
+// Apply the commits `base..tip` on top of `onto` without touching the index or work tree,
+// dropping commits that become empty. Returns the new last commit, or the first commit that did
+// not apply.
+fn replay(repo: &Repository, base: Oid, tip: Oid, onto: Oid) -> Result<RestackStatus> {
+    let committer = get_signature(&repo.config()?, "COMMITTER")?;
+    let mut head: Commit = repo.find_commit(onto)?;
+    for commit in get_commits(repo, base, tip)? {
+        if commit.parent_count() != 1 {
+            return Ok(RestackStatus::Failed(commit.id()));
+        }
+        let mut index = repo.merge_trees(&commit.parent(0)?.tree()?, &head.tree()?, &commit.tree()?, None)?;
+        if index.has_conflicts() {
+            return Ok(RestackStatus::Failed(commit.id()));
+        }
+        let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+        if tree.id() == head.tree_id() {
+            continue;
+        }
+        let message = String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        let id = repo.commit(None, &commit.author(), &committer, &message, &tree, &[&head])?;
+        head = repo.find_commit(id)?;
+    }
+    Ok(RestackStatus::Restacked(head.id()))
+}
+
+/// Rebase every other series whose working `base` lies within one of the `old` ranges of the
+/// series `name`, but not within its `new` range, onto the corresponding commit of `new`, and set
+/// its working `base` and `series` to match. Series that fail to apply stay as they were. Series
+/// based on a restacked series then restack onto it in turn, after the series they build on.
+pub fn restack_dependents(
+    repo: &Repository,
+    name: &str,
+    old: &[(Oid, Oid)],
+    new: (Oid, Oid),
+) -> Result<Vec<Restack>> {
+    let mut restacks = Vec::new();
+    let mut seen = HashSet::new();
+    seen.insert(name.to_string());
+    let mut pending = VecDeque::new();
+    pending.push_back((old.to_vec(), new));
+    while let Some((old, new)) = pending.pop_front() {
+        for (restack, range) in restack_onto(repo, &seen, &old, new)? {
+            seen.insert(restack.name.clone());
+            if let RestackStatus::Restacked(new_tip) = restack.status {
+                let mut old = vec![range];
+                for range in previous_ranges(&Series::find(repo, &restack.name)?)? {
+                    if !old.contains(&range) {
+                        old.push(range);
+                    }
+                }
+                pending.push_back((old, (restack.new_base, new_tip)));
+            }
+            restacks.push(restack);
+        }
+    }
+    Ok(restacks)
+}
+
+// Restack the series not in `skip` whose working `base` lies within one of the `old` ranges but
+// not within `new`, onto `new`. Returns each restack with the working range of the series before
+// it.
+fn restack_onto(
+    repo: &Repository,
+    skip: &HashSet<String>,
+    old: &[(Oid, Oid)],
+    new: (Oid, Oid),
+) -> Result<Vec<(Restack, (Oid, Oid))>> {
+    let new_commits: HashSet<Oid> = get_commits(repo, new.0, new.1)?.iter().map(|c| c.id()).collect();
+    let mut old_commits = Vec::new();
+    for &range in old.iter().filter(|&&range| range != new) {
+        let ids: HashSet<Oid> = get_commits(repo, range.0, range.1)?.iter().map(|c| c.id()).collect();
+        old_commits.push((range, ids));
+    }
+
+    let mut restacks = Vec::new();
+    for dependent in list_series(repo)? {
+        if skip.contains(&dependent) {
+            continue;
+        }
+        let series = Series::find(repo, &dependent)?;
+        let mut internals = series.internals()?;
+        let base = internals.working.get("base")?.map(|e| e.id());
+        let tip = internals.working.get("series")?.map(|e| e.id());
+        let (base, tip) = match (base, tip) {
+            (Some(base), Some(tip)) if !new_commits.contains(&base) => (base, tip),
+            _ => continue,
+        };
+        let range = match old_commits.iter().find(|(_, ids)| ids.contains(&base)) {
+            Some(&(range, _)) => range,
+            None => continue,
+        };
+        let new_base = corresponding_commit(repo, range, new, base)?;
+        let status = replay(repo, base, tip, new_base)?;
+        if let RestackStatus::Restacked(new_tip) = status {
+            internals.working.insert("base", new_base, GIT_FILEMODE_COMMIT as i32)?;
+            internals.working.insert("series", new_tip, GIT_FILEMODE_COMMIT as i32)?;
+            internals.write(repo)?;
+        }
+        restacks.push((Restack { name: dependent, old_base: base, new_base, status }, (base, tip)));
+    }
+    Ok(restacks)
+}
+
+#[test]
+fn test_restack() {
+    use crate::internals::SHEAD_REF;
+
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    t.commit(&[("d", Some("1\n"))], "D1");
+    let d2 = t.commit(&[("e", Some("1\n"))], "D2");
+    let dep = Series::start(repo, "dep").unwrap();
+    dep.set_base(Some(base)).unwrap();
+    dep.commit("First version", true).unwrap();
+
+    let t1 = t.commit(&[("t", Some("1\n"))], "T1");
+    let top = Series::start(repo, "top").unwrap();
+    top.set_base(Some(d2)).unwrap();
+    top.commit("First version", true).unwrap();
+
+    t.commit(&[("u", Some("1\n"))], "U1");
+    let third = Series::start(repo, "third").unwrap();
+    third.set_base(Some(t1)).unwrap();
+    third.commit("First version", true).unwrap();
+    repo.reference_symbolic(SHEAD_REF, &dep.refname(), true, "checkout dep").unwrap();
+
+    // Restacking onto the same version of the dependency changes nothing.
+    let old = previous_ranges(&dep).unwrap();
+    assert_eq!(old, vec![(base, d2)]);
+    assert!(restack_dependents(repo, "dep", &old, (base, d2)).unwrap().is_empty());
+
+    // Rebase the dependency onto a new base, changing its second patch.
+    t.checkout(base);
+    let new_base = t.commit(&[("g", Some("1\n"))], "New base");
+    t.commit(&[("d", Some("1\n"))], "D1");
+    let new_d2 = t.commit(&[("e", Some("2\n"))], "D2");
+    let restacks = restack_dependents(repo, "dep", &old, (new_base, new_d2)).unwrap();
+    assert_eq!(restacks.len(), 2);
+    let restacked = |restack: &Restack| match restack.status {
+        RestackStatus::Restacked(id) => id,
+        RestackStatus::Failed(id) => panic!("restack of {} failed at {}", restack.name, id),
+    };
+    let restack = &restacks[0];
+    assert_eq!((restack.name.as_str(), restack.old_base, restack.new_base), ("top", d2, new_d2));
+    let new_t1 = restacked(restack);
+    assert_eq!(t.summaries(new_d2, new_t1), vec!["T1"]);
+    let tree = repo.find_commit(new_t1).unwrap().tree().unwrap();
+    assert!(tree.get_name("g").is_some() && tree.get_name("t").is_some());
+    let working = top.internals().unwrap().working;
+    assert_eq!(working.get("base").unwrap().map(|e| e.id()), Some(new_d2));
+    assert_eq!(working.get("series").unwrap().map(|e| e.id()), Some(new_t1));
+
+    // A series based on a restacked series restacks onto it in turn.
+    let restack = &restacks[1];
+    assert_eq!((restack.name.as_str(), restack.old_base, restack.new_base), ("third", t1, new_t1));
+    let new_u1 = restacked(restack);
+    assert_eq!(t.summaries(new_t1, new_u1), vec!["U1"]);
+    let working = third.internals().unwrap().working;
+    assert_eq!(working.get("base").unwrap().map(|e| e.id()), Some(new_t1));
+    assert_eq!(working.get("series").unwrap().map(|e| e.id()), Some(new_u1));
+}
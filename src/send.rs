@@ -0,0 +1,367 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+
+use git2::Config;
+
+use crate::error::Result;
+
+/// How to send mail, from the `sendemail.*` configuration that `git send-email` also uses.
+#[derive(Clone, Debug)]
+pub struct SendEmailConfig {
+    /// The SMTP server host, or the absolute path of a sendmail-compatible program.
+    pub smtp_server: String,
+    pub smtp_port: u16,
+    pub smtp_user: Option<String>,
+    pub smtp_pass: Option<String>,
+    /// The name to greet the SMTP server with.
+    pub smtp_domain: String,
+    /// The envelope sender, if different from the From: address.
+    pub envelope_sender: Option<String>,
+}
+
+impl SendEmailConfig {
+    pub fn new(config: &Config) -> Result<Self> {
+        let get = |name: &str| config.get_string(&format!("sendemail.{}", name)).ok();
+        match get("smtpEncryption").as_deref() {
+            None | Some("") | Some("none") => {}
+            Some(encryption) => {
+                return Err(format!(
+                    concat!(
+                        "sendemail.smtpEncryption={} is not supported.\n",
+                        "Use a local SMTP relay, or set sendemail.smtpServer to a sendmail-compatible program.",
+                    ),
+                    encryption,
+                ).into());
+            }
+        }
+        let smtp_port = match get("smtpServerPort") {
+            Some(port) => port.parse().map_err(|_| format!("Invalid sendemail.smtpServerPort: {}", port))?,
+            None => 25,
+        };
+        let config = SendEmailConfig {
+            smtp_server: get("smtpServer").unwrap_or_else(|| "localhost".to_string()),
+            smtp_port,
+            smtp_user: get("smtpUser"),
+            smtp_pass: get("smtpPass"),
+            smtp_domain: get("smtpDomain").unwrap_or_else(|| "localhost.localdomain".to_string()),
+            envelope_sender: get("envelopeSender"),
+        };
+        // Without encryption, only send a password to a server on this machine.
+        if config.smtp_pass.is_some() && !config.is_sendmail() && !config.is_local() {
+            return Err(format!(
+                concat!(
+                    "Refusing to send sendemail.smtpPass unencrypted to {}.\n",
+                    "Use a local SMTP relay, or set sendemail.smtpServer to a sendmail-compatible program.",
+                ),
+                config.smtp_server,
+            ).into());
+        }
+        Ok(config)
+    }
+
+    /// True if `smtp_server` names a sendmail-compatible program rather than an SMTP server.
+    pub fn is_sendmail(&self) -> bool {
+        self.smtp_server.starts_with('/')
+    }
+
+    /// True if `smtp_server` names this machine.
+    pub fn is_local(&self) -> bool {
+        match self.smtp_server.as_str() {
+            "localhost" => true,
+            server => server.trim_start_matches('[').trim_end_matches(']')
+                .parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback()),
+        }
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(CHARS[(n >> (18 - 6 * i)) & 63] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+#[test]
+fn test_send_email_config() {
+    let dir = tempdir::TempDir::new("git-series-test").unwrap();
+    let mut config = Config::open(&dir.path().join("config")).unwrap();
+    config.set_str("sendemail.smtpUser", "user").unwrap();
+    config.set_str("sendemail.smtpPass", "secret").unwrap();
+    assert!(SendEmailConfig::new(&config).is_ok());
+    config.set_str("sendemail.smtpServer", "[::1]").unwrap();
+    assert!(SendEmailConfig::new(&config).is_ok());
+    config.set_str("sendemail.smtpServer", "smtp.example.com").unwrap();
+    assert!(SendEmailConfig::new(&config).is_err());
+    config.set_str("sendemail.smtpServer", "/usr/sbin/sendmail").unwrap();
+    assert!(SendEmailConfig::new(&config).is_ok());
+    config.set_str("sendemail.smtpEncryption", "tls").unwrap();
+    assert!(SendEmailConfig::new(&config).is_err());
+}
+
+#[test]
+fn test_base64() {
+    let encoded: Vec<String> = ["", "f", "fo", "foo", "foob", "fooba", "foobar"].iter()
+        .map(|s| base64(s.as_bytes()))
+        .collect();
+    assert_eq!(encoded, vec!["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"]);
+    assert_eq!(base64(&[0, 0xff, 0xfe, 0x80, 0x3f]), "AP/+gD8=");
+    assert_eq!(base64("\0user\0pässword".as_bytes()), "AHVzZXIAcMOkc3N3b3Jk");
+}
+
+/// A connection to an SMTP server.
+pub struct SmtpConnection<S: Read + Write> {
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> SmtpConnection<S> {
+    /// Read the server greeting and introduce ourselves as `domain`.
+    pub fn new(stream: S, domain: &str) -> Result<Self> {
+        let mut conn = SmtpConnection { stream: BufReader::new(stream) };
+        conn.reply(220)?;
+        conn.command(&format!("EHLO {}", domain), 250)?;
+        Ok(conn)
+    }
+
+    fn reply(&mut self, expected: u32) -> Result<String> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err("SMTP server closed the connection".into());
+            }
+            let line = line.trim_end();
+            if line.len() < 3 {
+                return Err(format!("Malformed SMTP reply: {}", line).into());
+            }
+            text.push_str(line);
+            text.push('\n');
+            if line.as_bytes().get(3) != Some(&b'-') {
+                let code: u32 = line[..3].parse().map_err(|_| format!("Malformed SMTP reply: {}", line))?;
+                if code != expected {
+                    return Err(format!("SMTP server error:\n{}", text).into());
+                }
+                return Ok(text);
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, expected: u32) -> Result<String> {
+        write!(self.stream.get_mut(), "{}\r\n", command)?;
+        self.stream.get_mut().flush()?;
+        self.reply(expected)
+    }
+
+    pub fn auth_plain(&mut self, user: &str, pass: &str) -> Result<()> {
+        let credentials = base64(format!("\0{}\0{}", user, pass).as_bytes());
+        self.command(&format!("AUTH PLAIN {}", credentials), 235)?;
+        Ok(())
+    }
+
+    /// Send one message from `from` to each of `recipients`.
+    pub fn send(&mut self, from: &str, recipients: &[String], message: &[u8]) -> Result<()> {
+        self.command(&format!("MAIL FROM:<{}>", from), 250)?;
+        for recipient in recipients {
+            self.command(&format!("RCPT TO:<{}>", recipient), 250)?;
+        }
+        self.command("DATA", 354)?;
+        let out = self.stream.get_mut();
+        for line in message.split(|&c| c == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.starts_with(b".") {
+                out.write_all(b".")?;
+            }
+            out.write_all(line)?;
+            out.write_all(b"\r\n")?;
+        }
+        self.command(".", 250)?;
+        Ok(())
+    }
+
+    pub fn quit(mut self) -> Result<()> {
+        self.command("QUIT", 221)?;
+        Ok(())
+    }
+}
+
+/// The headers of a mail, with continuation lines joined.
+fn mail_headers(message: &[u8]) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(message);
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        if line.is_empty() {
+            break;
+        } else if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some(pos) = line.find(':') {
+            headers.push((line[..pos].to_string(), line[pos + 1..].trim().to_string()));
+        }
+    }
+    headers
+}
+
+/// Split a list of addresses such as `"Doe, Jane" <jane@example.com>, joe@example.com` into the
+/// bare addresses.
+pub fn parse_addresses(list: &str) -> Vec<String> {
+    let mut addresses = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in list.chars().chain(std::iter::once(',')) {
+        match c {
+            '"' => { quoted = !quoted; current.push(c); }
+            ',' if !quoted => {
+                let entry = current.trim();
+                let address = match (entry.rfind('<'), entry.rfind('>')) {
+                    (Some(start), Some(end)) if start < end => &entry[start + 1..end],
+                    _ => entry,
+                };
+                if !address.is_empty() {
+                    addresses.push(address.to_string());
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+    addresses
+}
+
+/// The envelope recipients of a formatted mail: the addresses in its To: and Cc: headers.
+pub fn mail_recipients(message: &[u8]) -> Vec<String> {
+    let mut recipients = Vec::new();
+    for (name, value) in mail_headers(message) {
+        if name.eq_ignore_ascii_case("To") || name.eq_ignore_ascii_case("Cc") {
+            for address in parse_addresses(&value) {
+                if !recipients.contains(&address) {
+                    recipients.push(address);
+                }
+            }
+        }
+    }
+    recipients
+}
+
+/// The bare address of the From: header of a formatted mail.
+pub fn mail_sender(message: &[u8]) -> Option<String> {
+    mail_headers(message).into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("From"))
+        .and_then(|(_, value)| parse_addresses(&value).into_iter().next())
+}
+
+/// Strip the mbox "From <id> <date>" line from a formatted mail, to send it.
+pub fn strip_mbox_from(message: &[u8]) -> &[u8] {
+    if message.starts_with(b"From ") {
+        if let Some(pos) = message.iter().position(|&c| c == b'\n') {
+            return &message[pos + 1..];
+        }
+    }
+    message
+}
+
+/// Send formatted mails, in order, each to the recipients in its To: and Cc: headers.
+pub fn send_mails(config: &SendEmailConfig, messages: &[&[u8]]) -> Result<()> {
+    let envelopes = messages.iter().map(|message| {
+        let sender = config.envelope_sender.clone()
+            .or_else(|| mail_sender(message))
+            .ok_or("Cannot send mail without a From: address")?;
+        let recipients = mail_recipients(message);
+        if recipients.is_empty() {
+            return Err("Cannot send mail with no recipients; set format.to or sendemail.to".into());
+        }
+        Ok((sender, recipients, strip_mbox_from(message)))
+    }).collect::<Result<Vec<_>>>()?;
+
+    if config.is_sendmail() {
+        for (sender, recipients, message) in envelopes {
+            let mut child = Command::new(&config.smtp_server)
+                .arg("-i").arg("-f").arg(&sender).args(&recipients)
+                .stdin(Stdio::piped())
+                .spawn()?;
+            child.stdin.take().unwrap().write_all(message)?;
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(format!("{} exited with status {}", config.smtp_server, status).into());
+            }
+        }
+        return Ok(());
+    }
+
+    let stream = TcpStream::connect((config.smtp_server.as_str(), config.smtp_port))?;
+    let mut conn = SmtpConnection::new(stream, &config.smtp_domain)?;
+    if let (Some(user), Some(pass)) = (&config.smtp_user, &config.smtp_pass) {
+        conn.auth_plain(user, pass)?;
+    }
+    for (sender, recipients, message) in envelopes {
+        conn.send(&sender, &recipients, message)?;
+    }
+    conn.quit()
+}
+
+#[test]
+fn test_smtp_send() {
+    use std::io::Cursor;
+
+    // A stand-in for an SMTP server: canned replies, and a record of what the client sent.
+    struct StandIn {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+    impl Read for StandIn {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> { self.replies.read(buf) }
+    }
+    impl Write for StandIn {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.sent.write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    let message = concat!(
+        "From 1111111111111111111111111111111111111111 Mon Sep 17 00:00:00 2001\n",
+        "To: \"Doe, Jane\" <jane@example.com>\n",
+        "Cc: joe@example.com,\n",
+        " jane@example.com\n",
+        "From: A U Thor <author@example.com>\n",
+        "Subject: [PATCH] Test\n",
+        "\n",
+        ".leading dot\n",
+    ).as_bytes();
+    assert_eq!(mail_recipients(message), vec!["jane@example.com", "joe@example.com"]);
+    assert_eq!(mail_sender(message), Some("author@example.com".to_string()));
+
+    let replies = "220 ready\r\n250-hello\r\n250 AUTH PLAIN\r\n235 ok\r\n250 ok\r\n250 ok\r\n250 ok\r\n354 go\r\n250 queued\r\n221 bye\r\n";
+    let stand_in = StandIn { replies: Cursor::new(replies.as_bytes().to_vec()), sent: Vec::new() };
+    let mut conn = SmtpConnection::new(stand_in, "test").unwrap();
+    conn.auth_plain("user", "pass").unwrap();
+    conn.send("author@example.com", &mail_recipients(message), strip_mbox_from(message)).unwrap();
+    let sent = String::from_utf8(conn.stream.get_ref().sent.clone()).unwrap();
+    conn.quit().unwrap();
+    assert_eq!(sent, concat!(
+        "EHLO test\r\n",
+        "AUTH PLAIN AHVzZXIAcGFzcw==\r\n",
+        "MAIL FROM:<author@example.com>\r\n",
+        "RCPT TO:<jane@example.com>\r\n",
+        "RCPT TO:<joe@example.com>\r\n",
+        "DATA\r\n",
+        "To: \"Doe, Jane\" <jane@example.com>\r\n",
+        "Cc: joe@example.com,\r\n",
+        " jane@example.com\r\n",
+        "From: A U Thor <author@example.com>\r\n",
+        "Subject: [PATCH] Test\r\n",
+        "\r\n",
+        "..leading dot\r\n",
+        "\r\n",
+        ".\r\n",
+    ));
+}
@@ -0,0 +1,825 @@
+use git2::{Commit, Delta, Diff, ObjectType, Oid, Repository, Tree};
+
+use crate::config::{parse_series_config, write_series_config, SeriesConfig};
+use crate::depends::{parse_dependencies, write_dependencies, Dependency};
+use crate::error::Result;
+use crate::internals::{
+    shead_series_name, Internals, GIT_FILEMODE_BLOB, GIT_FILEMODE_COMMIT, MERGE_PREFIX, SERIES_PREFIX,
+    SHEAD_REF, STAGED_PREFIX, WORKING_PREFIX,
+};
+use crate::notes::{find_note, parse_notes, write_notes, PatchNote};
+use crate::patchid::patch_id;
+use crate::recipients::{parse_recipients, write_recipients, Recipients};
+use crate::util::{commit_summarize_components, get_signature, notfound_to_none, parents_from_ids};
+use crate::versions::{insert_version, parse_versions, write_versions, SentVersion};
+
+/// Get the name of the current series, if any.
+pub fn current_series_name(repo: &Repository) -> Result<Option<String>> {
+    match notfound_to_none(repo.find_reference(SHEAD_REF))? {
+        Some(shead) => Ok(Some(shead_series_name(&shead)?)),
+        None => Ok(None),
+    }
+}
+
+/// List the names of all series, including series with no commits yet and the current series.
+pub fn list_series(repo: &Repository) -> Result<Vec<String>> {
+    let mut refs = Vec::new();
+    for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
+        let l = prefix.len();
+        for r in repo.references_glob(&[prefix, "*"].concat())?.names() {
+            refs.push(r?[l..].to_string());
+        }
+    }
+    refs.extend(current_series_name(repo)?);
+    refs.sort();
+    refs.dedup();
+    Ok(refs)
+}
+
+/// One change to a git-series tree entry, such as "series", "base", or "cover".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryChange {
+    pub entry: String,
+    pub status: Delta,
+}
+
+fn entry_changes(diff: &Diff) -> Result<Vec<EntryChange>> {
+    let mut changes = Vec::new();
+    diff.foreach(&mut |delta, _| {
+        changes.push(EntryChange {
+            entry: delta.old_file().path().unwrap().to_str().unwrap().to_string(),
+            status: delta.status(),
+        });
+        true
+    }, None, None, None)?;
+    Ok(changes)
+}
+
+/// The state of a series relative to its last series commit.
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// True if the series has no series commits yet.
+    pub initial: bool,
+    /// Changes from the last series commit to the staged version.
+    pub staged: Vec<EntryChange>,
+    /// Changes from the staged version to the working version.
+    pub unstaged: Vec<EntryChange>,
+    /// Changes from the last series commit to the working version, as committed by "commit -a".
+    pub all: Vec<EntryChange>,
+    /// The series commit being merged, if a merge is in progress.
+    pub merging: Option<Oid>,
+}
+
+/// One version of a patch series: a git-series commit.
+pub struct SeriesVersion<'repo> {
+    commit: Commit<'repo>,
+    tree: Tree<'repo>,
+}
+
+impl<'repo> SeriesVersion<'repo> {
+    pub fn new(commit: Commit<'repo>) -> Result<Self> {
+        let tree = commit.tree()?;
+        Ok(SeriesVersion { commit, tree })
+    }
+
+    pub fn find(repo: &'repo Repository, id: Oid) -> Result<Self> {
+        SeriesVersion::new(repo.find_commit(id)?)
+    }
+
+    pub fn id(&self) -> Oid {
+        self.commit.id()
+    }
+
+    pub fn commit(&self) -> &Commit<'repo> {
+        &self.commit
+    }
+
+    pub fn tree(&self) -> &Tree<'repo> {
+        &self.tree
+    }
+
+    pub fn series(&self) -> Option<Oid> {
+        self.tree.get_name("series").map(|e| e.id())
+    }
+
+    pub fn base(&self) -> Option<Oid> {
+        self.tree.get_name("base").map(|e| e.id())
+    }
+
+    pub fn cover(&self, repo: &Repository) -> Result<Option<String>> {
+        match self.tree.get_name("cover") {
+            Some(entry) => Ok(Some(std::str::from_utf8(repo.find_blob(entry.id())?.content())?.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn recipients(&self, repo: &Repository) -> Result<Recipients> {
+        match self.tree.get_name("recipients") {
+            Some(entry) => parse_recipients(std::str::from_utf8(repo.find_blob(entry.id())?.content())?),
+            None => Ok(Recipients::default()),
+        }
+    }
+
+    pub fn notes(&self, repo: &Repository) -> Result<Vec<PatchNote>> {
+        match self.tree.get_name("notes") {
+            Some(entry) => parse_notes(std::str::from_utf8(repo.find_blob(entry.id())?.content())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn config(&self, repo: &Repository) -> Result<SeriesConfig> {
+        match self.tree.get_name("config") {
+            Some(entry) => parse_series_config(std::str::from_utf8(repo.find_blob(entry.id())?.content())?),
+            None => Ok(SeriesConfig::default()),
+        }
+    }
+
+    pub fn upstream(&self, repo: &Repository) -> Result<Option<String>> {
+        match self.tree.get_name("upstream") {
+            Some(entry) => Ok(Some(std::str::from_utf8(repo.find_blob(entry.id())?.content())?.trim().to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn dependencies(&self, repo: &Repository) -> Result<Vec<Dependency>> {
+        match self.tree.get_name("depends-on") {
+            Some(entry) => parse_dependencies(std::str::from_utf8(repo.find_blob(entry.id())?.content())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The parents connecting the history of the series, excluding gitlink parents.
+    pub fn history_parents(&self) -> Vec<Oid> {
+        self.commit.parent_ids().take_while(|parent_id| self.tree.get_id(*parent_id).is_none()).collect()
+    }
+}
+
+/// A patch series, identified by name.
+pub struct Series<'repo> {
+    repo: &'repo Repository,
+    name: String,
+}
+
+impl<'repo> Series<'repo> {
+    /// Get the current series, if any.
+    pub fn current(repo: &'repo Repository) -> Result<Option<Self>> {
+        Ok(current_series_name(repo)?.map(|name| Series { repo, name }))
+    }
+
+    /// Get the current series, or fail if there isn't one.
+    pub fn require_current(repo: &'repo Repository) -> Result<Self> {
+        Series::current(repo)?.ok_or_else(|| "No current series; use \"git series start <name>\" to start".into())
+    }
+
+    /// Find an existing series by name.
+    pub fn find(repo: &'repo Repository, name: &str) -> Result<Self> {
+        if !Internals::exists(repo, name)? {
+            return Err(format!("Series {} does not exist.", name).into());
+        }
+        Ok(Series { repo, name: name.to_string() })
+    }
+
+    /// Start a new series and make it the current series.
+    pub fn start(repo: &'repo Repository, name: &str) -> Result<Self> {
+        if Internals::exists(repo, name)? {
+            return Err(format!("Series {} already exists.\nUse checkout to resume working on an existing patch series.", name).into());
+        }
+        let prefixed_name = &[SERIES_PREFIX, name].concat();
+        repo.reference_symbolic(
+            SHEAD_REF,
+            prefixed_name,
+            true,
+            &format!("git series start {}", name),
+        )?;
+
+        let internals = Internals::read(repo)?;
+        internals.write(repo)?;
+        Ok(Series { repo, name: name.to_string() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn repo(&self) -> &'repo Repository {
+        self.repo
+    }
+
+    /// The name of the series ref.
+    pub fn refname(&self) -> String {
+        format!("{}{}", SERIES_PREFIX, self.name)
+    }
+
+    pub fn is_current(&self) -> Result<bool> {
+        Ok(current_series_name(self.repo)?.as_ref() == Some(&self.name))
+    }
+
+    /// Read the staged and working versions of the series. For the current series, this updates
+    /// the working `series` to HEAD.
+    pub fn internals(&self) -> Result<Internals<'repo>> {
+        let mut internals = Internals::read_series(self.repo, &self.name)?;
+        if self.is_current()? {
+            internals.update_series(self.repo)?;
+        }
+        Ok(internals)
+    }
+
+    /// The last series commit, or None if the series has no commits yet.
+    pub fn head(&self) -> Result<Option<SeriesVersion<'repo>>> {
+        match notfound_to_none(self.repo.refname_to_id(&self.refname()))? {
+            Some(id) => Ok(Some(SeriesVersion::find(self.repo, id)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The last series commit, or an error if the series has no commits yet.
+    pub fn require_head(&self) -> Result<SeriesVersion<'repo>> {
+        self.head()?.ok_or_else(|| format!("Series {} has no commits yet", self.name).into())
+    }
+
+    /// The base commit in the working version of the series.
+    pub fn base(&self) -> Result<Option<Oid>> {
+        Ok(self.internals()?.working.get("base")?.map(|e| e.id()))
+    }
+
+    /// Set or clear the base in the working version of the series, returning the previous base.
+    /// The new base must be an ancestor of the working `series`.
+    pub fn set_base(&self, new_base: Option<Oid>) -> Result<Option<Oid>> {
+        let mut internals = self.internals()?;
+        let current_base = internals.working.get("base")?.map(|e| e.id());
+        match new_base {
+            Some(base_id) => {
+                let s_working_series = internals.working.get("series")?
+                    .ok_or("Could not find entry \"series\" in working vesion of current series")?
+                    .id();
+                if base_id != s_working_series
+                    && !self.repo.graph_descendant_of(s_working_series, base_id)?
+                {
+                    return Err(format!(
+                        "Cannot set base to {}: not an ancestor of the patch series {}",
+                        base_id,
+                        s_working_series,
+                    ).into());
+                }
+                internals.working.insert("base", base_id, GIT_FILEMODE_COMMIT as i32)?;
+            }
+            None => {
+                if current_base.is_some() {
+                    internals.working.remove("base")?;
+                }
+            }
+        }
+        if current_base != new_base {
+            internals.write(self.repo)?;
+        }
+        Ok(current_base)
+    }
+
+    /// The cover letter in the working version of the series.
+    pub fn cover(&self) -> Result<Option<String>> {
+        match self.internals()?.working.get("cover")? {
+            Some(entry) => Ok(Some(std::str::from_utf8(self.repo.find_blob(entry.id())?.content())?.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Set or delete the cover letter in the working version of the series. Returns true if this
+    /// changed the cover letter.
+    pub fn set_cover(&self, cover: Option<&str>) -> Result<bool> {
+        let mut internals = self.internals()?;
+        let working_cover_id = internals.working.get("cover")?.map(|e| e.id());
+        let new_cover_id = match cover {
+            Some(content) => Some(self.repo.blob(content.as_bytes())?),
+            None => None,
+        };
+        if new_cover_id == working_cover_id {
+            return Ok(false);
+        }
+        match new_cover_id {
+            Some(id) => {
+                internals.working.insert("cover", id, GIT_FILEMODE_BLOB as i32)?;
+            }
+            None => internals.working.remove("cover")?,
+        }
+        internals.write(self.repo)?;
+        Ok(true)
+    }
+
+    /// The recipients in the working version of the series.
+    pub fn recipients(&self) -> Result<Recipients> {
+        match self.internals()?.working.get("recipients")? {
+            Some(entry) => parse_recipients(std::str::from_utf8(self.repo.find_blob(entry.id())?.content())?),
+            None => Ok(Recipients::default()),
+        }
+    }
+
+    /// Set the recipients in the working version of the series, deleting the entry if there are
+    /// none.
+    pub fn set_recipients(&self, recipients: &Recipients) -> Result<()> {
+        let mut internals = self.internals()?;
+        if recipients.is_empty() {
+            if internals.working.get("recipients")?.is_some() {
+                internals.working.remove("recipients")?;
+            }
+        } else {
+            let id = self.repo.blob(write_recipients(recipients).as_bytes())?;
+            internals.working.insert("recipients", id, GIT_FILEMODE_BLOB as i32)?;
+        }
+        internals.write(self.repo)
+    }
+
+    /// The settings in the working version of the series.
+    pub fn config(&self) -> Result<SeriesConfig> {
+        match self.internals()?.working.get("config")? {
+            Some(entry) => parse_series_config(std::str::from_utf8(self.repo.find_blob(entry.id())?.content())?),
+            None => Ok(SeriesConfig::default()),
+        }
+    }
+
+    /// Set the settings in the working version of the series, deleting the entry if there are
+    /// none.
+    pub fn set_config(&self, config: &SeriesConfig) -> Result<()> {
+        let mut internals = self.internals()?;
+        if config.is_empty() {
+            if internals.working.get("config")?.is_some() {
+                internals.working.remove("config")?;
+            }
+        } else {
+            let id = self.repo.blob(write_series_config(config).as_bytes())?;
+            internals.working.insert("config", id, GIT_FILEMODE_BLOB as i32)?;
+        }
+        internals.write(self.repo)
+    }
+
+    /// The upstream branch the working version of the series targets.
+    pub fn upstream(&self) -> Result<Option<String>> {
+        match self.internals()?.working.get("upstream")? {
+            Some(entry) => Ok(Some(std::str::from_utf8(self.repo.find_blob(entry.id())?.content())?.trim().to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Set or delete the upstream in the working version of the series. Returns true if this
+    /// changed the upstream.
+    pub fn set_upstream(&self, upstream: Option<&str>) -> Result<bool> {
+        let mut internals = self.internals()?;
+        let working_id = internals.working.get("upstream")?.map(|e| e.id());
+        let new_id = match upstream {
+            Some(upstream) => Some(self.repo.blob(format!("{}\n", upstream).as_bytes())?),
+            None => None,
+        };
+        if new_id == working_id {
+            return Ok(false);
+        }
+        match new_id {
+            Some(id) => {
+                internals.working.insert("upstream", id, GIT_FILEMODE_BLOB as i32)?;
+            }
+            None => internals.working.remove("upstream")?,
+        }
+        internals.write(self.repo)?;
+        Ok(true)
+    }
+
+    /// The prerequisites in the working version of the series.
+    pub fn dependencies(&self) -> Result<Vec<Dependency>> {
+        match self.internals()?.working.get("depends-on")? {
+            Some(entry) => parse_dependencies(std::str::from_utf8(self.repo.find_blob(entry.id())?.content())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Set the prerequisites in the working version of the series, deleting the entry if there
+    /// are none.
+    pub fn set_dependencies(&self, dependencies: &[Dependency]) -> Result<()> {
+        let mut internals = self.internals()?;
+        if dependencies.is_empty() {
+            if internals.working.get("depends-on")?.is_some() {
+                internals.working.remove("depends-on")?;
+            }
+        } else {
+            let id = self.repo.blob(write_dependencies(dependencies).as_bytes())?;
+            internals.working.insert("depends-on", id, GIT_FILEMODE_BLOB as i32)?;
+        }
+        internals.write(self.repo)
+    }
+
+    /// The patch notes in the working version of the series.
+    pub fn notes(&self) -> Result<Vec<PatchNote>> {
+        match self.internals()?.working.get("notes")? {
+            Some(entry) => parse_notes(std::str::from_utf8(self.repo.find_blob(entry.id())?.content())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // The patch ID and subject of each patch in the working version of the series, if it has a
+    // base, to tell which patch a note belongs to.
+    fn patch_subjects(&self) -> Result<Vec<(Oid, String)>> {
+        if self.base()?.is_none() {
+            return Ok(Vec::new());
+        }
+        self.commits()?.iter()
+            .map(|c| Ok((patch_id(self.repo, c)?, c.summary().unwrap_or("").to_string())))
+            .collect()
+    }
+
+    /// The note for the patch `commit` in the working version of the series, if any.
+    pub fn note(&self, commit: &Commit) -> Result<Option<PatchNote>> {
+        let id = patch_id(self.repo, commit)?;
+        Ok(find_note(&self.notes()?, &self.patch_subjects()?, id, commit.summary().unwrap_or("")).cloned())
+    }
+
+    /// Set or delete the note for the patch `commit` in the working version of the series,
+    /// recording the current patch ID and subject of the patch. Returns true if this changed the
+    /// notes.
+    pub fn set_note(&self, commit: &Commit, text: Option<&str>) -> Result<bool> {
+        let mut notes = self.notes()?;
+        let id = patch_id(self.repo, commit)?;
+        let subject = commit.summary().unwrap_or("").to_string();
+        let old = find_note(&notes, &self.patch_subjects()?, id, &subject).cloned();
+        notes.retain(|n| Some(n) != old.as_ref());
+        let new = text.map(|text| PatchNote { patch_id: id, subject, text: text.to_string() });
+        if new == old {
+            return Ok(false);
+        }
+        notes.extend(new);
+
+        let mut internals = self.internals()?;
+        if notes.is_empty() {
+            internals.working.remove("notes")?;
+        } else {
+            let blob = self.repo.blob(write_notes(&notes).as_bytes())?;
+            internals.working.insert("notes", blob, GIT_FILEMODE_BLOB as i32)?;
+        }
+        internals.write(self.repo)?;
+        Ok(true)
+    }
+
+    /// The versions of the series recorded as sent, in the working version of the series.
+    pub fn versions(&self) -> Result<Vec<SentVersion>> {
+        match self.internals()?.working.get("versions")? {
+            Some(entry) => parse_versions(std::str::from_utf8(self.repo.find_blob(entry.id())?.content())?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Record in the working version of the series that a series commit was sent as a version.
+    pub fn record_version(&self, sent: SentVersion) -> Result<()> {
+        let mut versions = self.versions()?;
+        insert_version(&mut versions, sent);
+        let mut internals = self.internals()?;
+        let id = self.repo.blob(write_versions(&versions).as_bytes())?;
+        internals.working.insert("versions", id, GIT_FILEMODE_BLOB as i32)?;
+        internals.write(self.repo)
+    }
+
+    /// Stage the working version of the named entries for the next series commit.
+    pub fn add<S: AsRef<str>>(&self, entries: &[S]) -> Result<()> {
+        let mut internals = self.internals()?;
+        for entry in entries {
+            let entry = entry.as_ref();
+            match internals.working.get(entry)? {
+                Some(e) => {
+                    internals.staged.insert(entry, e.id(), e.filemode())?;
+                }
+                None => {
+                    if internals.staged.get(entry)?.is_some() {
+                        internals.staged.remove(entry)?;
+                    }
+                }
+            }
+        }
+        internals.write(self.repo)
+    }
+
+    /// Undo `add`, restoring the named staged entries to their committed versions.
+    pub fn unadd<S: AsRef<str>>(&self, entries: &[S]) -> Result<()> {
+        let mut internals = self.internals()?;
+        match self.head()? {
+            Some(head) => {
+                for entry in entries {
+                    let entry = entry.as_ref();
+                    match head.tree().get_name(entry) {
+                        Some(e) => {
+                            internals.staged.insert(entry, e.id(), e.filemode())?;
+                        }
+                        None => {
+                            internals.staged.remove(entry)?;
+                        }
+                    }
+                }
+            }
+            None => {
+                for entry in entries {
+                    internals.staged.remove(entry.as_ref())?
+                }
+            }
+        }
+        internals.write(self.repo)
+    }
+
+    pub fn status(&self) -> Result<Status> {
+        let internals = self.internals()?;
+        let working_tree = self.repo.find_tree(internals.working.write()?)?;
+        let staged_tree = self.repo.find_tree(internals.staged.write()?)?;
+        let head = self.head()?;
+        let head_tree = head.as_ref().map(|h| h.tree());
+        Ok(Status {
+            initial: head.is_none(),
+            staged: entry_changes(&self.repo.diff_tree_to_tree(head_tree, Some(&staged_tree), None)?)?,
+            unstaged: entry_changes(&self.repo.diff_tree_to_tree(Some(&staged_tree), Some(&working_tree), None)?)?,
+            all: entry_changes(&self.repo.diff_tree_to_tree(head_tree, Some(&working_tree), None)?)?,
+            merging: self.merging()?,
+        })
+    }
+
+    /// The name of the ref recording the series commit being merged.
+    fn merge_refname(&self) -> String {
+        format!("{}{}", MERGE_PREFIX, self.name)
+    }
+
+    /// The series commit being merged into this series, if a merge is in progress.
+    pub fn merging(&self) -> Result<Option<Oid>> {
+        notfound_to_none(self.repo.refname_to_id(&self.merge_refname()))
+    }
+
+    /// Begin merging the series commit `theirs`: set the staged and working versions of the series
+    /// to the git-series tree `tree`, and record `theirs` as an additional parent for the next
+    /// series commit. For the current series, HEAD must already match the `series` in `tree`.
+    pub fn start_merge(&self, theirs: Oid, tree: Oid) -> Result<()> {
+        if self.merging()?.is_some() {
+            return Err("A series merge is already in progress.".into());
+        }
+        let tree = self.repo.find_tree(tree)?;
+        let mut internals = self.internals()?;
+        internals.staged = self.repo.treebuilder(Some(&tree))?;
+        internals.working = self.repo.treebuilder(Some(&tree))?;
+        internals.write(self.repo)?;
+        self.repo.reference(&self.merge_refname(), theirs, false, &format!("git series merge {}", theirs))?;
+        Ok(())
+    }
+
+    /// Abandon a merge in progress, resetting the staged and working versions of the series to
+    /// the last series commit.
+    pub fn abort_merge(&self) -> Result<()> {
+        let mut merge_ref = notfound_to_none(self.repo.find_reference(&self.merge_refname()))?
+            .ok_or("No series merge in progress.")?;
+        let head = self.require_head()?;
+        let mut internals = self.internals()?;
+        internals.staged = self.repo.treebuilder(Some(head.tree()))?;
+        internals.working = self.repo.treebuilder(Some(head.tree()))?;
+        internals.write(self.repo)?;
+        merge_ref.delete()?;
+        Ok(())
+    }
+
+    /// Move the series ref forward to `theirs`, a descendant of the last series commit, and set
+    /// the staged and working versions of the series to match. For the current series, HEAD must
+    /// already match the `series` of `theirs`.
+    pub fn fast_forward(&self, theirs: &SeriesVersion) -> Result<()> {
+        let mut internals = self.internals()?;
+        internals.staged = self.repo.treebuilder(Some(theirs.tree()))?;
+        internals.working = self.repo.treebuilder(Some(theirs.tree()))?;
+        internals.write(self.repo)?;
+        self.repo.reference(&self.refname(), theirs.id(), true, &format!("git series merge: Fast-forward to {}", theirs.id()))?;
+        Ok(())
+    }
+
+    /// Get the tree that a series commit would record: the working tree if `all`, otherwise the
+    /// staged tree.
+    pub fn commit_tree(&self, all: bool) -> Result<Tree<'repo>> {
+        let internals = self.internals()?;
+        let tb = if all { &internals.working } else { &internals.staged };
+        Ok(self.repo.find_tree(tb.write()?)?)
+    }
+
+    /// Record a new version of the series with the given message, returning the new series
+    /// commit. With `all`, commit the working version and stage it; otherwise commit the staged
+    /// version. If a merge is in progress, this concludes it with a series merge commit.
+    pub fn commit(&self, message: &str, all: bool) -> Result<Oid> {
+        let repo = self.repo;
+        if message.is_empty() {
+            return Err("Aborting series commit due to empty commit message.".into());
+        }
+        let tree = self.commit_tree(all)?;
+
+        // Check that the commit includes the series
+        let series_id = match tree.get_name("series") {
+            None => {
+                return Err(concat!(
+                    "Cannot commit: initial commit must include \"series\"\n",
+                    "Use \"git series add series\" or \"git series commit -a\"",
+                ).into());
+            }
+            Some(series) => series.id(),
+        };
+
+        // Check that the base is still an ancestor of the series
+        if let Some(base) = tree.get_name("base") {
+            if base.id() != series_id && !repo.graph_descendant_of(series_id, base.id())? {
+                let (base_short_id, base_summary) = commit_summarize_components(repo, base.id())?;
+                let (series_short_id, series_summary) = commit_summarize_components(repo, series_id)?;
+                return Err(format!(
+                    concat!(
+                        "Cannot commit: base {} is not an ancestor of patch series {}\n",
+                        "base   {} {}\n",
+                        "series {} {}"
+                    ),
+                    base_short_id, series_short_id,
+                    base_short_id, base_summary,
+                    series_short_id, series_summary,
+                ).into());
+            }
+        }
+
+        let config = repo.config()?.snapshot()?;
+        let author = get_signature(&config, "AUTHOR")?;
+        let committer = get_signature(&config, "COMMITTER")?;
+        let head = self.head()?;
+        let head_commit = head.as_ref().map(|h| h.commit());
+        let merging = self.merging()?;
+        let merging_commit = match merging {
+            Some(id) => Some(repo.find_commit(id)?),
+            None => None,
+        };
+        let mut parents: Vec<Oid> = Vec::new();
+        // Include all commits from tree, to keep them reachable and fetchable.
+        for e in tree.iter() {
+            if e.kind() == Some(ObjectType::Commit) && e.name().unwrap() != "base" {
+                parents.push(e.id())
+            }
+        }
+        let parents = parents_from_ids(repo, parents)?;
+        // History parents come before gitlink parents.
+        let parents_ref: Vec<&_> = head_commit.into_iter().chain(merging_commit.iter()).chain(parents.iter()).collect();
+        let new_commit_oid = repo.commit(Some(&self.refname()), &author, &committer, message, &tree, &parents_ref)?;
+        if merging.is_some() {
+            repo.find_reference(&self.merge_refname())?.delete()?;
+        }
+
+        if all {
+            let mut internals = self.internals()?;
+            internals.staged = repo.treebuilder(Some(&tree))?;
+            internals.write(repo)?;
+        }
+
+        Ok(new_commit_oid)
+    }
+
+    /// The history of the series: every series commit reachable from the series ref, in
+    /// topological order starting from the most recent.
+    pub fn history(&self) -> Result<Vec<SeriesVersion<'repo>>> {
+        match self.head()? {
+            Some(head) => history_from(self.repo, head.id()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Look up a version of the series by revision: "vN" for the series commit sent as version N;
+    /// "@{N}" for the Nth prior value of the series ref; or any revision naming a git-series commit.
+    pub fn resolve_version(&self, spec: &str) -> Result<SeriesVersion<'repo>> {
+        if let Some(n) = spec.strip_prefix("@{").and_then(|s| s.strip_suffix('}')) {
+            let n: usize = n.parse().map_err(|_| format!("Invalid reflog index in \"{}\"", spec))?;
+            let reflog = self.repo.reflog(&self.refname())?;
+            let entry = reflog.get(n).ok_or_else(|| format!(
+                "Series {} only has {} reflog entries",
+                self.name,
+                reflog.len(),
+            ))?;
+            return SeriesVersion::find(self.repo, entry.id_new());
+        }
+        if let Some(n) = spec.strip_prefix('v').and_then(|n| n.parse::<usize>().ok()) {
+            let sent = self.versions()?.into_iter().find(|v| v.version == n.to_string()).ok_or_else(|| format!(
+                "Series {} has no version {} recorded as sent; see \"git series versions\"",
+                self.name,
+                n,
+            ))?;
+            return SeriesVersion::find(self.repo, sent.series_commit);
+        }
+        let commit = self.repo.revparse_single(spec)?.peel_to_commit()?;
+        let version = SeriesVersion::new(commit)?;
+        if version.series().is_none() {
+            return Err(format!("{} is not a git-series commit", spec).into());
+        }
+        Ok(version)
+    }
+
+    /// The commits of the working version of the series, from `base` to `series`.
+    pub fn commits(&self) -> Result<Vec<Commit<'repo>>> {
+        let internals = self.internals()?;
+        let series = internals.working.get("series")?
+            .ok_or("Could not find entry \"series\" in working index")?;
+        let base = internals.working.get("base")?
+            .ok_or("Series has no base set.\nUse \"git series base\" to set base.")?;
+        crate::diff::get_commits(self.repo, base.id(), series.id())
+    }
+
+    /// Copy this series, including work in progress, to a new series named `dest`.
+    pub fn copy_to(&self, dest: &str) -> Result<Series<'repo>> {
+        if Internals::exists(self.repo, dest)? {
+            return Err(format!("The destination series \"{}\" already exists", dest).into());
+        }
+        if !Internals::copy(self.repo, &self.name, dest)? {
+            return Err(format!("The source series \"{}\" does not exist", self.name).into());
+        }
+        Ok(Series { repo: self.repo, name: dest.to_string() })
+    }
+
+    /// Rename this series, including work in progress, to `dest`, keeping it current if it was.
+    pub fn rename(self, dest: &str) -> Result<Series<'repo>> {
+        let was_current = self.is_current()?;
+        let new = self.copy_to(dest)?;
+        if was_current {
+            let prefixed_dest = &[SERIES_PREFIX, dest].concat();
+            self.repo.reference_symbolic(
+                SHEAD_REF,
+                prefixed_dest,
+                true,
+                &format!("git series mv {} {}", self.name, dest),
+            )?;
+        }
+        Internals::delete(self.repo, &self.name)?;
+        Ok(new)
+    }
+
+    /// Delete this series, including work in progress. The series must not be current.
+    pub fn delete(self) -> Result<()> {
+        if self.is_current()? {
+            return Err(format!(
+                "Cannot delete the current series \"{}\"; detach first.",
+                self.name,
+            ).into());
+        }
+        if !Internals::delete(self.repo, &self.name)? {
+            return Err(format!("Nothing to delete: series \"{}\" does not exist.", self.name).into());
+        }
+        Ok(())
+    }
+}
+
+/// Walk the history of series commits starting from `id`, skipping gitlink parents.
+pub fn history_from(repo: &Repository, id: Oid) -> Result<Vec<SeriesVersion<'_>>> {
+    let mut hidden_ids = std::collections::HashSet::new();
+    let mut commit_stack = Vec::new();
+    commit_stack.push(id);
+    while let Some(oid) = commit_stack.pop() {
+        let commit = repo.find_commit(oid)?;
+        let tree = commit.tree()?;
+        for parent_id in commit.parent_ids() {
+            if tree.get_id(parent_id).is_some() {
+                hidden_ids.insert(parent_id);
+            } else {
+                commit_stack.push(parent_id);
+            }
+        }
+    }
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL);
+    revwalk.push(id)?;
+    for id in hidden_ids {
+        revwalk.hide(id)?;
+    }
+    revwalk.map(|oid| SeriesVersion::find(repo, oid?)).collect()
+}
+
+/// Stop working on any series.
+pub fn detach(repo: &Repository) -> Result<()> {
+    match repo.find_reference(SHEAD_REF) {
+        Ok(mut r) => r.delete()?,
+        Err(_) => return Err("No current patch series to detach from.".into()),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_resolve_version() {
+    let t = crate::testutil::TestRepo::new();
+    let repo = &t.repo;
+    let base = t.commit(&[("f", Some("1\n"))], "Base");
+    t.commit(&[("f", Some("2\n"))], "Change");
+    let series = Series::start(repo, "topic").unwrap();
+    series.set_base(Some(base)).unwrap();
+    let v1 = series.commit("First version", true).unwrap();
+    series.record_version(SentVersion { version: "1".to_string(), series_commit: v1, message_id: "<m>".to_string() })
+        .unwrap();
+    let v2 = series.commit("Record v1", true).unwrap();
+    series.set_cover(Some("Cover\n")).unwrap();
+    let v3 = series.commit("Add a cover letter", true).unwrap();
+
+    let resolve = |spec: &str| series.resolve_version(spec).map(|v| v.id()).map_err(|e| e.to_string());
+    // "vN" names the version sent as version N, not the Nth series commit.
+    assert_eq!(resolve("v1"), Ok(v1));
+    assert_eq!(resolve("v2"), Err("Series topic has no version 2 recorded as sent; see \"git series versions\"".to_string()));
+    // "@{N}" names the Nth prior value of the series ref.
+    assert_eq!(resolve("@{0}"), Ok(v3));
+    assert_eq!(resolve("@{1}"), Ok(v2));
+    assert_eq!(resolve("@{2}"), Ok(v1));
+    assert_eq!(resolve("@{3}"), Err("Series topic only has 3 reflog entries".to_string()));
+    assert!(resolve("@{x}").is_err());
+    // Any other revision must name a git-series commit.
+    assert_eq!(resolve(&v2.to_string()), Ok(v2));
+    assert_eq!(resolve(&format!("{}^", v3)), Ok(v2));
+    assert_eq!(resolve(&base.to_string()), Err(format!("{} is not a git-series commit", base)));
+    assert!(resolve("no-such-revision").is_err());
+}
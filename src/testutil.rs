@@ -0,0 +1,76 @@
+//! Helpers for tests that need a repository.
+
+use std::fs;
+use std::path::Path;
+
+use git2::{Oid, Repository, Signature};
+use tempdir::TempDir;
+
+/// A scratch repository with a work tree, removed when dropped.
+pub struct TestRepo {
+    pub repo: Repository,
+    // Declared after `repo`, so that the repository closes before its directory goes away.
+    _dir: TempDir,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let dir = TempDir::new("git-series-test").unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "A U Thor").unwrap();
+        config.set_str("user.email", "author@example.com").unwrap();
+        config.set_str("core.editor", "true").unwrap();
+        TestRepo { repo, _dir: dir }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.repo.workdir().unwrap()
+    }
+
+    /// Write `files` to the work tree and the index, removing those with no content, and commit
+    /// the result on top of HEAD, if any. Returns the new commit, which becomes HEAD.
+    pub fn commit(&self, files: &[(&str, Option<&str>)], message: &str) -> Oid {
+        let mut index = self.repo.index().unwrap();
+        for &(path, content) in files {
+            let full = self.path().join(path);
+            match content {
+                Some(content) => {
+                    fs::create_dir_all(full.parent().unwrap()).unwrap();
+                    fs::write(&full, content).unwrap();
+                    index.add_path(Path::new(path)).unwrap();
+                }
+                None => {
+                    fs::remove_file(&full).unwrap();
+                    index.remove_path(Path::new(path)).unwrap();
+                }
+            }
+        }
+        index.write().unwrap();
+        let tree = self.repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::new("A U Thor", "author@example.com", &git2::Time::new(1_500_000_000, 0)).unwrap();
+        let parent = self.repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        self.repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents).unwrap()
+    }
+
+    /// Check out `id` as a detached HEAD, discarding any changes.
+    pub fn checkout(&self, id: Oid) {
+        let commit = self.repo.find_commit(id).unwrap();
+        self.repo.set_head_detached(id).unwrap();
+        self.repo.reset(commit.as_object(), git2::ResetType::Hard, None).unwrap();
+    }
+
+    /// The summaries of the first-parent history from `tip` back to `base`, oldest first.
+    pub fn summaries(&self, base: Oid, tip: Oid) -> Vec<String> {
+        let mut summaries = Vec::new();
+        let mut id = tip;
+        while id != base {
+            let commit = self.repo.find_commit(id).unwrap();
+            summaries.push(commit.summary().unwrap().to_string());
+            id = commit.parent_id(0).unwrap();
+        }
+        summaries.reverse();
+        summaries
+    }
+}
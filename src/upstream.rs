@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use git2::{Commit, Oid, Repository};
+
+use crate::diff::{get_commits, DiffColors, RangeDiff, RangeDiffOptions};
+use crate::error::Result;
+use crate::patchid::patch_id;
+
+/// Whether a patch of a series has been applied upstream.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamStatus {
+    /// Upstream has a commit with the same patch ID.
+    Applied,
+    /// Upstream has a commit by the same author that the range diff matches with the patch, with
+    /// at most `MODIFIED_PERCENT` percent of their lines differing.
+    Modified,
+    /// Upstream has no matching commit.
+    Pending,
+}
+
+impl UpstreamStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            UpstreamStatus::Applied => "applied",
+            UpstreamStatus::Modified => "modified",
+            UpstreamStatus::Pending => "pending",
+        }
+    }
+
+    /// Whether to treat the patch as applied upstream, and drop it from the series. Patches
+    /// applied with changes only count with `include_modified`.
+    pub fn is_applied(self, include_modified: bool) -> bool {
+        match self {
+            UpstreamStatus::Applied => true,
+            UpstreamStatus::Modified => include_modified,
+            UpstreamStatus::Pending => false,
+        }
+    }
+}
+
+/// The largest share of differing lines, as a percentage of the lines of both commits, for which
+/// an upstream commit the range diff pairs with a patch counts as a modified version of it.
+const MODIFIED_PERCENT: usize = 30;
+
+/// One patch of a series, and the upstream commit it was applied as, if any.
+pub struct UpstreamPatch<'repo> {
+    pub commit: Commit<'repo>,
+    pub status: UpstreamStatus,
+    pub upstream: Option<Commit<'repo>>,
+}
+
+/// Find which patches of the series `base..series` upstream has applied, looking at the commits
+/// from the merge base of `base` and `upstream` to `upstream`. A patch counts as applied if an
+/// upstream commit has the same patch ID, and as modified if a range diff between the remaining
+/// patches and the remaining upstream commits by the same authors pairs it with a commit that
+/// differs from it in at most `MODIFIED_PERCENT` percent of their lines.
+pub fn upstream_status<'repo>(
+    repo: &'repo Repository,
+    base: Oid,
+    series: Oid,
+    upstream: Oid,
+) -> Result<Vec<UpstreamPatch<'repo>>> {
+    let upstream_base = repo.merge_base(base, upstream)?;
+    let upstream_commits: Vec<_> = get_commits(repo, upstream_base, upstream)?.into_iter()
+        .filter(|c| c.parent_count() == 1)
+        .collect();
+    // Upstream may apply the same change more than once, such as after a revert, so keep every
+    // commit with each patch ID, to match each one with a patch at most once.
+    let mut upstream_ids: HashMap<Oid, Vec<usize>> = HashMap::new();
+    for (i, commit) in upstream_commits.iter().enumerate() {
+        upstream_ids.entry(patch_id(repo, commit)?).or_default().push(i);
+    }
+
+    let mut patches = Vec::new();
+    let mut used = vec![false; upstream_commits.len()];
+    for commit in get_commits(repo, base, series)? {
+        let found = if commit.parent_count() == 1 {
+            upstream_ids.get(&patch_id(repo, &commit)?)
+                .and_then(|ids| ids.iter().copied().find(|&i| !used[i]))
+        } else {
+            None
+        };
+        let (status, upstream) = match found {
+            Some(i) => {
+                used[i] = true;
+                (UpstreamStatus::Applied, Some(upstream_commits[i].clone()))
+            }
+            None => (UpstreamStatus::Pending, None),
+        };
+        patches.push(UpstreamPatch { commit, status, upstream });
+    }
+
+    // Only consider upstream commits by the authors of the pending patches, to keep the range
+    // diff small.
+    let pending: Vec<_> = patches.iter().enumerate()
+        .filter(|(_, p)| p.status == UpstreamStatus::Pending && p.commit.parent_count() == 1)
+        .map(|(i, _)| i)
+        .collect();
+    let author = |c: &Commit| c.author().email_bytes().to_owned();
+    let authors: Vec<_> = pending.iter().map(|&i| author(&patches[i].commit)).collect();
+    let candidates: Vec<_> = upstream_commits.iter().enumerate()
+        .filter(|&(i, c)| !used[i] && authors.contains(&author(c)))
+        .map(|(_, c)| c.clone())
+        .collect();
+    if pending.is_empty() || candidates.is_empty() {
+        return Ok(patches);
+    }
+    let range_diff = RangeDiff::from_commits(
+        repo,
+        &DiffColors::plain(),
+        &RangeDiffOptions::default(),
+        pending.iter().map(|&i| patches[i].commit.clone()).collect(),
+        candidates,
+    )?;
+    for pair in range_diff.pairs.iter() {
+        if let (Some(old), Some(new)) = (pair.old, pair.new) {
+            let (changes, lines) = range_diff.changes(old, new)?;
+            if changes * 100 > lines * MODIFIED_PERCENT {
+                continue;
+            }
+            let patch = &mut patches[pending[old]];
+            patch.status = UpstreamStatus::Modified;
+            patch.upstream = Some(range_diff.commits2[new].clone());
+        }
+    }
+    Ok(patches)
+}
+
+#[test]
+fn test_upstream_status() {
+    let t = crate::testutil::TestRepo::new();
+    let lines: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+    let base = t.commit(&[("a", Some("1\n")), ("b", Some(&lines))], "Base");
+
+    let x = Some("1\nx\n");
+    let changed_b = lines.replace("line 5\n", "line five\n");
+    let c: String = (1..=10).map(|i| format!("c {}\n", i)).collect();
+    let series = [
+        t.commit(&[("a", x)], "Add x"),
+        t.commit(&[("a", Some("1\n"))], "Remove x"),
+        t.commit(&[("a", x)], "Add x again"),
+        t.commit(&[("b", Some(&changed_b))], "Change b"),
+        t.commit(&[("c", Some(&c))], "Add c"),
+    ];
+
+    t.checkout(base);
+    let other_c: String = (1..=10).map(|i| format!("c {}\n", if i % 3 != 1 { i * 100 } else { i })).collect();
+    let upstream = [
+        t.commit(&[("a", x)], "Add x upstream"),
+        t.commit(&[("a", Some("1\n"))], "Remove x upstream"),
+        t.commit(&[("a", x)], "Add x again upstream"),
+        t.commit(&[("b", Some(&lines.replace("line 5\n", "line 5!\n")))], "Change b"),
+        t.commit(&[("c", Some(&other_c))], "Add c"),
+    ];
+
+    let patches = upstream_status(&t.repo, base, series[4], upstream[4]).unwrap();
+    let statuses: Vec<_> = patches.iter()
+        .map(|p| (p.commit.id(), p.status, p.upstream.as_ref().map(|u| u.id())))
+        .collect();
+    assert_eq!(statuses, vec![
+        (series[0], UpstreamStatus::Applied, Some(upstream[0])),
+        (series[1], UpstreamStatus::Applied, Some(upstream[1])),
+        (series[2], UpstreamStatus::Applied, Some(upstream[2])),
+        (series[3], UpstreamStatus::Modified, Some(upstream[3])),
+        (series[4], UpstreamStatus::Pending, None),
+    ]);
+
+    // The range diff pairs the two versions of "Add c", but too much differs between them.
+    let range_diff = RangeDiff::from_commits(
+        &t.repo,
+        &DiffColors::plain(),
+        &RangeDiffOptions::default(),
+        vec![patches[4].commit.clone()],
+        vec![t.repo.find_commit(upstream[4]).unwrap()],
+    ).unwrap();
+    assert_eq!(range_diff.pairs.len(), 1);
+    let (changes, lines) = range_diff.changes(0, 0).unwrap();
+    assert!(changes * 100 > lines * MODIFIED_PERCENT);
+}
+
+#[test]
+fn test_is_applied() {
+    assert!(UpstreamStatus::Applied.is_applied(false));
+    assert!(!UpstreamStatus::Modified.is_applied(false));
+    assert!(UpstreamStatus::Modified.is_applied(true));
+    assert!(!UpstreamStatus::Pending.is_applied(true));
+}
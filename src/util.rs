@@ -0,0 +1,178 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::process::Command;
+
+use chrono::offset::TimeZone;
+use git2::{Commit, Config, Oid, Reference, Repository};
+
+use crate::error::Result;
+
+const SHELL_METACHARS: &str = "|&;<>()$`\\\"' \t\n*?[#~=%";
+
+pub fn commit_obj_summarize_components(commit: &mut Commit) -> Result<(String, String)> {
+    let short_id_buf = commit.as_object().short_id()?;
+    let short_id = short_id_buf.as_str().unwrap();
+    let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap());
+    Ok((short_id.to_string(), summary.to_string()))
+}
+
+pub fn commit_summarize_components(repo: &Repository, id: Oid) -> Result<(String, String)> {
+    let mut commit = repo.find_commit(id)?;
+    commit_obj_summarize_components(&mut commit)
+}
+
+pub fn commit_obj_summarize(commit: &mut Commit) -> Result<String> {
+    let (short_id, summary) = commit_obj_summarize_components(commit)?;
+    Ok(format!("{} {}", short_id, summary))
+}
+
+pub fn commit_summarize(repo: &Repository, id: Oid) -> Result<String> {
+    let mut commit = repo.find_commit(id)?;
+    commit_obj_summarize(&mut commit)
+}
+
+pub fn notfound_to_none<T>(result: std::result::Result<T, git2::Error>) -> Result<Option<T>> {
+    match result {
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+        Ok(x) => Ok(Some(x)),
+    }
+}
+
+/// All values of a multi-valued config variable such as "format.to", in the order git reads them.
+pub fn config_values(config: &Config, name: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let entries = config.entries(Some(&name.replace('.', "\\.")))?;
+    for entry in &entries {
+        let entry = entry?;
+        if entry.name().is_some_and(|n| n.eq_ignore_ascii_case(name)) {
+            if let Some(value) = entry.value() {
+                values.push(value.to_string());
+            }
+        }
+    }
+    Ok(values)
+}
+
+// If current_id_opt is Some, acts like reference_matching.  If current_id_opt is None, acts like
+// reference.
+pub(crate) fn reference_matching_opt<'repo>(
+    repo: &'repo Repository,
+    name: &str,
+    id: Oid,
+    force: bool,
+    current_id_opt: Option<Oid>,
+    log_message: &str,
+) -> Result<Reference<'repo>> {
+    Ok(match current_id_opt {
+        None => repo.reference(name, id, force, log_message)?,
+        Some(current_id) => repo.reference_matching(name, id, force, current_id, log_message)?,
+    })
+}
+
+pub(crate) fn parents_from_ids(repo: &Repository, mut parents: Vec<Oid>) -> Result<Vec<Commit<'_>>> {
+    parents.sort();
+    parents.dedup();
+    parents.drain(..).map(|id| Ok(repo.find_commit(id)?)).collect()
+}
+
+pub fn get_editor(config: &Config) -> Result<OsString> {
+    if let Some(e) = env::var_os("GIT_EDITOR") {
+        return Ok(e);
+    }
+    if let Ok(e) = config.get_path("core.editor") {
+        return Ok(e.into());
+    }
+    let terminal_is_dumb = match env::var_os("TERM") {
+        None => true,
+        Some(t) => t.as_os_str() == "dumb",
+    };
+    if !terminal_is_dumb {
+        if let Some(e) = env::var_os("VISUAL") {
+            return Ok(e);
+        }
+    }
+    if let Some(e) = env::var_os("EDITOR") {
+        return Ok(e);
+    }
+    if terminal_is_dumb {
+        return Err("TERM unset or \"dumb\" but EDITOR unset".into());
+    }
+    Ok("vi".into())
+}
+
+/// Construct a Command, using the shell if the command contains shell metachars
+pub fn cmd_maybe_shell<S: AsRef<OsStr>>(program: S, args: bool) -> Command {
+    if program.as_ref().to_string_lossy().contains(|c| SHELL_METACHARS.contains(c)) {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        if args {
+            let mut program_with_args = program.as_ref().to_os_string();
+            program_with_args.push(" \"$@\"");
+            cmd.arg(program_with_args).arg(program);
+        } else {
+            cmd.arg(program);
+        }
+        cmd
+    } else {
+        Command::new(program)
+    }
+}
+
+pub fn run_editor<S: AsRef<OsStr>>(config: &Config, filename: S) -> Result<()> {
+    let editor = get_editor(config)?;
+    let editor_status = cmd_maybe_shell(editor, true).arg(&filename).status()?;
+    if !editor_status.success() {
+        return Err(format!("Editor exited with status {}", editor_status).into());
+    }
+    Ok(())
+}
+
+pub fn get_signature(config: &Config, which: &str) -> Result<git2::Signature<'static>> {
+    let name_var = ["GIT_", which, "_NAME"].concat();
+    let email_var = ["GIT_", which, "_EMAIL"].concat();
+    let which_lc = which.to_lowercase();
+    let name = env::var(&name_var)
+        .or_else(|_| config.get_string("user.name"))
+        .map_err(|_| format!(
+            "Could not determine {} name: checked ${} and user.name in git config",
+            which_lc, name_var,
+        ))?;
+    let email = env::var(&email_var)
+        .or_else(|_| config.get_string("user.email"))
+        .or_else(|_| env::var("EMAIL"))
+        .map_err(|_| format!(
+            "Could not determine {} email: checked ${}, user.email in git config, and $EMAIL",
+            which_lc, email_var,
+        ))?;
+    Ok(git2::Signature::now(&name, &email)?)
+}
+
+pub fn date_822(t: git2::Time) -> String {
+    let offset = chrono::offset::fixed::FixedOffset::east(t.offset_minutes() * 60);
+    let datetime = offset.timestamp(t.seconds(), 0);
+    datetime.to_rfc2822()
+}
+
+pub fn split_message(message: &str) -> (&str, &str) {
+    let mut iter = message.splitn(2, '\n');
+    let subject = iter.next().unwrap().trim_end();
+    let body = iter.next().map(|s| s.trim_start()).unwrap_or("");
+    (subject, body)
+}
+
+pub fn ensure_space(s: &str) -> &'static str {
+    if s.is_empty() || s.ends_with(' ') {
+        ""
+    } else {
+        " "
+    }
+}
+
+pub fn ensure_nl(s: &str) -> &'static str {
+    if !s.ends_with('\n') {
+        "\n"
+    } else {
+        ""
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt::Write as FmtWrite;
+
+use git2::Oid;
+
+use crate::error::Result;
+
+/// A record that a series commit was sent as a numbered version of the series. The `versions`
+/// entry of the git-series tree stores one line per record: the version, the series commit, and
+/// the Message-Id of the first mail, separated by spaces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentVersion {
+    pub version: String,
+    pub series_commit: Oid,
+    pub message_id: String,
+}
+
+pub fn parse_versions(content: &str) -> Result<Vec<SentVersion>> {
+    content.lines().filter(|line| !line.trim().is_empty()).map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(version), Some(id), Some(message_id), None) => Ok(SentVersion {
+                version: version.to_string(),
+                series_commit: Oid::from_str(id)?,
+                message_id: message_id.to_string(),
+            }),
+            _ => Err(format!("Malformed line in \"versions\": {}", line).into()),
+        }
+    }).collect()
+}
+
+pub fn write_versions(versions: &[SentVersion]) -> String {
+    let mut s = String::new();
+    for v in versions {
+        writeln!(s, "{} {} {}", v.version, v.series_commit, v.message_id).unwrap();
+    }
+    s
+}
+
+/// Add a record to a list of versions, replacing any previous record of the same version, and
+/// keeping numbered versions in numeric order before any others.
+pub fn insert_version(versions: &mut Vec<SentVersion>, new: SentVersion) {
+    versions.retain(|v| v.version != new.version);
+    versions.push(new);
+    versions.sort_by_key(|v| match v.version.parse::<u64>() {
+        Ok(n) => (false, n),
+        Err(_) => (true, 0),
+    });
+}
+
+/// The version to send the series commit `id` as, if not specified: the version it was already
+/// sent as, or one more than the highest numbered version sent so far. Returns None if no version
+/// has been sent.
+pub fn next_version(versions: &[SentVersion], id: Oid) -> Option<String> {
+    if let Some(v) = versions.iter().rev().find(|v| v.series_commit == id) {
+        return Some(v.version.clone());
+    }
+    versions.iter()
+        .filter_map(|v| v.version.parse::<u64>().ok())
+        .max()
+        .map(|n| (n + 1).to_string())
+}
+
+/// The version sent before `version`: the highest numbered version below it, or if `version` is
+/// not a number, the last version recorded other than `version`.
+pub fn previous_version<'a>(versions: &'a [SentVersion], version: &str) -> Option<&'a SentVersion> {
+    match version.parse::<u64>() {
+        Ok(n) => versions.iter()
+            .filter(|v| v.version.parse::<u64>().is_ok_and(|m| m < n))
+            .max_by_key(|v| v.version.parse::<u64>().unwrap()),
+        Err(_) => versions.iter().rev().find(|v| v.version != version),
+    }
+}
+
+#[test]
+fn test_versions() {
+    let id1 = Oid::from_str("1111111111111111111111111111111111111111").unwrap();
+    let id2 = Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+    let mut versions = parse_versions("2 1111111111111111111111111111111111111111 <b@x>\n").unwrap();
+    assert_eq!(next_version(&versions, id1), Some("2".to_string()));
+    assert_eq!(next_version(&versions, id2), Some("3".to_string()));
+    assert_eq!(next_version(&[], id2), None);
+
+    insert_version(&mut versions, SentVersion {
+        version: "1".to_string(),
+        series_commit: id2,
+        message_id: "<a@x>".to_string(),
+    });
+    assert_eq!(
+        write_versions(&versions),
+        concat!(
+            "1 2222222222222222222222222222222222222222 <a@x>\n",
+            "2 1111111111111111111111111111111111111111 <b@x>\n",
+        ),
+    );
+    assert_eq!(previous_version(&versions, "3").map(|v| v.series_commit), Some(id1));
+    assert_eq!(previous_version(&versions, "2").map(|v| v.series_commit), Some(id2));
+    assert_eq!(previous_version(&versions, "1"), None);
+    assert_eq!(previous_version(&versions, "rc").map(|v| v.series_commit), Some(id1));
+    assert!(parse_versions("1 2222222222222222222222222222222222222222\n").is_err());
+}